@@ -0,0 +1,4 @@
+pub mod procfs;
+pub mod protocol;
+pub mod server;
+pub mod sysfs;
@@ -0,0 +1,49 @@
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use usbipd_agent::server::{serve, Roots};
+
+const USAGE: &str = "Использование: usbipd_agent [--sysfs-root <PATH>] [--procfs-root <PATH>]
+
+Читает запросы JSON (по одному на строку) из stdin и пишет ответы в stdout.
+Запускается менеджером usbipd_gui внутри WSL-дистрибутива.";
+
+fn parse_roots(args: &[String]) -> Result<Roots, String> {
+    let mut roots = Roots::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || {
+            iter.next()
+                .map(PathBuf::from)
+                .ok_or_else(|| format!("Для {arg} требуется путь"))
+        };
+        match arg.as_str() {
+            "--sysfs-root" => roots.sysfs = value()?,
+            "--procfs-root" => roots.procfs = value()?,
+            other => return Err(format!("Неизвестный параметр: {other}")),
+        }
+    }
+    Ok(roots)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{USAGE}");
+        return;
+    }
+
+    let roots = match parse_roots(&args) {
+        Ok(roots) => roots,
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = serve(&roots, io::stdin().lock(), io::stdout().lock()) {
+        eprintln!("Ошибка ввода-вывода: {e}");
+        process::exit(1);
+    }
+}
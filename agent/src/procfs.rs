@@ -0,0 +1,179 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mount {
+    pub source: String,
+    pub target: String,
+}
+
+// A process keeping a device node, or a file on one of its filesystems, open.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Holder {
+    pub pid: u32,
+    pub command: String,
+    pub path: String,
+}
+
+// /proc/mounts escapes whitespace and backslashes as \ooo octal sequences.
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|digits| {
+            std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+        });
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                result.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                result.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&result).to_string()
+}
+
+pub fn parse_mounts(contents: &str) -> Vec<Mount> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some(Mount {
+                source: unescape_mount_field(fields.next()?),
+                target: unescape_mount_field(fields.next()?),
+            })
+        })
+        .collect()
+}
+
+pub fn read_mounts(root: &Path) -> io::Result<Vec<Mount>> {
+    fs::read_to_string(root.join("mounts")).map(|contents| parse_mounts(&contents))
+}
+
+// Mounts backed by any of the given nodes, deepest first so they can be
+// unmounted in order.
+pub fn mounts_of(mounts: &[Mount], nodes: &[String]) -> Vec<Mount> {
+    let mut found: Vec<Mount> = mounts
+        .iter()
+        .filter(|mount| nodes.contains(&mount.source))
+        .cloned()
+        .collect();
+    found.sort_by_key(|mount| std::cmp::Reverse(mount.target.len()));
+    found
+}
+
+fn is_within(path: &str, target: &str) -> bool {
+    target == "/"
+        || path == target
+        || path
+            .strip_prefix(target)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+// Looks at every process' open descriptors and working directory. Processes
+// that vanish or cannot be inspected (other users without root) are skipped.
+pub fn find_holders(root: &Path, nodes: &[String], mounts: &[Mount]) -> io::Result<Vec<Holder>> {
+    let mut holders = Vec::new();
+    for entry in fs::read_dir(root)?.flatten() {
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        else {
+            continue;
+        };
+        let process = entry.path();
+        let command = fs::read_to_string(process.join("comm"))
+            .map(|comm| comm.trim().to_string())
+            .unwrap_or_default();
+
+        let mut links: Vec<_> = fs::read_dir(process.join("fd"))
+            .map(|fds| fds.flatten().map(|fd| fd.path()).collect())
+            .unwrap_or_default();
+        links.push(process.join("cwd"));
+
+        for link in links {
+            let Ok(target) = fs::read_link(&link) else {
+                continue;
+            };
+            let path = target.to_string_lossy().to_string();
+            let held =
+                nodes.contains(&path) || mounts.iter().any(|mount| is_within(&path, &mount.target));
+            if held {
+                holders.push(Holder {
+                    pid,
+                    command: command.clone(),
+                    path,
+                });
+            }
+        }
+    }
+    holders.sort();
+    holders.dedup();
+    Ok(holders)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_escaped_mount_points() {
+        let mounts = parse_mounts(
+            "/dev/sdc / ext4 rw,relatime 0 0\n\
+             /dev/sdd1 /mnt/usb\\040stick vfat rw 0 0\n",
+        );
+        assert_eq!(mounts[1].source, "/dev/sdd1");
+        assert_eq!(mounts[1].target, "/mnt/usb stick");
+
+        let nodes = vec!["/dev/sdd".to_string(), "/dev/sdd1".to_string()];
+        assert_eq!(mounts_of(&mounts, &nodes), vec![mounts[1].clone()]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn finds_processes_holding_nodes_and_mounts() {
+        use crate::sysfs::fixtures;
+
+        let procfs = fixtures::tree(&[
+            ("mounts", "/dev/sdd1 /media/stick vfat rw 0 0"),
+            ("101/comm", "minicom"),
+            ("202/comm", "bash"),
+            ("303/comm", "cp"),
+            ("self/", ""),
+        ]);
+        fixtures::symlink(&procfs, "101/fd/3", "/dev/ttyACM0");
+        fixtures::symlink(&procfs, "101/cwd", "/home/user");
+        fixtures::symlink(&procfs, "202/fd/0", "/dev/pts/0");
+        fixtures::symlink(&procfs, "202/cwd", "/media/stick");
+        fixtures::symlink(&procfs, "303/fd/4", "/media/stick/firmware.bin");
+        fixtures::symlink(&procfs, "303/cwd", "/media/sticker");
+
+        let nodes = vec!["/dev/ttyACM0".to_string(), "/dev/sdd1".to_string()];
+        let mounts = mounts_of(&read_mounts(&procfs).unwrap(), &nodes);
+        let holders = find_holders(&procfs, &nodes, &mounts).unwrap();
+        fs::remove_dir_all(procfs).unwrap();
+
+        let found: Vec<(u32, &str, &str)> = holders
+            .iter()
+            .map(|holder| (holder.pid, holder.command.as_str(), holder.path.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (101, "minicom", "/dev/ttyACM0"),
+                (202, "bash", "/media/stick"),
+                (303, "cp", "/media/stick/firmware.bin"),
+            ]
+        );
+    }
+}
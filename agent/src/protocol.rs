@@ -0,0 +1,59 @@
+use crate::procfs::{Holder, Mount};
+use crate::sysfs::{SysfsSnapshot, SysfsUsbDevice};
+use serde::{Deserialize, Serialize};
+
+// Bumped whenever a request or response changes shape.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+// One JSON object per line on the agent's stdin, answered by one JSON object
+// per line on its stdout.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Version,
+    Snapshot,
+    Resolve {
+        vid_pid: String,
+        #[serde(default)]
+        serial: Option<String>,
+    },
+    Holders {
+        vid_pid: String,
+        #[serde(default)]
+        serial: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Version {
+        agent_version: String,
+        protocol: u32,
+        kernel: Option<String>,
+    },
+    Snapshot {
+        snapshot: SysfsSnapshot,
+    },
+    Resolved {
+        device: Option<SysfsUsbDevice>,
+        vhci_loaded: bool,
+    },
+    Holders {
+        mounts: Vec<Mount>,
+        holders: Vec<Holder>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+pub fn encode<T: Serialize>(message: &T) -> String {
+    serde_json::to_string(message).unwrap_or_else(|e| {
+        serde_json::json!({ "type": "error", "message": e.to_string() }).to_string()
+    })
+}
+
+pub fn decode<T: for<'de> Deserialize<'de>>(line: &str) -> Result<T, String> {
+    serde_json::from_str(line.trim()).map_err(|e| format!("Некорректное сообщение агента: {e}"))
+}
@@ -0,0 +1,134 @@
+use crate::procfs::{find_holders, mounts_of, read_mounts};
+use crate::protocol::{decode, encode, Request, Response, PROTOCOL_VERSION};
+use crate::sysfs::read_snapshot;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Roots {
+    pub sysfs: PathBuf,
+    pub procfs: PathBuf,
+}
+
+impl Default for Roots {
+    fn default() -> Self {
+        Self {
+            sysfs: PathBuf::from("/sys"),
+            procfs: PathBuf::from("/proc"),
+        }
+    }
+}
+
+pub fn handle(roots: &Roots, request: Request) -> Response {
+    match request {
+        Request::Version => Response::Version {
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol: PROTOCOL_VERSION,
+            kernel: fs::read_to_string(roots.procfs.join("sys/kernel/osrelease"))
+                .ok()
+                .map(|release| release.trim().to_string()),
+        },
+        Request::Snapshot => match read_snapshot(&roots.sysfs) {
+            Ok(snapshot) => Response::Snapshot { snapshot },
+            Err(e) => sysfs_error(e),
+        },
+        Request::Resolve { vid_pid, serial } => match read_snapshot(&roots.sysfs) {
+            Ok(snapshot) => Response::Resolved {
+                device: snapshot.find_device(&vid_pid, serial.as_deref()).cloned(),
+                vhci_loaded: snapshot.vhci_loaded,
+            },
+            Err(e) => sysfs_error(e),
+        },
+        Request::Holders { vid_pid, serial } => match read_snapshot(&roots.sysfs) {
+            Ok(snapshot) => {
+                let nodes: Vec<String> = snapshot
+                    .find_device(&vid_pid, serial.as_deref())
+                    .map(|device| device.nodes.iter().map(|node| node.path.clone()).collect())
+                    .unwrap_or_default();
+                let mounts = read_mounts(&roots.procfs)
+                    .map(|mounts| mounts_of(&mounts, &nodes))
+                    .unwrap_or_default();
+                match find_holders(&roots.procfs, &nodes, &mounts) {
+                    Ok(holders) => Response::Holders { mounts, holders },
+                    Err(e) => Response::Error {
+                        message: format!("Не удалось прочитать procfs: {e}"),
+                    },
+                }
+            }
+            Err(e) => sysfs_error(e),
+        },
+    }
+}
+
+fn sysfs_error(e: io::Error) -> Response {
+    Response::Error {
+        message: format!("Не удалось прочитать sysfs: {e}"),
+    }
+}
+
+pub fn serve(roots: &Roots, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match decode::<Request>(&line) {
+            Ok(request) => handle(roots, request),
+            Err(message) => Response::Error { message },
+        };
+        writeln!(output, "{}", encode(&response))?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysfs::fixtures;
+
+    #[test]
+    fn answers_requests_line_by_line() {
+        let sysfs = fixtures::tree(&[
+            ("bus/usb/devices/1-1/idVendor", "058f"),
+            ("bus/usb/devices/1-1/idProduct", "9540"),
+            (
+                "bus/usb/devices/1-1/if0/hidraw/hidraw0/uevent",
+                "DEVNAME=hidraw0",
+            ),
+        ]);
+        let procfs =
+            fixtures::tree(&[("sys/kernel/osrelease", "6.6.87.2-microsoft-standard-WSL2")]);
+        let roots = Roots {
+            sysfs: sysfs.clone(),
+            procfs: procfs.clone(),
+        };
+
+        let input = "{\"command\":\"version\"}\n\
+                     {\"command\":\"resolve\",\"vid_pid\":\"058f:9540\"}\n\
+                     not json\n";
+        let mut output = Vec::new();
+        serve(&roots, input.as_bytes(), &mut output).unwrap();
+        fs::remove_dir_all(sysfs).unwrap();
+        fs::remove_dir_all(procfs).unwrap();
+
+        let responses: Vec<Response> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| decode(line).unwrap())
+            .collect();
+        assert_eq!(responses.len(), 3);
+        assert!(matches!(
+            &responses[0],
+            Response::Version { kernel: Some(kernel), protocol: PROTOCOL_VERSION, .. }
+                if kernel.ends_with("WSL2")
+        ));
+        assert!(matches!(
+            &responses[1],
+            Response::Resolved { device: Some(device), vhci_loaded: false }
+                if device.nodes[0].path == "/dev/hidraw0"
+        ));
+        assert!(matches!(&responses[2], Response::Error { .. }));
+    }
+}
@@ -0,0 +1,424 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const USB_DEVICES_DIR: &str = "bus/usb/devices";
+pub const VHCI_DRIVER_DIR: &str = "bus/platform/drivers/vhci_hcd";
+
+const DEVICE_ATTRIBUTES: &[&str] = &["idVendor", "idProduct", "serial", "product"];
+
+// Fallback for distros without the agent: runs with the sysfs root as $1
+// (default /sys) and prints what `read_snapshot` collects, minus device nodes,
+// in the format understood by `parse_dump`.
+pub const DUMP_SCRIPT: &str = r##"root="${1:-/sys}"
+[ -d "$root/bus/platform/drivers/vhci_hcd" ] && echo "#vhci"
+for d in "$root"/bus/usb/devices/*; do
+  [ -f "$d/idVendor" ] || continue
+  echo "@${d##*/}"
+  for a in idVendor idProduct serial product; do
+    [ -r "$d/$a" ] && echo "$a=$(cat "$d/$a")"
+  done
+done
+"##;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SysfsUsbDevice {
+    pub name: String,
+    pub vid_pid: String,
+    pub serial: Option<String>,
+    pub product: Option<String>,
+    #[serde(default)]
+    pub nodes: Vec<DeviceNode>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    Tty,
+    Hidraw,
+    Input,
+    Sg,
+    Block,
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct DeviceNode {
+    pub kind: NodeKind,
+    pub path: String,
+}
+
+impl NodeKind {
+    // Block devices are recognised by DEVTYPE since their names (sda, sr0,
+    // mmcblk0) follow no common prefix.
+    pub fn classify(devname: &str, devtype: Option<&str>) -> Self {
+        if matches!(devtype, Some("disk" | "partition")) {
+            NodeKind::Block
+        } else if devname.starts_with("tty") {
+            NodeKind::Tty
+        } else if devname.starts_with("hidraw") {
+            NodeKind::Hidraw
+        } else if devname.starts_with("input/") {
+            NodeKind::Input
+        } else if devname.starts_with("sg") {
+            NodeKind::Sg
+        } else {
+            NodeKind::Other
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SysfsSnapshot {
+    pub devices: Vec<SysfsUsbDevice>,
+    pub vhci_loaded: bool,
+}
+
+impl SysfsSnapshot {
+    pub fn find(&self, vid_pid: &str) -> Option<&SysfsUsbDevice> {
+        self.find_device(vid_pid, None)
+    }
+
+    // With a serial only that exact device (or one that exposes no serial to
+    // Linux) matches, so identical adapters are not confused with each other.
+    pub fn find_device(&self, vid_pid: &str, serial: Option<&str>) -> Option<&SysfsUsbDevice> {
+        let mut candidates = self
+            .devices
+            .iter()
+            .filter(|device| device.vid_pid.eq_ignore_ascii_case(vid_pid));
+        match serial {
+            None => candidates.next(),
+            Some(serial) => {
+                let candidates: Vec<_> = candidates.collect();
+                candidates
+                    .iter()
+                    .find(|device| device.serial.as_deref() == Some(serial))
+                    .or_else(|| candidates.iter().find(|device| device.serial.is_none()))
+                    .copied()
+            }
+        }
+    }
+}
+
+fn device_from_attributes(
+    name: String,
+    mut attributes: HashMap<String, String>,
+) -> Option<SysfsUsbDevice> {
+    let vendor = attributes.remove("idVendor")?;
+    let product_id = attributes.remove("idProduct")?;
+    Some(SysfsUsbDevice {
+        name,
+        vid_pid: format!("{vendor}:{product_id}").to_ascii_lowercase(),
+        serial: attributes.remove("serial").filter(|s| !s.is_empty()),
+        product: attributes.remove("product").filter(|s| !s.is_empty()),
+        nodes: Vec::new(),
+    })
+}
+
+pub fn read_snapshot(root: &Path) -> io::Result<SysfsSnapshot> {
+    let mut devices = Vec::new();
+    for entry in fs::read_dir(root.join(USB_DEVICES_DIR))? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let attributes = DEVICE_ATTRIBUTES
+            .iter()
+            .filter_map(|attribute| {
+                fs::read_to_string(path.join(attribute))
+                    .ok()
+                    .map(|value| (attribute.to_string(), value.trim().to_string()))
+            })
+            .collect();
+        if let Some(mut device) = device_from_attributes(name, attributes) {
+            device.nodes = device_nodes(&path);
+            devices.push(device);
+        }
+    }
+    devices.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(SysfsSnapshot {
+        devices,
+        vhci_loaded: root.join(VHCI_DRIVER_DIR).is_dir(),
+    })
+}
+
+// Every class device below a USB device (tty, hidraw, input, block, sg, ...)
+// has a `uevent` with DEVNAME; nested USB devices behind a hub are skipped.
+pub fn device_nodes(device_dir: &Path) -> Vec<DeviceNode> {
+    let mut nodes = Vec::new();
+    if let Ok(entries) = fs::read_dir(device_dir) {
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                collect_nodes(&entry.path(), &mut nodes);
+            }
+        }
+    }
+    nodes.sort_by(|a, b| a.path.cmp(&b.path));
+    nodes.dedup();
+    nodes
+}
+
+fn collect_nodes(dir: &Path, nodes: &mut Vec<DeviceNode>) {
+    if dir.join("idVendor").is_file() {
+        return;
+    }
+    if let Ok(uevent) = fs::read_to_string(dir.join("uevent")) {
+        let value = |key: &str| {
+            uevent
+                .lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+                .map(str::trim)
+        };
+        if let Some(devname) = value("DEVNAME") {
+            nodes.push(DeviceNode {
+                kind: NodeKind::classify(devname, value("DEVTYPE")),
+                path: format!("/dev/{devname}"),
+            });
+        }
+    }
+    // file_type() does not follow symlinks, which keeps us out of the
+    // `subsystem`/`driver`/`port` back-links that make sysfs cyclic.
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                collect_nodes(&entry.path(), nodes);
+            }
+        }
+    }
+}
+
+pub fn parse_dump(output: &str) -> SysfsSnapshot {
+    let mut snapshot = SysfsSnapshot::default();
+    let mut current: Option<(String, HashMap<String, String>)> = None;
+
+    for line in output.lines() {
+        let line = line.trim_end();
+        if line == "#vhci" {
+            snapshot.vhci_loaded = true;
+        } else if let Some(name) = line.strip_prefix('@') {
+            if let Some((name, attributes)) = current.take() {
+                snapshot
+                    .devices
+                    .extend(device_from_attributes(name, attributes));
+            }
+            current = Some((name.to_string(), HashMap::new()));
+        } else if let (Some((_, attributes)), Some((key, value))) =
+            (current.as_mut(), line.split_once('='))
+        {
+            attributes.insert(key.to_string(), value.trim().to_string());
+        }
+    }
+    if let Some((name, attributes)) = current {
+        snapshot
+            .devices
+            .extend(device_from_attributes(name, attributes));
+    }
+    snapshot.devices.sort_by(|a, b| a.name.cmp(&b.name));
+    snapshot
+}
+
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_FIXTURE: AtomicUsize = AtomicUsize::new(0);
+
+    // Builds a throwaway tree of `(relative path, contents)` files; a
+    // trailing '/' creates an empty directory.
+    pub fn tree(files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "usbipd_agent_fixture_{}_{}",
+            std::process::id(),
+            NEXT_FIXTURE.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&root);
+        for (path, contents) in files {
+            let full = root.join(path);
+            if path.ends_with('/') {
+                fs::create_dir_all(&full).unwrap();
+            } else {
+                fs::create_dir_all(full.parent().unwrap()).unwrap();
+                fs::write(&full, format!("{contents}\n")).unwrap();
+            }
+        }
+        root
+    }
+
+    #[cfg(unix)]
+    pub fn symlink(root: &std::path::Path, path: &str, target: &str) {
+        let full = root.join(path);
+        fs::create_dir_all(full.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(target, full).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    fn dump_fixture(files: &[(&str, &str)]) -> SysfsSnapshot {
+        let root = fixtures::tree(files);
+        let output = std::process::Command::new("sh")
+            .args(["-c", DUMP_SCRIPT, "sh"])
+            .arg(&root)
+            .output()
+            .unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+        parse_dump(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dumps_fixture_tree() {
+        let snapshot = dump_fixture(&[
+            ("bus/usb/devices/usb1/idVendor", "1d6b"),
+            ("bus/usb/devices/usb1/idProduct", "0002"),
+            (
+                "bus/usb/devices/usb1/product",
+                "USB/IP Virtual Host Controller",
+            ),
+            ("bus/usb/devices/1-1/idVendor", "058f"),
+            ("bus/usb/devices/1-1/idProduct", "9540"),
+            ("bus/usb/devices/1-1/product", "EMV Smartcard Reader"),
+            ("bus/usb/devices/1-1/serial", "AU9540-0001"),
+            ("bus/platform/drivers/vhci_hcd/", ""),
+        ]);
+        assert!(snapshot.vhci_loaded);
+        assert_eq!(snapshot.devices.len(), 2);
+
+        let reader = snapshot.find("058F:9540").unwrap();
+        assert_eq!(reader.name, "1-1");
+        assert_eq!(reader.serial.as_deref(), Some("AU9540-0001"));
+        assert_eq!(reader.product.as_deref(), Some("EMV Smartcard Reader"));
+        assert!(snapshot.find("2912:0008").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn detects_missing_vhci_driver() {
+        let snapshot = dump_fixture(&[
+            ("bus/usb/devices/usb1/idVendor", "1d6b"),
+            ("bus/usb/devices/usb1/idProduct", "0002"),
+            ("bus/usb/devices/usb1/power/", ""),
+        ]);
+        assert!(!snapshot.vhci_loaded);
+        assert_eq!(snapshot.devices.len(), 1);
+        assert_eq!(snapshot.devices[0].vid_pid, "1d6b:0002");
+    }
+
+    #[test]
+    fn reads_fixture_tree() {
+        let root = fixtures::tree(&[
+            ("bus/usb/devices/usb1/idVendor", "1d6b"),
+            ("bus/usb/devices/usb1/idProduct", "0002"),
+            ("bus/usb/devices/1-1/idVendor", "2912"),
+            ("bus/usb/devices/1-1/idProduct", "0008"),
+            ("bus/usb/devices/1-1/uevent", "DEVNAME=bus/usb/001/002"),
+            (
+                "bus/usb/devices/1-1/if0/tty/ttyACM0/uevent",
+                "MAJOR=166\nDEVNAME=ttyACM0",
+            ),
+            ("bus/usb/devices/1-1/if1/power/", ""),
+            ("bus/usb/devices/1-1/1-1.2/idVendor", "058f"),
+            (
+                "bus/usb/devices/1-1/1-1.2/tty/ttyACM1/uevent",
+                "DEVNAME=ttyACM1",
+            ),
+            ("bus/platform/drivers/vhci_hcd/", ""),
+        ]);
+        let snapshot = read_snapshot(&root).unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+
+        assert!(snapshot.vhci_loaded);
+        let device = snapshot.find("2912:0008").unwrap();
+        assert_eq!(
+            device.nodes,
+            vec![DeviceNode {
+                kind: NodeKind::Tty,
+                path: "/dev/ttyACM0".to_string()
+            }]
+        );
+        assert!(snapshot.find("1d6b:0002").unwrap().nodes.is_empty());
+    }
+
+    #[test]
+    fn classifies_nodes_of_composite_devices() {
+        let root = fixtures::tree(&[
+            ("bus/usb/devices/2-1/idVendor", "0781"),
+            ("bus/usb/devices/2-1/idProduct", "5581"),
+            ("bus/usb/devices/2-1/serial", "4C530001"),
+            (
+                "bus/usb/devices/2-1/if0/host0/target0/0/block/sda/uevent",
+                "MAJOR=8\nDEVNAME=sda\nDEVTYPE=disk",
+            ),
+            (
+                "bus/usb/devices/2-1/if0/host0/target0/0/block/sda/sda1/uevent",
+                "DEVNAME=sda1\nDEVTYPE=partition",
+            ),
+            (
+                "bus/usb/devices/2-1/if0/host0/target0/0/scsi_generic/sg0/uevent",
+                "DEVNAME=sg0",
+            ),
+            (
+                "bus/usb/devices/2-1/if1/hid/hidraw/hidraw1/uevent",
+                "DEVNAME=hidraw1",
+            ),
+            (
+                "bus/usb/devices/2-1/if1/hid/input/input7/event7/uevent",
+                "DEVNAME=input/event7",
+            ),
+            (
+                "bus/usb/devices/2-1/if1/hid/input/input7/uevent",
+                "PRODUCT=3/781/5581/110",
+            ),
+            ("bus/usb/devices/2-2/idVendor", "0781"),
+            ("bus/usb/devices/2-2/idProduct", "5581"),
+            ("bus/usb/devices/2-2/serial", "4C530002"),
+        ]);
+        let snapshot = read_snapshot(&root).unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+
+        let device = snapshot.find_device("0781:5581", Some("4C530001")).unwrap();
+        let nodes: Vec<_> = device
+            .nodes
+            .iter()
+            .map(|node| (node.kind, node.path.as_str()))
+            .collect();
+        assert_eq!(
+            nodes,
+            vec![
+                (NodeKind::Hidraw, "/dev/hidraw1"),
+                (NodeKind::Input, "/dev/input/event7"),
+                (NodeKind::Block, "/dev/sda"),
+                (NodeKind::Block, "/dev/sda1"),
+                (NodeKind::Sg, "/dev/sg0"),
+            ]
+        );
+        assert_eq!(
+            snapshot
+                .find_device("0781:5581", Some("4C530002"))
+                .unwrap()
+                .name,
+            "2-2"
+        );
+        assert!(snapshot.find_device("0781:5581", Some("OTHER")).is_none());
+    }
+
+    #[test]
+    fn parses_dump_output() {
+        let output = "#vhci\n@1-1\nidVendor=2912\nidProduct=0008\nproduct=ATOL USB\n\
+                      @usb1\nidVendor=1d6b\nidProduct=0002\nserial=\n";
+        let snapshot = parse_dump(output);
+        assert!(snapshot.vhci_loaded);
+        assert_eq!(snapshot.devices.len(), 2);
+        assert_eq!(snapshot.devices[0].product.as_deref(), Some("ATOL USB"));
+        assert_eq!(snapshot.devices[1].serial, None);
+    }
+}
@@ -0,0 +1,88 @@
+use crate::encoding::decode_output;
+use crate::session;
+use crate::tools::{self, Tool};
+use std::env;
+use usbipd_agent::protocol::{decode, encode, Request, Response};
+
+pub const AGENT_BINARY: &str = "usbipd_agent";
+pub const AGENT_PATH_ENV: &str = "USBIPD_AGENT_PATH";
+
+// Path of the agent as seen from inside the distro: $USBIPD_AGENT_PATH, the
+// binary shipped next to the manager (through /mnt/<drive>), or PATH lookup.
+pub fn agent_path() -> String {
+    if let Ok(path) = env::var(AGENT_PATH_ENV) {
+        if !path.trim().is_empty() {
+            return path;
+        }
+    }
+    env::current_exe()
+        .ok()
+        .map(|exe| exe.with_file_name(AGENT_BINARY))
+        .filter(|candidate| candidate.is_file())
+        .and_then(|candidate| windows_to_wsl_path(&candidate.to_string_lossy()))
+        .unwrap_or_else(|| AGENT_BINARY.to_string())
+}
+
+pub fn windows_to_wsl_path(path: &str) -> Option<String> {
+    let mut chars = path.chars();
+    let drive = chars.next().filter(char::is_ascii_alphabetic)?;
+    if chars.next() != Some(':') {
+        return None;
+    }
+    Some(format!(
+        "/mnt/{}{}",
+        drive.to_ascii_lowercase(),
+        path[2..].replace('\\', "/")
+    ))
+}
+
+pub fn query(wsl_distro: &str, request: &Request) -> Result<Response, String> {
+    run_agent(wsl_distro, &[], request)
+}
+
+// Other users' /proc/<pid>/fd entries are only readable by root.
+pub fn query_as_root(wsl_distro: &str, request: &Request) -> Result<Response, String> {
+    run_agent(wsl_distro, &["-u", "root"], request)
+}
+
+fn run_agent(wsl_distro: &str, wsl_args: &[&str], request: &Request) -> Result<Response, String> {
+    let output = session::output_with_input(
+        Tool::Wsl,
+        tools::command(Tool::Wsl)
+            .args(["-d", wsl_distro])
+            .args(wsl_args)
+            .args(["--exec", &agent_path()]),
+        &format!("{}\n", encode(request)),
+    )?;
+    if !output.status.success() {
+        return Err(format!(
+            "Агент в {wsl_distro} недоступен: {}",
+            decode_output(&output.stderr).trim()
+        ));
+    }
+
+    let stdout = decode_output(&output.stdout);
+    let line = stdout
+        .lines()
+        .next()
+        .ok_or_else(|| "Агент не вернул ответ".to_string())?;
+    match decode::<Response>(line)? {
+        Response::Error { message } => Err(message),
+        response => Ok(response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_windows_paths_for_wsl() {
+        assert_eq!(
+            windows_to_wsl_path(r"C:\Tools\usbipd_gui\usbipd_agent"),
+            Some("/mnt/c/Tools/usbipd_gui/usbipd_agent".to_string())
+        );
+        assert_eq!(windows_to_wsl_path("/usr/local/bin/usbipd_agent"), None);
+        assert_eq!(windows_to_wsl_path(r"\\server\share"), None);
+    }
+}
@@ -0,0 +1,521 @@
+// Stand-in for usbipd, wsl and powershell used to run the manager end to end
+// without Windows. The personality follows the executable's file name
+// (symlink it as `usbipd`, `wsl` and `powershell`); the virtual devices and
+// distros live in the JSON file named by $USBIPD_SIM_STATE, which every
+// command reads and updates.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process::{self, Command};
+use std::thread;
+use std::time::Duration;
+use usbipd_agent::procfs::{Holder, Mount};
+use usbipd_agent::protocol::{decode, encode, Request, Response, PROTOCOL_VERSION};
+use usbipd_agent::sysfs::{DeviceNode, SysfsSnapshot, SysfsUsbDevice};
+
+const STATE_ENV: &str = "USBIPD_SIM_STATE";
+const AUTO_ATTACH_POLL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SimState {
+    #[serde(default)]
+    distros: Vec<SimDistro>,
+    #[serde(default)]
+    devices: Vec<SimDevice>,
+    // Command name (`attach`, `bind`, `powershell`, ...) → error it fails with.
+    // `powershell <usbipd arguments>` declines the UAC prompt of that one
+    // elevated command.
+    #[serde(default)]
+    failures: HashMap<String, String>,
+    // Descriptions of the network adapters that are up (Get-NetAdapter).
+    #[serde(default)]
+    adapters: Vec<String>,
+    // Whether usbipd's port 3240 is reachable from the distros.
+    #[serde(default = "port_open")]
+    port_open: bool,
+    // Every command run, so tests can check what the manager did.
+    #[serde(default)]
+    log: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SimDistro {
+    name: String,
+    #[serde(default = "running")]
+    state: String,
+    #[serde(default = "wsl2")]
+    version: u8,
+    #[serde(default)]
+    default: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SimDevice {
+    bus_id: String,
+    vid_pid: String,
+    name: String,
+    state: String,
+    #[serde(default)]
+    serial: Option<String>,
+    // What Linux sees once the device is attached.
+    #[serde(default)]
+    nodes: Vec<DeviceNode>,
+    #[serde(default)]
+    attached_to: Option<String>,
+    // Bound with --force; usbipd keeps showing it after a detach.
+    #[serde(default)]
+    forced: bool,
+    #[serde(default)]
+    mounts: Vec<Mount>,
+    #[serde(default)]
+    holders: Vec<Holder>,
+    // Windows setup classes of the interfaces ("HIDClass", "Ports").
+    #[serde(default)]
+    setup_classes: Vec<String>,
+}
+
+impl Default for SimState {
+    fn default() -> Self {
+        serde_json::from_str("{}").unwrap()
+    }
+}
+
+fn port_open() -> bool {
+    true
+}
+
+fn running() -> String {
+    "Running".to_string()
+}
+
+fn wsl2() -> u8 {
+    2
+}
+
+type SimResult = Result<(), String>;
+
+impl SimState {
+    fn load() -> SimState {
+        let Ok(path) = env::var(STATE_ENV) else {
+            return SimState::default();
+        };
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) {
+        if let Ok(path) = env::var(STATE_ENV) {
+            if let Ok(json) = serde_json::to_string_pretty(self) {
+                let _ = fs::write(path, json);
+            }
+        }
+    }
+
+    fn fail_if_requested(&self, command: &str) -> SimResult {
+        match self.failures.get(command) {
+            Some(message) => Err(message.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn device(&mut self, bus_id: &str) -> Result<&mut SimDevice, String> {
+        self.devices
+            .iter_mut()
+            .find(|device| device.bus_id == bus_id)
+            .ok_or_else(|| format!("There is no device with busid '{bus_id}'."))
+    }
+
+    fn distro(&mut self, name: &str) -> Option<&mut SimDistro> {
+        self.distros
+            .iter_mut()
+            .find(|distro| distro.name.eq_ignore_ascii_case(name))
+    }
+}
+
+fn option<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|index| args.get(index + 1))
+        .map(String::as_str)
+}
+
+// --- usbipd ---------------------------------------------------------------
+
+fn usbipd(args: &[String]) -> i32 {
+    let mut state = SimState::load();
+    state.log.push(format!("usbipd {}", args.join(" ")));
+    let result = usbipd_command(&mut state, args);
+    state.save();
+    match result {
+        Ok(()) => 0,
+        Err(message) => {
+            eprintln!("usbipd: error: {message}");
+            1
+        }
+    }
+}
+
+fn usbipd_command(state: &mut SimState, args: &[String]) -> SimResult {
+    let command = args.first().map(String::as_str).unwrap_or("");
+    state.fail_if_requested(command)?;
+    let bus_id = || option(args, "--busid").ok_or("Missing --busid".to_string());
+    match command {
+        "--version" => println!("4.3.0-sim"),
+        "list" => print_list(state),
+        "state" => print_state(state),
+        "bind" => {
+            let forced = args.iter().any(|arg| arg == "--force");
+            let device = state.device(bus_id()?)?;
+            if device.state == "Not shared" {
+                device.state = if forced { "Shared (forced)" } else { "Shared" }.to_string();
+                device.forced = forced;
+            }
+        }
+        "unbind" => {
+            let device = state.device(bus_id()?)?;
+            device.state = "Not shared".to_string();
+            device.attached_to = None;
+            device.forced = false;
+        }
+        "attach" => {
+            let wsl_distro = option(args, "--wsl").ok_or("Missing --wsl".to_string())?;
+            let bus_id = bus_id()?.to_string();
+            if args.iter().any(|arg| arg == "--auto-attach") {
+                state.save();
+                auto_attach(&bus_id, wsl_distro);
+            }
+            attach(state, &bus_id, wsl_distro)?;
+        }
+        "detach" => {
+            let device = state.device(bus_id()?)?;
+            if device.state != "Attached" {
+                return Err(format!(
+                    "Device with busid '{}' is not attached.",
+                    device.bus_id
+                ));
+            }
+            device.state = if device.forced {
+                "Shared (forced)"
+            } else {
+                "Shared"
+            }
+            .to_string();
+            device.attached_to = None;
+        }
+        other => return Err(format!("Unknown command '{other}'.")),
+    }
+    Ok(())
+}
+
+fn attach(state: &mut SimState, bus_id: &str, wsl_distro: &str) -> SimResult {
+    match state.distro(wsl_distro) {
+        None => return Err(format!("There is no WSL distribution '{wsl_distro}'.")),
+        Some(distro) if distro.version != 2 => {
+            return Err(format!("WSL distribution '{wsl_distro}' is not WSL 2."))
+        }
+        Some(distro) if distro.state != "Running" => {
+            return Err(format!("WSL distribution '{wsl_distro}' is not running."))
+        }
+        Some(_) => {}
+    }
+    let device = state.device(bus_id)?;
+    if !device.state.starts_with("Shared") {
+        return Err(format!(
+            "Device is not shared; run 'usbipd bind --busid {bus_id}' first."
+        ));
+    }
+    device.state = "Attached".to_string();
+    device.attached_to = Some(wsl_distro.to_string());
+    Ok(())
+}
+
+// Like the real supervisor: keeps re-attaching until killed.
+fn auto_attach(bus_id: &str, wsl_distro: &str) -> ! {
+    println!("usbipd: info: Starting endless attach loop; press Ctrl+C to quit.");
+    loop {
+        let mut state = SimState::load();
+        let shared = state
+            .devices
+            .iter()
+            .any(|device| device.bus_id == bus_id && device.state.starts_with("Shared"));
+        if shared && attach(&mut state, bus_id, wsl_distro).is_ok() {
+            state.save();
+        }
+        thread::sleep(AUTO_ATTACH_POLL);
+    }
+}
+
+fn print_list(state: &SimState) {
+    println!("Connected:");
+    println!("{:<7}{:<11}{:<62}STATE", "BUSID", "VID:PID", "DEVICE");
+    for device in &state.devices {
+        println!(
+            "{:<7}{:<11}{:<62}{}",
+            device.bus_id, device.vid_pid, device.name, device.state
+        );
+    }
+    println!();
+    println!("Persisted:");
+    println!("{:<38}DEVICE", "GUID");
+}
+
+fn print_state(state: &SimState) {
+    let devices: Vec<_> = state
+        .devices
+        .iter()
+        .map(|device| {
+            let (vid, pid) = device
+                .vid_pid
+                .split_once(':')
+                .unwrap_or((&device.vid_pid, ""));
+            let instance = device
+                .serial
+                .clone()
+                .unwrap_or_else(|| "5&1A2B3C4D&0&1".to_string());
+            serde_json::json!({
+                "BusId": device.bus_id,
+                "IsForced": device.forced || device.state == "Shared (forced)",
+                "InstanceId": format!(
+                    "USB\\VID_{}&PID_{}\\{instance}",
+                    vid.to_uppercase(),
+                    pid.to_uppercase()
+                ),
+            })
+        })
+        .collect();
+    println!("{}", serde_json::json!({ "Devices": devices }));
+}
+
+// --- wsl --------------------------------------------------------------------
+
+fn utf16le(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn wsl(args: &[String]) -> i32 {
+    let mut state = SimState::load();
+    state.log.push(format!("wsl {}", args.join(" ")));
+    if args.first().map(String::as_str) == Some("--version") {
+        state.save();
+        let version = "WSL version: 2.3.26.0\r\nKernel version: 5.15.167.4-1\r\n";
+        let _ = io::stdout().write_all(&utf16le(version));
+        return 0;
+    }
+    if args.first().map(String::as_str) == Some("-l") {
+        state.save();
+        let mut table = String::from("  NAME      STATE           VERSION\r\n");
+        for distro in &state.distros {
+            let marker = if distro.default { '*' } else { ' ' };
+            table.push_str(&format!(
+                "{marker} {:<9} {:<15} {}\r\n",
+                distro.name, distro.state, distro.version
+            ));
+        }
+        let _ = io::stdout().write_all(&utf16le(&table));
+        return 0;
+    }
+
+    let Some(wsl_distro) = option(args, "-d").map(str::to_string) else {
+        let _ = io::stderr().write_all(&utf16le("Invalid command line argument.\r\n"));
+        return 1;
+    };
+    let Some(distro) = state.distro(&wsl_distro) else {
+        state.save();
+        let _ = io::stderr().write_all(&utf16le(
+            "There is no distribution with the supplied name.\r\n",
+        ));
+        return 1;
+    };
+    // Any command boots the distro.
+    distro.state = "Running".to_string();
+
+    let exec: Vec<String> = args
+        .iter()
+        .skip_while(|arg| *arg != "--exec")
+        .skip(1)
+        .cloned()
+        .collect();
+    let program = exec.first().map(String::as_str).unwrap_or("");
+    let is_agent = Path::new(program)
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with("usbipd_agent"));
+    let code = if is_agent {
+        agent(&mut state, &wsl_distro)
+    } else if program == "uname" {
+        println!("5.15.167.4-microsoft-standard-WSL2");
+        0
+    } else if program == "sh" && exec.last().map(String::as_str) == Some("3240") {
+        // The doctor's port probe.
+        if state.port_open {
+            println!("open 127.0.0.1");
+        } else {
+            println!("closed 172.22.0.1");
+        }
+        0
+    } else if program == "umount" {
+        let target = exec.last().cloned().unwrap_or_default();
+        for device in &mut state.devices {
+            device.mounts.retain(|mount| mount.target != target);
+        }
+        0
+    } else {
+        // Hooks, `true` and keep-alive sessions run on the host.
+        state.save();
+        return Command::new(program)
+            .args(&exec[1..])
+            .status()
+            .ok()
+            .and_then(|status| status.code())
+            .unwrap_or(127);
+    };
+    state.save();
+    code
+}
+
+fn agent(state: &mut SimState, wsl_distro: &str) -> i32 {
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line).is_err() {
+        return 1;
+    }
+    let response = match (state.fail_if_requested("agent"), decode::<Request>(&line)) {
+        (Err(message), _) | (_, Err(message)) => Response::Error { message },
+        (Ok(()), Ok(request)) => agent_response(state, wsl_distro, request),
+    };
+    println!("{}", encode(&response));
+    0
+}
+
+fn agent_response(state: &SimState, wsl_distro: &str, request: Request) -> Response {
+    let attached: Vec<&SimDevice> = state
+        .devices
+        .iter()
+        .filter(|device| device.attached_to.as_deref() == Some(wsl_distro))
+        .collect();
+    let snapshot = SysfsSnapshot {
+        devices: attached
+            .iter()
+            .enumerate()
+            .map(|(index, device)| SysfsUsbDevice {
+                name: format!("1-{}", index + 1),
+                vid_pid: device.vid_pid.clone(),
+                serial: device.serial.clone(),
+                product: Some(device.name.clone()),
+                nodes: device.nodes.clone(),
+            })
+            .collect(),
+        vhci_loaded: true,
+    };
+    let find = |vid_pid: &str, serial: Option<&str>| {
+        attached.iter().copied().find(|device| {
+            device.vid_pid.eq_ignore_ascii_case(vid_pid)
+                && (serial.is_none() || device.serial.as_deref() == serial)
+        })
+    };
+    match request {
+        Request::Version => Response::Version {
+            agent_version: "sim".to_string(),
+            protocol: PROTOCOL_VERSION,
+            kernel: Some("5.15.0-sim-microsoft-standard-WSL2".to_string()),
+        },
+        Request::Resolve { vid_pid, serial } => Response::Resolved {
+            device: snapshot.find_device(&vid_pid, serial.as_deref()).cloned(),
+            vhci_loaded: true,
+        },
+        Request::Snapshot => Response::Snapshot { snapshot },
+        Request::Holders { vid_pid, serial } => match find(&vid_pid, serial.as_deref()) {
+            Some(device) => Response::Holders {
+                mounts: device.mounts.clone(),
+                holders: device.holders.clone(),
+            },
+            None => Response::Holders {
+                mounts: Vec::new(),
+                holders: Vec::new(),
+            },
+        },
+    }
+}
+
+// --- powershell -------------------------------------------------------------
+
+// Only the elevation wrapper, the adapter list and the device classes are
+// understood:
+// Start-Process -FilePath 'cmd.exe' -ArgumentList '/C <usbipd> <args>' -Verb RunAs -Wait
+fn powershell(args: &[String]) -> i32 {
+    let mut state = SimState::load();
+    state.log.push(format!("powershell {}", args.join(" ")));
+    let failure = state.fail_if_requested("powershell");
+    state.save();
+    if let Err(message) = failure {
+        eprintln!("{message}");
+        return 1;
+    }
+
+    let script = option(args, "-Command").unwrap_or("");
+    if script.starts_with("Get-NetAdapter") {
+        for adapter in &state.adapters {
+            println!("{adapter}");
+        }
+        return 0;
+    }
+    if script.starts_with("Get-PnpDevice") {
+        for device in &state.devices {
+            let (vid, pid) = device.vid_pid.split_once(':').unwrap_or_default();
+            for (index, class) in device.setup_classes.iter().enumerate() {
+                println!(
+                    "USB\\VID_{}&PID_{}&MI_{index:02}\\7&1A2B3C&0&000{index}|{class}",
+                    vid.to_uppercase(),
+                    pid.to_uppercase()
+                );
+            }
+        }
+        return 0;
+    }
+    let Some(command_line) = script
+        .split_once("-ArgumentList '/C ")
+        .and_then(|(_, rest)| rest.split_once('\''))
+        .map(|(command_line, _)| command_line.trim())
+    else {
+        eprintln!("Unsupported command: {script}");
+        return 1;
+    };
+    // Skip the (possibly quoted) path of usbipd itself.
+    let arguments = match command_line.strip_prefix('"') {
+        Some(rest) => rest.split_once('"').map(|(_, args)| args).unwrap_or(""),
+        None => command_line
+            .split_once(' ')
+            .map(|(_, args)| args)
+            .unwrap_or(""),
+    };
+    let arguments: Vec<String> = arguments.split_whitespace().map(str::to_string).collect();
+    if let Err(message) = state.fail_if_requested(&format!("powershell {}", arguments.join(" "))) {
+        eprintln!("{message}");
+        return 1;
+    }
+    // Start-Process -Wait does not pass the exit code on.
+    usbipd(&arguments);
+    0
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let personality = env::args()
+        .next()
+        .and_then(|exe| {
+            Path::new(&exe)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_lowercase())
+        })
+        .unwrap_or_default();
+    let code = match personality.as_str() {
+        "wsl" => wsl(&args),
+        "powershell" => powershell(&args),
+        _ => usbipd(&args),
+    };
+    process::exit(code);
+}
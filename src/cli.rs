@@ -0,0 +1,885 @@
+use crate::config::{identity_key, load_config, load_error, save_config, Config, DeviceSettings};
+use crate::device_view::{DeviceView, SortOrder, StateFilter};
+use crate::diagnostics;
+use crate::doctor;
+use crate::executor::{
+    Executor, ExecutorEvent, Operation, OperationContext, OperationOutcome, TIMED_OUT,
+};
+use crate::history::{self, HistoryFilter};
+use crate::inventory;
+use crate::operations::{self, DetachOutcome, MoveOutcome};
+use crate::policy;
+use crate::tools::{ToolPaths, TOOLS};
+use crate::usb_ids;
+use crate::usbipd::{
+    fetch_usb_devices_with_serials, format_device_display, is_bindable_state, UsbDevice,
+};
+use crate::wsl::{
+    check_attached, describe_attach_check, find_distro, list_wsl_distros, AttachCheck,
+};
+use serde::Serialize;
+use std::time::SystemTime;
+
+const USAGE: &str = "Использование: usbipd_gui [КОМАНДА]
+
+Без аргументов запускается графический интерфейс (только Windows).
+
+Команды:
+  list [--wsl <DISTRO>] [--search <ТЕКСТ>] [--tag <ТЕГ>] [--state <СОСТОЯНИЕ>]
+       [--sort <ПОРЯДОК>] [--json]
+                                    список устройств с узлами /dev подключённых;
+                                    поиск по BUSID, VID:PID, названию, серийному
+                                    номеру, имени, заметкам и тегам; СОСТОЯНИЕ —
+                                    attached, shared или auto-attach; ПОРЯДОК —
+                                    topology (по портам), name или state
+  bind <BUSID> [--force|--no-force] [--yes]
+                                    привязать устройство (UAC); --force заменяет
+                                    драйвер Windows (по умолчанию из force_bind
+                                    в config.json); --yes подтверждает привязку
+                                    устройства из списка protect.confirm или
+                                    последней клавиатуры/мыши
+  rebind <BUSID> --force|--no-force [--yes]
+                                    перепривязать привязанное устройство в
+                                    принудительном или обычном режиме
+  unbind <BUSID>                    отвязать устройство (UAC)
+  attach <BUSID> [--wsl <DISTRO>]   подключить к WSL и проверить в дистрибутиве
+  detach <BUSID> [--wsl <DISTRO>] [--force]
+                                    отключить от WSL, предварительно проверив
+                                    открытые дескрипторы и отмонтировав ФС
+  connect <BUSID> [--wsl <DISTRO>] [--force|--no-force] [--yes]
+                                    bind + attach + проверка с откатом при ошибке
+  verify <BUSID> [--wsl <DISTRO>]   проверить, что подключённое устройство видно в WSL
+  move <BUSID> <DISTRO> [--force] [--yes]
+                                    переместить устройство в другой дистрибутив
+                                    и сохранить его в настройках устройства;
+                                    --force отключает занятое устройство, как
+                                    detach --force; --yes подтверждает привязку,
+                                    как у bind
+  doctor [--wsl <DISTRO>] [--json]  проверить usbipd, WSL, ядро, порт 3240,
+                                    USBdk/VPN и config.json
+  export-diagnostics [ФАЙЛ]         сохранить диагностический архив (JSON) для
+                                    обращения в поддержку
+  annotate <BUSID|VID:PID/SERIAL> [--nickname <ИМЯ>] [--tags <ТЕГ,...>] [--notes <ТЕКСТ>]
+                                    задать имя, теги и заметки устройства
+                                    (пустое значение удаляет их)
+  inventory [--json]                все когда-либо подключавшиеся устройства, в том
+                                    числе отключённые сейчас
+  history [--device <BUSID|VID:PID>] [--since <ВРЕМЯ>] [--limit <N>] [--json]
+                                    журнал bind/unbind/attach/detach и Auto-Attach;
+                                    ВРЕМЯ — ГГГГ-ММ-ДД (UTC), RFC 3339 или 7d/12h/30m
+  help                              эта справка
+
+Общие параметры (в том числе для графического интерфейса):
+  --usbipd <ПУТЬ>                   путь к usbipd.exe
+  --wsl-exe <ПУТЬ>                  путь к wsl.exe
+  --powershell <ПУТЬ>               путь к powershell.exe
+  --record <ФАЙЛ>                   записать вызовы usbipd/wsl/powershell и их
+                                    вывод в файл (для отчёта об ошибке)
+  --replay <ФАЙЛ>                   отвечать на вызовы из записи вместо запуска
+                                    программ";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CliCommand {
+    Help,
+    List {
+        wsl_distro: Option<String>,
+        view: DeviceView,
+        json: bool,
+    },
+    Bind {
+        bus_id: String,
+        force: Option<bool>,
+        yes: bool,
+    },
+    Rebind {
+        bus_id: String,
+        force: bool,
+        yes: bool,
+    },
+    Unbind {
+        bus_id: String,
+    },
+    Attach {
+        bus_id: String,
+        wsl_distro: Option<String>,
+    },
+    Detach {
+        bus_id: String,
+        wsl_distro: Option<String>,
+        force: bool,
+    },
+    Connect {
+        bus_id: String,
+        wsl_distro: Option<String>,
+        force: Option<bool>,
+        yes: bool,
+    },
+    Verify {
+        bus_id: String,
+        wsl_distro: Option<String>,
+    },
+    Move {
+        bus_id: String,
+        target: String,
+        force: bool,
+        yes: bool,
+    },
+    Doctor {
+        wsl_distro: Option<String>,
+        json: bool,
+    },
+    ExportDiagnostics {
+        path: Option<String>,
+    },
+    History {
+        filter: HistoryFilter,
+        json: bool,
+    },
+    Inventory {
+        json: bool,
+    },
+    Annotate {
+        target: String,
+        nickname: Option<String>,
+        tags: Option<Vec<String>>,
+        notes: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GlobalOptions {
+    pub tools: ToolPaths,
+    pub record: Option<String>,
+    pub replay: Option<String>,
+}
+
+// Executable paths and session recording apply to every command and to the
+// GUI, so they are taken out before the command itself is parsed.
+pub fn take_global_options(args: &[String]) -> Result<(GlobalOptions, Vec<String>), String> {
+    let mut options = GlobalOptions::default();
+    let mut rest = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let tool = TOOLS.iter().find(|tool| tool.cli_flag() == arg);
+        if tool.is_none() && arg != "--record" && arg != "--replay" {
+            rest.push(arg.clone());
+            continue;
+        }
+        let path = iter
+            .next()
+            .ok_or_else(|| format!("Для {arg} требуется путь"))?;
+        match tool {
+            Some(&tool) => options.tools.set(tool, path),
+            None if arg == "--record" => options.record = Some(path.clone()),
+            None => options.replay = Some(path.clone()),
+        }
+    }
+    if options.record.is_some() && options.replay.is_some() {
+        return Err("--record и --replay нельзя использовать одновременно".to_string());
+    }
+    Ok((options, rest))
+}
+
+pub fn usage_error(err: &str) -> i32 {
+    eprintln!("{err}\n\n{USAGE}");
+    2
+}
+
+pub fn parse_args(args: &[String]) -> Result<CliCommand, String> {
+    let Some((command, rest)) = args.split_first() else {
+        return Ok(CliCommand::Help);
+    };
+
+    let mut positional = Vec::new();
+    let mut wsl_distro = None;
+    let mut json = false;
+    let mut force = false;
+    let mut yes = false;
+    let mut force_bind = None;
+    let mut filter = HistoryFilter::default();
+    let mut view = DeviceView::default();
+    let (mut nickname, mut tags, mut notes) = (None, None, None);
+    let mut iter = rest.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--wsl"
+                if ["list", "attach", "detach", "connect", "verify", "doctor"]
+                    .contains(&command.as_str()) =>
+            {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| "Для --wsl требуется имя дистрибутива".to_string())?;
+                wsl_distro = Some(value.clone());
+            }
+            "--json" if ["list", "doctor", "history", "inventory"].contains(&command.as_str()) => {
+                json = true
+            }
+            "--search" | "--tag" | "--state" | "--sort" if command == "list" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| format!("Для {arg} требуется значение"))?;
+                match arg.as_str() {
+                    "--search" => view.search = value.clone(),
+                    "--tag" => view.tag = Some(value.clone()),
+                    "--state" => view.state = StateFilter::parse(value)?,
+                    _ => view.sort = SortOrder::parse(value)?,
+                }
+            }
+            "--nickname" | "--tags" | "--notes" if command == "annotate" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| format!("Для {arg} требуется значение"))?;
+                match arg.as_str() {
+                    "--nickname" => nickname = Some(value.clone()),
+                    "--tags" => tags = Some(value.split(',').map(str::to_string).collect()),
+                    _ => notes = Some(value.clone()),
+                }
+            }
+            "--device" | "--since" | "--limit" if command == "history" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| format!("Для {arg} требуется значение"))?;
+                match arg.as_str() {
+                    "--device" => filter.device = Some(value.clone()),
+                    "--since" => {
+                        filter.since = Some(history::parse_since(value, SystemTime::now())?)
+                    }
+                    _ => {
+                        let limit = value
+                            .parse()
+                            .map_err(|_| format!("Неверное значение --limit: {value}"))?;
+                        filter.limit = Some(limit);
+                    }
+                }
+            }
+            "--force" if ["detach", "move"].contains(&command.as_str()) => force = true,
+            "--force" | "--no-force"
+                if ["bind", "connect", "rebind"].contains(&command.as_str()) =>
+            {
+                force_bind = Some(arg == "--force")
+            }
+            "--yes" if ["bind", "connect", "move", "rebind"].contains(&command.as_str()) => {
+                yes = true
+            }
+            flag if flag.starts_with("--") => return Err(format!("Неизвестный параметр: {flag}")),
+            value => positional.push(value.to_string()),
+        }
+    }
+
+    let bus_id = || {
+        positional
+            .first()
+            .cloned()
+            .ok_or_else(|| format!("Для команды {command} требуется BUSID"))
+    };
+
+    match command.as_str() {
+        "help" | "--help" | "-h" => Ok(CliCommand::Help),
+        "list" => Ok(CliCommand::List {
+            wsl_distro,
+            view,
+            json,
+        }),
+        "bind" => Ok(CliCommand::Bind {
+            bus_id: bus_id()?,
+            force: force_bind,
+            yes,
+        }),
+        "rebind" => Ok(CliCommand::Rebind {
+            bus_id: bus_id()?,
+            force: force_bind
+                .ok_or_else(|| "Для rebind требуется --force или --no-force".to_string())?,
+            yes,
+        }),
+        "unbind" => Ok(CliCommand::Unbind { bus_id: bus_id()? }),
+        "attach" => Ok(CliCommand::Attach {
+            bus_id: bus_id()?,
+            wsl_distro,
+        }),
+        "detach" => Ok(CliCommand::Detach {
+            bus_id: bus_id()?,
+            wsl_distro,
+            force,
+        }),
+        "connect" => Ok(CliCommand::Connect {
+            bus_id: bus_id()?,
+            wsl_distro,
+            force: force_bind,
+            yes,
+        }),
+        "verify" => Ok(CliCommand::Verify {
+            bus_id: bus_id()?,
+            wsl_distro,
+        }),
+        "move" => Ok(CliCommand::Move {
+            bus_id: bus_id()?,
+            target: positional
+                .get(1)
+                .cloned()
+                .ok_or_else(|| "Для команды move требуется имя дистрибутива".to_string())?,
+            force,
+            yes,
+        }),
+        "doctor" => Ok(CliCommand::Doctor { wsl_distro, json }),
+        "export-diagnostics" => Ok(CliCommand::ExportDiagnostics {
+            path: positional.first().cloned(),
+        }),
+        "history" => Ok(CliCommand::History { filter, json }),
+        "inventory" => Ok(CliCommand::Inventory { json }),
+        "annotate" if nickname.is_none() && tags.is_none() && notes.is_none() => {
+            Err("Для annotate требуется --nickname, --tags или --notes".to_string())
+        }
+        "annotate" => Ok(CliCommand::Annotate {
+            target: bus_id()?,
+            nickname,
+            tags,
+            notes,
+        }),
+        other => Err(format!("Неизвестная команда: {other}")),
+    }
+}
+
+pub fn run(args: &[String]) -> i32 {
+    let command = match parse_args(args) {
+        Ok(command) => command,
+        Err(err) => return usage_error(&err),
+    };
+
+    match execute(command) {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("Ошибка: {err}");
+            1
+        }
+    }
+}
+
+impl CliCommand {
+    fn wsl_distro(&self) -> Option<&str> {
+        match self {
+            CliCommand::List { wsl_distro, .. }
+            | CliCommand::Attach { wsl_distro, .. }
+            | CliCommand::Detach { wsl_distro, .. }
+            | CliCommand::Connect { wsl_distro, .. }
+            | CliCommand::Verify { wsl_distro, .. }
+            | CliCommand::Doctor { wsl_distro, .. } => wsl_distro.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+// `list --json`: the device with its user-defined description and the names
+// from usb.ids.
+#[derive(Serialize)]
+struct ListedDevice<'a> {
+    #[serde(flatten)]
+    device: &'a UsbDevice,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vendor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    product: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    class: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nickname: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    tags: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notes: Option<&'a str>,
+}
+
+fn execute(command: CliCommand) -> Result<(), String> {
+    let mut config = load_config();
+    if let Some(wsl_distro) = command.wsl_distro() {
+        config.override_wsl_distro(wsl_distro);
+    }
+
+    match command {
+        CliCommand::Help => println!("{USAGE}"),
+        CliCommand::List { view, json, .. } => {
+            let devices = view.apply(&operations::list_devices(&config)?, &config);
+            if json {
+                let listed: Vec<ListedDevice> = devices
+                    .iter()
+                    .map(|(device, settings)| {
+                        let info = usb_ids::describe(device);
+                        ListedDevice {
+                            device,
+                            vendor: info.vendor,
+                            product: info.product,
+                            class: info.class,
+                            nickname: settings.nickname.as_deref(),
+                            tags: &settings.tags,
+                            notes: settings.notes.as_deref(),
+                        }
+                    })
+                    .collect();
+                let output = serde_json::to_string_pretty(&listed)
+                    .map_err(|e| format!("Ошибка сериализации: {e}"))?;
+                println!("{output}");
+            } else {
+                for (device, settings) in &devices {
+                    println!(
+                        "{}",
+                        format_device_display(
+                            device,
+                            settings.nickname.as_deref(),
+                            &settings.tags,
+                            settings.auto_attach
+                        )
+                    );
+                    if let Some(notes) = &settings.notes {
+                        println!("    {notes}");
+                    }
+                }
+            }
+        }
+        CliCommand::Annotate {
+            target,
+            nickname,
+            tags,
+            notes,
+        } => {
+            let device = find_device_or_known(&target)?;
+            config.annotate(&device, nickname, tags, notes);
+            save_config(&config)?;
+            let settings = config.settings_for(&device);
+            println!(
+                "{}",
+                format_device_display(
+                    &device,
+                    settings.nickname.as_deref(),
+                    &settings.tags,
+                    settings.auto_attach
+                )
+            );
+        }
+        CliCommand::Bind { bus_id, force, yes } => {
+            let device = find_device(&bus_id)?;
+            let mut settings = config.settings_for(&device);
+            settings.force_bind = force.unwrap_or(settings.force_bind);
+            check_bind_policy(&config, &device, &settings, yes)?;
+            run_operation(format!("Bind {bus_id}"), &bus_id, move |ctx| {
+                operations::bind(ctx, &device, &settings)
+            })?;
+        }
+        CliCommand::Unbind { bus_id } => {
+            let device = find_device(&bus_id)?;
+            run_operation(format!("Unbind {bus_id}"), &bus_id, move |ctx| {
+                operations::unbind(ctx, &device)
+            })?;
+        }
+        CliCommand::Attach { bus_id, .. } => {
+            let device = find_device(&bus_id)?;
+            let settings = config.settings_for(&device);
+            let (target, target_settings) = (device.clone(), settings.clone());
+            let found = run_operation(format!("Attach {bus_id}"), &bus_id, move |ctx| {
+                operations::attach_and_verify(ctx, &target, &target_settings)
+            })?;
+            let check = AttachCheck::Present(found);
+            println!(
+                "{}",
+                describe_attach_check(&check, &device.vid_pid, &settings.wsl_distro)
+            );
+        }
+        CliCommand::Detach { bus_id, force, .. } => {
+            let device = find_device(&bus_id)?;
+            let settings = config.settings_for(&device);
+            let wsl_distro = settings.wsl_distro.clone();
+            let outcome = run_operation(format!("Detach {bus_id}"), &bus_id, move |ctx| {
+                operations::safe_detach(ctx, &device, &settings, force)
+            })?;
+            if let Some(refusal) = outcome.refusal(&bus_id, &wsl_distro) {
+                return Err(format!("{refusal}\n{}", detach_hint(&outcome)));
+            }
+        }
+        CliCommand::Rebind { bus_id, force, yes } => {
+            let device = find_device(&bus_id)?;
+            let mut settings = config.settings_for(&device);
+            settings.force_bind = force;
+            if force {
+                check_bind_policy(&config, &device, &settings, yes)?;
+            }
+            run_operation(format!("Rebind {bus_id}"), &bus_id, move |ctx| {
+                operations::rebind(ctx, &device, &settings)
+            })?;
+        }
+        CliCommand::Connect {
+            bus_id, force, yes, ..
+        } => {
+            let device = find_device(&bus_id)?;
+            let mut settings = config.settings_for(&device);
+            settings.force_bind = force.unwrap_or(settings.force_bind);
+            if is_bindable_state(&device.state) {
+                check_bind_policy(&config, &device, &settings, yes)?;
+            }
+            let (target, target_settings) = (device.clone(), settings.clone());
+            let found = run_operation(format!("Connect {bus_id}"), &bus_id, move |ctx| {
+                operations::connect_to_wsl(ctx, &target, &target_settings)
+            })?;
+            let check = AttachCheck::Present(found);
+            println!(
+                "{}",
+                describe_attach_check(&check, &device.vid_pid, &settings.wsl_distro)
+            );
+        }
+        CliCommand::Verify { bus_id, .. } => {
+            let device = find_device(&bus_id)?;
+            let wsl_distro = config.settings_for(&device).wsl_distro;
+            if device.state != "Attached" {
+                return Err(format!(
+                    "Устройство {bus_id} не подключено к WSL (состояние: {})",
+                    device.state
+                ));
+            }
+            let check = check_attached(&wsl_distro, &device.vid_pid, device.serial.as_deref())?;
+            let message = describe_attach_check(&check, &device.vid_pid, &wsl_distro);
+            match check {
+                AttachCheck::Present(_) => println!("{message}"),
+                AttachCheck::Missing { .. } => return Err(message),
+            }
+        }
+        CliCommand::Doctor { json, .. } => {
+            let results = doctor::run_doctor(&config);
+            if json {
+                let output = serde_json::to_string_pretty(&results)
+                    .map_err(|e| format!("Ошибка сериализации: {e}"))?;
+                println!("{output}");
+            } else {
+                println!("{}", doctor::format_report(&results));
+            }
+            if doctor::has_failures(&results) {
+                return Err("диагностика обнаружила ошибки".to_string());
+            }
+        }
+        CliCommand::ExportDiagnostics { path } => {
+            let path = path.unwrap_or_else(diagnostics::default_bundle_path);
+            // A new process has no operations of its own to report.
+            let bundle = diagnostics::collect_bundle(&config, Vec::new());
+            diagnostics::export_bundle(&path, &bundle)?;
+            println!("Диагностика сохранена в {path}");
+        }
+        CliCommand::Inventory { json } => {
+            // Refreshes the inventory with what is connected right now.
+            let devices = fetch_usb_devices_with_serials()?;
+            inventory::observe(&devices);
+            let entries = inventory::entries();
+            if json {
+                let output = serde_json::to_string_pretty(&entries)
+                    .map_err(|e| format!("Ошибка сериализации: {e}"))?;
+                println!("{output}");
+                return Ok(());
+            }
+            let offline = inventory::offline(&devices);
+            for entry in &entries {
+                let settings = config.settings_for(&entry.as_device());
+                let connected = !offline.contains(entry);
+                println!("{}", entry.display(&settings, connected));
+            }
+        }
+        CliCommand::History { filter, json } => {
+            let entries = history::query(&filter)?;
+            if json {
+                let output = serde_json::to_string_pretty(&entries)
+                    .map_err(|e| format!("Ошибка сериализации: {e}"))?;
+                println!("{output}");
+            } else {
+                for entry in &entries {
+                    println!("{}", entry.display());
+                }
+            }
+        }
+        CliCommand::Move {
+            bus_id,
+            target,
+            force,
+            yes,
+        } => {
+            let distros = list_wsl_distros().unwrap_or_default();
+            if !distros.is_empty() && find_distro(&distros, &target).is_none() {
+                let names: Vec<&str> = distros.iter().map(|distro| distro.name.as_str()).collect();
+                return Err(format!(
+                    "Дистрибутив {target} не найден. Доступны: {}",
+                    names.join(", ")
+                ));
+            }
+            let device = find_device(&bus_id)?;
+            let settings = config.settings_for(&device);
+            // The move binds a device that is not shared yet.
+            if is_bindable_state(&device.state) {
+                check_bind_policy(&config, &device, &settings, yes)?;
+            }
+            let wsl_distro = settings.wsl_distro.clone();
+            let (moved, distro) = (device.clone(), target.clone());
+            let outcome = run_operation(format!("Move {bus_id}"), &bus_id, move |ctx| {
+                operations::move_to_distro(ctx, &moved, &settings, &distro, force)
+            })?;
+            let found = match outcome {
+                MoveOutcome::Moved(found) => found,
+                MoveOutcome::NotDetached(outcome) => {
+                    let refusal = outcome.refusal(&bus_id, &wsl_distro).unwrap_or_default();
+                    return Err(format!("{refusal}\n{}", detach_hint(&outcome)));
+                }
+            };
+            config.set_device_distro(&device, &target);
+            save_config(&config)?;
+            let check = AttachCheck::Present(found);
+            println!(
+                "{}",
+                describe_attach_check(&check, &device.vid_pid, &target)
+            );
+        }
+    }
+    Ok(())
+}
+
+fn detach_hint(outcome: &DetachOutcome) -> &'static str {
+    match outcome {
+        DetachOutcome::Unverified(_) => {
+            "Установите usbipd_agent (см. README) или повторите с --force"
+        }
+        _ => "Закройте эти процессы или повторите с --force",
+    }
+}
+
+// There is nobody to ask, so confirmations are given up front with --yes.
+fn check_bind_policy(
+    config: &Config,
+    device: &UsbDevice,
+    settings: &DeviceSettings,
+    yes: bool,
+) -> Result<(), String> {
+    let devices = fetch_usb_devices_with_serials()?;
+    let mut confirmations =
+        policy::bind_confirmations(&config.protect, &settings.protection, device, &devices)?;
+    if let Some(err) = load_error() {
+        confirmations.insert(0, format!("Правила protect не загружены: {err}"));
+    }
+    if confirmations.is_empty() {
+        return Ok(());
+    }
+    if !yes {
+        return Err(format!(
+            "{}. Чтобы всё равно привязать устройство, повторите команду с --yes",
+            confirmations.join("; ")
+        ));
+    }
+    for confirmation in confirmations {
+        eprintln!("Предупреждение: {confirmation}");
+    }
+    Ok(())
+}
+
+fn find_device(bus_id: &str) -> Result<UsbDevice, String> {
+    fetch_usb_devices_with_serials()?
+        .into_iter()
+        .find(|device| device.bus_id == bus_id)
+        .ok_or_else(|| format!("Устройство {bus_id} не найдено"))
+}
+
+// Also accepts VID:PID/SERIAL and devices that are not plugged in but are in
+// the inventory.
+fn find_device_or_known(target: &str) -> Result<UsbDevice, String> {
+    let devices = fetch_usb_devices_with_serials()?;
+    inventory::observe(&devices);
+    if let Some(device) = devices
+        .into_iter()
+        .find(|device| device.bus_id == target || identity_key(device).eq_ignore_ascii_case(target))
+    {
+        return Ok(device);
+    }
+    inventory::entries()
+        .into_iter()
+        .find(|entry| entry.key().eq_ignore_ascii_case(target))
+        .map(|entry| entry.as_device())
+        .ok_or_else(|| format!("Устройство {target} не найдено"))
+}
+
+fn run_operation<T: Send + 'static>(
+    label: String,
+    bus_id: &str,
+    task: impl FnOnce(&OperationContext) -> Result<T, String> + Send + 'static,
+) -> Result<T, String> {
+    let (executor, events) = Executor::new(1, || {});
+    executor.submit(Operation::new(label, task).for_device(bus_id));
+
+    for event in events.iter() {
+        match event {
+            ExecutorEvent::Progress { message, .. } => eprintln!("{message}"),
+            ExecutorEvent::Warning { message, .. } => eprintln!("Предупреждение: {message}"),
+            ExecutorEvent::Finished { outcome, .. } => {
+                return match outcome {
+                    OperationOutcome::Succeeded(value) => Ok(value),
+                    OperationOutcome::Failed(err) => Err(err),
+                    OperationOutcome::Cancelled => Err("Операция отменена".to_string()),
+                    OperationOutcome::TimedOut => Err(TIMED_OUT.to_string()),
+                };
+            }
+            _ => {}
+        }
+    }
+    Err("Исполнитель операций остановлен".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_commands_with_distro() {
+        assert_eq!(
+            parse_args(&args(&["verify", "2-7", "--wsl", "Debian"])),
+            Ok(CliCommand::Verify {
+                bus_id: "2-7".to_string(),
+                wsl_distro: Some("Debian".to_string()),
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["attach", "2-7"])),
+            Ok(CliCommand::Attach {
+                bus_id: "2-7".to_string(),
+                wsl_distro: None,
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["list", "--json", "--search", "касса"])),
+            Ok(CliCommand::List {
+                wsl_distro: None,
+                view: DeviceView {
+                    search: "касса".to_string(),
+                    ..DeviceView::default()
+                },
+                json: true,
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["list", "--state", "auto-attach", "--sort", "name"])),
+            Ok(CliCommand::List {
+                wsl_distro: None,
+                view: DeviceView {
+                    state: StateFilter::AutoAttach,
+                    sort: SortOrder::Name,
+                    ..DeviceView::default()
+                },
+                json: false,
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&[
+                "annotate",
+                "2912:0008/00106",
+                "--tags",
+                "склад,эцп"
+            ])),
+            Ok(CliCommand::Annotate {
+                target: "2912:0008/00106".to_string(),
+                nickname: None,
+                tags: Some(vec!["склад".to_string(), "эцп".to_string()]),
+                notes: None,
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["bind", "2-7", "--yes"])),
+            Ok(CliCommand::Bind {
+                bus_id: "2-7".to_string(),
+                force: None,
+                yes: true,
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["rebind", "2-7", "--no-force"])),
+            Ok(CliCommand::Rebind {
+                bus_id: "2-7".to_string(),
+                force: false,
+                yes: false,
+            })
+        );
+        assert!(parse_args(&args(&["rebind", "2-7"])).is_err());
+        assert_eq!(
+            parse_args(&args(&["detach", "2-7", "--force"])),
+            Ok(CliCommand::Detach {
+                bus_id: "2-7".to_string(),
+                wsl_distro: None,
+                force: true,
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["move", "2-7", "Debian", "--force", "--yes"])),
+            Ok(CliCommand::Move {
+                bus_id: "2-7".to_string(),
+                target: "Debian".to_string(),
+                force: true,
+                yes: true,
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["doctor", "--json", "--wsl", "Debian"])),
+            Ok(CliCommand::Doctor {
+                wsl_distro: Some("Debian".to_string()),
+                json: true,
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&[
+                "history",
+                "--device",
+                "2-7",
+                "--since",
+                "2024-05-01"
+            ])),
+            Ok(CliCommand::History {
+                filter: HistoryFilter {
+                    device: Some("2-7".to_string()),
+                    since: Some("2024-05-01T00:00:00.000Z".to_string()),
+                    limit: None,
+                },
+                json: false,
+            })
+        );
+        assert_eq!(parse_args(&[]), Ok(CliCommand::Help));
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(parse_args(&args(&["bind"])).is_err());
+        assert!(parse_args(&args(&["attach", "2-7", "--wsl"])).is_err());
+        assert!(parse_args(&args(&["frobnicate"])).is_err());
+        assert!(parse_args(&args(&["list", "--verbose"])).is_err());
+        assert!(parse_args(&args(&["bind", "2-7", "--json"])).is_err());
+        assert!(parse_args(&args(&["attach", "2-7", "--force"])).is_err());
+        assert!(parse_args(&args(&["move", "2-7"])).is_err());
+        assert!(parse_args(&args(&["bind", "2-7", "--wsl", "Debian"])).is_err());
+        assert!(parse_args(&args(&["annotate", "2-7"])).is_err());
+        assert!(parse_args(&args(&["history", "--limit", "много"])).is_err());
+        assert!(parse_args(&args(&["list", "--since", "7d"])).is_err());
+        assert!(take_global_options(&args(&["list", "--usbipd"])).is_err());
+    }
+
+    #[test]
+    fn takes_global_options_anywhere() {
+        let (options, rest) = take_global_options(&args(&[
+            "--usbipd",
+            r"D:\usbipd-win\usbipd.exe",
+            "attach",
+            "2-7",
+            "--wsl-exe",
+            "/opt/sim/wsl",
+            "--wsl",
+            "Debian",
+            "--record",
+            "session.json",
+        ]))
+        .unwrap();
+        let paths = &options.tools;
+        assert_eq!(paths.usbipd.as_deref(), Some(r"D:\usbipd-win\usbipd.exe"));
+        assert_eq!(paths.wsl.as_deref(), Some("/opt/sim/wsl"));
+        assert_eq!(paths.powershell, None);
+        assert_eq!(options.record.as_deref(), Some("session.json"));
+        assert_eq!(rest, args(&["attach", "2-7", "--wsl", "Debian"]));
+        assert!(take_global_options(&args(&["--record", "a", "--replay", "b"])).is_err());
+    }
+}
@@ -1,15 +1,270 @@
+use crate::device_view::DeviceView;
+use crate::history::{self, HistoryRetention};
+use crate::hooks::Hooks;
+use crate::logging::{self, LogLevel};
+use crate::policy::{Protection, ProtectionPolicy};
+use crate::tools::{self, ToolPaths};
+use crate::usb_ids;
+use crate::usbipd::UsbDevice;
+use crate::wsl::list_wsl_distros;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::{Read, Write};
-use std::process::Command;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 const CONFIG_PATH: &str = "config.json";
 
+// Set when config.json exists but cannot be read. Such a file is never
+// overwritten, so a typo does not silently drop the protection rules.
+static LOAD_ERROR: Mutex<Option<String>> = Mutex::new(None);
+pub const STATE_DIR_ENV: &str = "USBIPD_GUI_STATE_DIR";
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Config {
     pub auto_attach_devices: Vec<String>,
     #[serde(default = "default_wsl_distro")]
     pub wsl_distro: String,
+    // Boot a stopped distro before attaching to it.
+    #[serde(default = "default_true")]
+    pub start_distro: bool,
+    // Hold a session open in distros with attached devices so WSL does not
+    // shut them down when idle.
+    #[serde(default = "default_true")]
+    pub keep_distro_alive: bool,
+    // `usbipd bind --force` replaces the Windows driver at once, so the
+    // device stops working on the host even before it is attached. Devices
+    // can override it.
+    #[serde(default)]
+    pub force_bind: bool,
+    #[serde(default, skip_serializing_if = "ToolPaths::is_empty")]
+    pub tools: ToolPaths,
+    // error, warn, info or debug.
+    #[serde(default)]
+    pub log_level: LogLevel,
+    #[serde(default, skip_serializing_if = "HistoryRetention::is_default")]
+    pub history: HistoryRetention,
+    #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+    pub hooks: Hooks,
+    // Devices that may not be bound, or only after a confirmation.
+    #[serde(default, skip_serializing_if = "ProtectionPolicy::is_default")]
+    pub protect: ProtectionPolicy,
+    // Search, filters and sorting of the device list in the window.
+    #[serde(default, skip_serializing_if = "DeviceView::is_default")]
+    pub view: DeviceView,
+    // Keyed by VID:PID/serial, bus id or VID:PID; a device uses the first of
+    // these it has an entry for (see `device_key`). Serial-keyed settings
+    // follow a device between ports, VID:PID ones apply to all identical
+    // devices.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub devices: HashMap<String, DeviceConfig>,
+}
+
+// Unset fields fall back to the global settings.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nickname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wsl_distro: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_attach: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub force_bind: Option<bool>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+    pub hooks: Hooks,
+}
+
+// What actually applies to one device once per-device values and global
+// fallbacks are combined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceSettings {
+    pub nickname: Option<String>,
+    pub tags: Vec<String>,
+    pub notes: Option<String>,
+    pub wsl_distro: String,
+    pub auto_attach: bool,
+    pub force_bind: bool,
+    pub protection: Protection,
+    pub start_distro: bool,
+    // Global hooks first, then the device's own.
+    pub hooks: Vec<Hooks>,
+}
+
+impl DeviceSettings {
+    // Case-insensitive search across what identifies a device to a person,
+    // including the names from usb.ids.
+    pub fn matches(&self, device: &UsbDevice, query: &str) -> bool {
+        let query = query.trim().to_lowercase();
+        let info = usb_ids::describe(device);
+        let fields = [
+            Some(device.bus_id.as_str()),
+            Some(device.vid_pid.as_str()),
+            Some(device.device_name.as_str()),
+            device.serial.as_deref(),
+            self.nickname.as_deref(),
+            self.notes.as_deref(),
+            info.vendor.as_deref(),
+            info.product.as_deref(),
+            info.class.as_deref(),
+        ];
+        fields
+            .iter()
+            .flatten()
+            .copied()
+            .chain(self.tags.iter().map(String::as_str))
+            .any(|field| field.to_lowercase().contains(&query))
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        let tag = tag.trim_start_matches('#').to_lowercase();
+        self.tags.iter().any(|own| own.to_lowercase() == tag)
+    }
+}
+
+// Names a single physical device: VID:PID and serial number when the device
+// has one, otherwise the port it is plugged into.
+pub fn identity_key(device: &UsbDevice) -> String {
+    match &device.serial {
+        Some(serial) => format!("{}/{serial}", device.vid_pid),
+        None => device.bus_id.clone(),
+    }
+}
+
+impl Config {
+    // The most specific entry wins: VID:PID/serial, then bus id, then VID:PID
+    // (shared by all identical devices).
+    fn device_key(&self, device: &UsbDevice) -> Option<String> {
+        if device.serial.is_some() {
+            let identity = identity_key(device);
+            if let Some(key) = self
+                .devices
+                .keys()
+                .find(|key| key.eq_ignore_ascii_case(&identity))
+            {
+                return Some(key.clone());
+            }
+        }
+        if self.devices.contains_key(&device.bus_id) {
+            return Some(device.bus_id.clone());
+        }
+        self.devices
+            .keys()
+            .find(|key| key.eq_ignore_ascii_case(&device.vid_pid))
+            .cloned()
+    }
+
+    pub fn device_config(&self, device: &UsbDevice) -> Option<&DeviceConfig> {
+        self.device_key(device)
+            .and_then(|key| self.devices.get(&key))
+    }
+
+    pub fn settings_for(&self, device: &UsbDevice) -> DeviceSettings {
+        let device_config = self.device_config(device).cloned().unwrap_or_default();
+        let nickname = device_config
+            .nickname
+            .filter(|name| !name.trim().is_empty());
+        DeviceSettings {
+            protection: self.protect.check(device, nickname.as_deref()),
+            nickname,
+            tags: device_config.tags,
+            notes: device_config.notes.filter(|notes| !notes.trim().is_empty()),
+            wsl_distro: device_config
+                .wsl_distro
+                .filter(|distro| !distro.trim().is_empty())
+                .unwrap_or_else(|| self.wsl_distro.clone()),
+            auto_attach: device_config
+                .auto_attach
+                .unwrap_or_else(|| self.auto_attach_devices.contains(&device.bus_id)),
+            force_bind: device_config.force_bind.unwrap_or(self.force_bind),
+            start_distro: self.start_distro,
+            hooks: vec![self.hooks.clone(), device_config.hooks],
+        }
+    }
+
+    // Moves this device only, not every device sharing its VID:PID entry.
+    pub fn set_device_distro(&mut self, device: &UsbDevice, wsl_distro: &str) {
+        if self.device_config(device).is_none() && wsl_distro == self.wsl_distro {
+            return;
+        }
+        self.own_entry(device).wsl_distro = Some(wsl_distro.to_string());
+    }
+
+    // The entry under the device's identity key, for settings that belong to
+    // one physical device. A new entry starts as a copy of the bus id or
+    // VID:PID entry the device used so far, so nothing else changes for it.
+    fn own_entry(&mut self, device: &UsbDevice) -> &mut DeviceConfig {
+        let identity = identity_key(device);
+        let key = match self
+            .devices
+            .keys()
+            .find(|key| key.eq_ignore_ascii_case(&identity))
+        {
+            Some(key) => key.clone(),
+            None => {
+                let inherited = self.device_config(device).cloned().unwrap_or_default();
+                self.devices.insert(identity.clone(), inherited);
+                identity
+            }
+        };
+        self.devices.get_mut(&key).unwrap()
+    }
+
+    // `None` leaves a field as it is, an empty value clears it. Identical
+    // devices sharing a VID:PID entry are annotated one by one.
+    pub fn annotate(
+        &mut self,
+        device: &UsbDevice,
+        nickname: Option<String>,
+        tags: Option<Vec<String>>,
+        notes: Option<String>,
+    ) {
+        let device_config = self.own_entry(device);
+        let non_empty = |value: String| Some(value).filter(|value| !value.trim().is_empty());
+        if let Some(nickname) = nickname {
+            device_config.nickname = non_empty(nickname);
+        }
+        if let Some(tags) = tags {
+            device_config.tags = tags
+                .into_iter()
+                .map(|tag| tag.trim().trim_start_matches('#').to_string())
+                .filter(|tag| !tag.is_empty())
+                .collect();
+        }
+        if let Some(notes) = notes {
+            device_config.notes = non_empty(notes);
+        }
+    }
+
+    // `--wsl` on the command line beats every configured distro.
+    pub fn override_wsl_distro(&mut self, wsl_distro: &str) {
+        for device_config in self.devices.values_mut() {
+            device_config.wsl_distro = None;
+        }
+        self.wsl_distro = wsl_distro.to_string();
+    }
+
+    // An explicit per-device value wins over the list, so it is kept in sync,
+    // for this device only and not every device sharing its VID:PID entry.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn set_auto_attach(&mut self, device: &UsbDevice, enabled: bool) {
+        let explicit = self
+            .device_config(device)
+            .is_some_and(|device_config| device_config.auto_attach.is_some());
+        if explicit {
+            self.own_entry(device).auto_attach = Some(enabled);
+        }
+        self.auto_attach_devices.retain(|id| id != &device.bus_id);
+        if enabled {
+            self.auto_attach_devices.push(device.bus_id.clone());
+        }
+    }
 }
 
 impl Default for Config {
@@ -17,78 +272,284 @@ impl Default for Config {
         Self {
             auto_attach_devices: Vec::new(),
             wsl_distro: detect_default_wsl_distro(),
+            start_distro: true,
+            keep_distro_alive: true,
+            force_bind: false,
+            tools: ToolPaths::default(),
+            log_level: LogLevel::default(),
+            history: HistoryRetention::default(),
+            hooks: Hooks::default(),
+            protect: ProtectionPolicy::default(),
+            view: DeviceView::default(),
+            devices: HashMap::new(),
         }
     }
 }
 
+fn default_true() -> bool {
+    true
+}
+
 fn default_wsl_distro() -> String {
     detect_default_wsl_distro()
 }
 
+// Per-user directory for the log, the operation history and the device
+// inventory:
+// $USBIPD_GUI_STATE_DIR, else %LOCALAPPDATA%\usbipd_gui on Windows and
+// $XDG_STATE_HOME (~/.local/state)/usbipd_gui elsewhere.
+pub fn state_dir() -> Option<PathBuf> {
+    let var = |name: &str| env::var(name).ok().filter(|value| !value.trim().is_empty());
+    if let Some(dir) = var(STATE_DIR_ENV) {
+        return Some(PathBuf::from(dir));
+    }
+    let base = if cfg!(windows) {
+        var("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        var("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| var("HOME").map(|home| Path::new(&home).join(".local/state")))
+    };
+    base.map(|base| base.join("usbipd_gui"))
+}
+
+// `Ok(None)` when there is no config.json yet.
+pub fn parse_config_file() -> Result<Option<Config>, String> {
+    let mut file = match File::open(CONFIG_PATH) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Не удалось открыть {CONFIG_PATH}: {e}")),
+    };
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .map_err(|e| format!("Не удалось прочитать {CONFIG_PATH}: {e}"))?;
+    serde_json::from_str(&contents)
+        .map(Some)
+        .map_err(|e| format!("Ошибка в {CONFIG_PATH}: {e}"))
+}
+
 pub fn load_config() -> Config {
-    if let Ok(mut file) = File::open(CONFIG_PATH) {
-        let mut contents = String::new();
-        if file.read_to_string(&mut contents).is_ok() {
-            if let Ok(mut config) = serde_json::from_str::<Config>(&contents) {
-                if config.wsl_distro.trim().is_empty() {
-                    config.wsl_distro = detect_default_wsl_distro();
-                }
-                return config;
+    let parsed = parse_config_file();
+    *LOAD_ERROR.lock().unwrap() = parsed.as_ref().err().cloned();
+    match parsed {
+        Ok(Some(mut config)) => {
+            tools::set_config_paths(config.tools.clone());
+            logging::set_level(config.log_level);
+            history::set_retention(&config.history);
+            if config.wsl_distro.trim().is_empty() {
+                config.wsl_distro = detect_default_wsl_distro();
             }
+            return config;
         }
+        Ok(None) => {}
+        Err(err) => logging::error(format!("{err}. Используются настройки по умолчанию")),
     }
+    history::set_retention(&HistoryRetention::default());
     Config::default()
 }
 
-pub fn save_config(config: &Config) {
-    if let Ok(mut file) = File::create(CONFIG_PATH) {
-        if let Ok(json) = serde_json::to_string_pretty(config) {
-            let _ = file.write_all(json.as_bytes());
-        }
-    }
+// Why the last `load_config` fell back to defaults, if it did.
+pub fn load_error() -> Option<String> {
+    LOAD_ERROR.lock().unwrap().clone()
 }
 
-fn decode_wsl_output(bytes: &[u8]) -> String {
-    if bytes.len() >= 2 && bytes[0] == 0xFF && bytes[1] == 0xFE {
-        let utf16: Vec<u16> = bytes[2..]
-            .chunks_exact(2)
-            .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
-            .take_while(|&c| c != 0)
-            .collect();
-        String::from_utf16_lossy(&utf16)
-    } else {
-        String::from_utf8_lossy(bytes).to_string()
+pub fn save_config(config: &Config) -> Result<(), String> {
+    if let Some(err) = load_error() {
+        return Err(format!("{err}. Исправьте файл: изменения не сохранены"));
     }
+    let json =
+        serde_json::to_string_pretty(config).map_err(|e| format!("Ошибка сериализации: {e}"))?;
+    fs::write(CONFIG_PATH, json).map_err(|e| format!("Не удалось записать {CONFIG_PATH}: {e}"))
 }
 
+// The distro marked with `*` in `wsl -l -v`, not merely the first one listed.
 pub fn detect_default_wsl_distro() -> String {
-    Command::new("wsl")
-        .args(["-l", "-q"])
-        .output()
-        .ok()
-        .map(|output| decode_wsl_output(&output.stdout))
-        .map(|text| {
-            text.lines()
-                .map(|line| {
-                    line.trim()
-                        .trim_start_matches('\u{FEFF}')
-                        .trim_start_matches('*')
-                        .trim()
-                })
-                .find(|line| !line.is_empty())
-                .unwrap_or("Ubuntu-24.04")
-                .to_string()
-        })
+    let distros = list_wsl_distros().unwrap_or_default();
+    distros
+        .iter()
+        .find(|distro| distro.is_default)
+        .or(distros.first())
+        .map(|distro| distro.name.clone())
         .unwrap_or_else(|| "Ubuntu-24.04".to_string())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hooks::HookEvent;
+    use crate::usbipd::parse_usbipd_line;
 
     #[test]
     fn default_config_has_wsl_distro() {
         let config = Config::default();
         assert!(!config.wsl_distro.is_empty());
+        assert!(!config.force_bind);
+    }
+
+    fn sample_config() -> Config {
+        serde_json::from_str(
+            r#"{
+                "auto_attach_devices": ["2-9"],
+                "wsl_distro": "Ubuntu",
+                "force_bind": true,
+                "hooks": {"post_attach": {"command": "echo global"}},
+                "devices": {
+                    "2912:0008": {
+                        "nickname": "Касса",
+                        "wsl_distro": "Debian",
+                        "auto_attach": true,
+                        "force_bind": false,
+                        "hooks": {"post_attach": {"command": "echo atol", "target": "wsl"}}
+                    }
+                }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn device(line: &str) -> UsbDevice {
+        parse_usbipd_line(line).unwrap()
+    }
+
+    #[test]
+    fn resolves_device_settings_with_global_fallback() {
+        let config = sample_config();
+        let atol = device(
+            "2-7    2912:0008  ATOL USB                                                      Attached",
+        );
+        let settings = config.settings_for(&atol);
+        assert_eq!(settings.nickname.as_deref(), Some("Касса"));
+        assert_eq!(settings.wsl_distro, "Debian");
+        assert!(settings.auto_attach);
+        assert!(!settings.force_bind);
+        let commands: Vec<&str> = settings
+            .hooks
+            .iter()
+            .filter_map(|hooks| hooks.get(HookEvent::PostAttach))
+            .map(|hook| hook.command.as_str())
+            .collect();
+        assert_eq!(commands, ["echo global", "echo atol"]);
+
+        let canon = device(
+            "2-9    04a9:26b4  Canon MF4010 Series                                           Shared",
+        );
+        let settings = config.settings_for(&canon);
+        assert_eq!(settings.nickname, None);
+        assert_eq!(settings.wsl_distro, "Ubuntu");
+        assert!(settings.auto_attach);
+        assert!(settings.force_bind);
+    }
+
+    #[test]
+    fn toggles_auto_attach_in_device_settings() {
+        let mut config = sample_config();
+        let atol = device(
+            "2-7    2912:0008  ATOL USB                                                      Attached",
+        );
+        config.set_auto_attach(&atol, false);
+        assert!(!config.settings_for(&atol).auto_attach);
+        assert_eq!(config.devices["2-7"].auto_attach, Some(false));
+        assert!(!config.auto_attach_devices.contains(&atol.bus_id));
+    }
+
+    #[test]
+    fn toggles_auto_attach_for_one_of_identical_devices() {
+        let mut config = sample_config();
+        let mut first = device(
+            "2-7    2912:0008  ATOL USB                                                      Attached",
+        );
+        first.serial = Some("00106".to_string());
+        let second = UsbDevice {
+            bus_id: "2-8".to_string(),
+            serial: Some("00107".to_string()),
+            ..first.clone()
+        };
+        config.set_auto_attach(&first, false);
+        assert!(!config.settings_for(&first).auto_attach);
+        assert_eq!(config.devices["2912:0008/00106"].auto_attach, Some(false));
+        assert!(config.settings_for(&second).auto_attach);
+        assert_eq!(config.devices["2912:0008"].auto_attach, Some(true));
+    }
+
+    #[test]
+    fn annotates_one_of_identical_devices() {
+        let mut config = sample_config();
+        let mut first = device(
+            "2-7    2912:0008  ATOL USB                                                      Attached",
+        );
+        first.serial = Some("00106".to_string());
+        let second = UsbDevice {
+            bus_id: "2-8".to_string(),
+            serial: Some("00107".to_string()),
+            ..first.clone()
+        };
+        config.annotate(
+            &first,
+            Some(String::new()),
+            Some(vec!["#склад".to_string(), " эцп ".to_string()]),
+            Some("Выдан бухгалтерии".to_string()),
+        );
+        // The first device gets its own entry, starting from the VID:PID one;
+        // the second keeps using the shared entry.
+        let settings = config.settings_for(&first);
+        assert_eq!(settings.nickname, None);
+        assert_eq!(settings.tags, ["склад", "эцп"]);
+        assert_eq!(settings.wsl_distro, "Debian");
+        assert!(config.devices.contains_key("2912:0008/00106"));
+        let settings = config.settings_for(&second);
+        assert_eq!(settings.nickname.as_deref(), Some("Касса"));
+        assert!(settings.tags.is_empty());
+        assert_eq!(settings.notes, None);
+
+        config.devices.insert(
+            "2912:0008/00107".to_string(),
+            DeviceConfig {
+                nickname: Some("Касса 2".to_string()),
+                ..DeviceConfig::default()
+            },
+        );
+        let settings = config.settings_for(&second);
+        assert_eq!(settings.nickname.as_deref(), Some("Касса 2"));
+        assert!(settings.tags.is_empty());
+        assert_eq!(config.settings_for(&first).wsl_distro, "Debian");
+
+        let settings = config.settings_for(&first);
+        assert!(settings.matches(&first, "бухгалтер"));
+        assert!(settings.matches(&first, "00106"));
+        assert!(settings.matches(&first, "atol"));
+        assert!(!settings.matches(&first, "принтер"));
+        assert!(settings.has_tag("#ЭЦП"));
+        assert_eq!(identity_key(&first), "2912:0008/00106");
+    }
+
+    #[test]
+    fn moves_device_to_another_distro() {
+        let mut config = sample_config();
+        let canon = device(
+            "2-9    04a9:26b4  Canon MF4010 Series                                           Shared",
+        );
+        config.set_device_distro(&canon, "Ubuntu");
+        assert!(!config.devices.contains_key("2-9"));
+
+        config.set_device_distro(&canon, "Fedora");
+        assert_eq!(config.settings_for(&canon).wsl_distro, "Fedora");
+
+        // Of two identical devices only the moved one changes.
+        let mut first = device(
+            "2-7    2912:0008  ATOL USB                                                      Attached",
+        );
+        first.serial = Some("00106".to_string());
+        let second = UsbDevice {
+            bus_id: "2-8".to_string(),
+            serial: Some("00107".to_string()),
+            ..first.clone()
+        };
+        config.set_device_distro(&first, "Ubuntu");
+        assert_eq!(config.settings_for(&first).wsl_distro, "Ubuntu");
+        assert_eq!(
+            config.settings_for(&first).nickname.as_deref(),
+            Some("Касса")
+        );
+        assert_eq!(config.settings_for(&second).wsl_distro, "Debian");
     }
 }
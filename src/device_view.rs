@@ -0,0 +1,309 @@
+// What the device list shows and in which order: a text search, filters by
+// state and tag, and the sort order. The GUI keeps it in config.json so the
+// list looks the same in the next session; `list` builds one from its flags.
+use crate::config::{Config, DeviceSettings};
+use crate::inventory::InventoryEntry;
+use crate::usb_ids;
+use crate::usbipd::UsbDevice;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StateFilter {
+    #[default]
+    All,
+    Attached,
+    Shared,
+    AutoAttach,
+}
+
+impl StateFilter {
+    // In the order of the GUI drop-down.
+    #[cfg(windows)]
+    pub const ALL: [StateFilter; 4] = [
+        StateFilter::All,
+        StateFilter::Attached,
+        StateFilter::Shared,
+        StateFilter::AutoAttach,
+    ];
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "all" => Ok(StateFilter::All),
+            "attached" => Ok(StateFilter::Attached),
+            "shared" => Ok(StateFilter::Shared),
+            "auto-attach" => Ok(StateFilter::AutoAttach),
+            _ => Err(format!(
+                "Неверное состояние: {value} (all, attached, shared или auto-attach)"
+            )),
+        }
+    }
+
+    #[cfg(windows)]
+    pub fn label(self) -> &'static str {
+        match self {
+            StateFilter::All => "Все состояния",
+            StateFilter::Attached => "Только Attached",
+            StateFilter::Shared => "Только Shared",
+            StateFilter::AutoAttach => "Только Auto-Attach",
+        }
+    }
+
+    fn shows(self, device: &UsbDevice, settings: &DeviceSettings) -> bool {
+        match self {
+            StateFilter::All => true,
+            StateFilter::Attached => device.state == "Attached",
+            // Bound but not attached, with or without --force.
+            StateFilter::Shared => device.state.starts_with("Shared"),
+            StateFilter::AutoAttach => settings.auto_attach,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    // By bus id, the order usbipd lists devices in.
+    #[default]
+    Topology,
+    Name,
+    State,
+}
+
+impl SortOrder {
+    #[cfg(windows)]
+    pub const ALL: [SortOrder; 3] = [SortOrder::Topology, SortOrder::Name, SortOrder::State];
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "topology" | "bus" => Ok(SortOrder::Topology),
+            "name" => Ok(SortOrder::Name),
+            "state" => Ok(SortOrder::State),
+            _ => Err(format!(
+                "Неверный порядок сортировки: {value} (topology, name или state)"
+            )),
+        }
+    }
+
+    #[cfg(windows)]
+    pub fn label(self) -> &'static str {
+        match self {
+            SortOrder::Topology => "По портам",
+            SortOrder::Name => "По имени",
+            SortOrder::State => "По состоянию",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceView {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub search: String,
+    #[serde(default)]
+    pub state: StateFilter,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(default)]
+    pub sort: SortOrder,
+}
+
+impl DeviceView {
+    pub fn is_default(&self) -> bool {
+        *self == DeviceView::default()
+    }
+
+    pub fn shows(&self, device: &UsbDevice, settings: &DeviceSettings) -> bool {
+        (self.search.trim().is_empty() || settings.matches(device, &self.search))
+            && self.tag.as_deref().is_none_or(|tag| settings.has_tag(tag))
+            && self.state.shows(device, settings)
+    }
+
+    pub fn apply(
+        &self,
+        devices: &[UsbDevice],
+        config: &Config,
+    ) -> Vec<(UsbDevice, DeviceSettings)> {
+        let mut shown: Vec<(UsbDevice, DeviceSettings)> = devices
+            .iter()
+            .map(|device| (device.clone(), config.settings_for(device)))
+            .filter(|(device, settings)| self.shows(device, settings))
+            .collect();
+        shown.sort_by(|(a, a_settings), (b, b_settings)| match self.sort {
+            SortOrder::Topology => compare_bus_ids(&a.bus_id, &b.bus_id),
+            SortOrder::Name => display_name(a, a_settings)
+                .cmp(&display_name(b, b_settings))
+                .then_with(|| compare_bus_ids(&a.bus_id, &b.bus_id)),
+            SortOrder::State => state_rank(&a.state)
+                .cmp(&state_rank(&b.state))
+                .then_with(|| compare_bus_ids(&a.bus_id, &b.bus_id)),
+        });
+        shown
+    }
+
+    // Devices that are not connected are never Attached or Shared; they keep
+    // the most recently seen first order unless sorted by name.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn apply_offline(
+        &self,
+        offline: &[InventoryEntry],
+        config: &Config,
+    ) -> Vec<InventoryEntry> {
+        let mut shown: Vec<(InventoryEntry, String)> = offline
+            .iter()
+            .filter_map(|entry| {
+                let device = entry.as_device();
+                let settings = config.settings_for(&device);
+                self.shows(&device, &settings)
+                    .then(|| (entry.clone(), display_name(&device, &settings)))
+            })
+            .collect();
+        if self.sort == SortOrder::Name {
+            shown.sort_by(|(_, a), (_, b)| a.cmp(b));
+        }
+        shown.into_iter().map(|(entry, _)| entry).collect()
+    }
+}
+
+// Every tag set on any device, for the tag filter.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn known_tags(config: &Config) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in config.devices.values().flat_map(|device| &device.tags) {
+        if !tags
+            .iter()
+            .any(|known| known.to_lowercase() == tag.to_lowercase())
+        {
+            tags.push(tag.clone());
+        }
+    }
+    tags.sort_by_key(|tag| tag.to_lowercase());
+    tags
+}
+
+// "1-10" after "1-9": bus ids are compared number by number.
+fn compare_bus_ids(a: &str, b: &str) -> Ordering {
+    let numbers = |bus_id: &str| -> Vec<u32> {
+        bus_id
+            .split(['-', '.'])
+            .map(|part| part.parse().unwrap_or(u32::MAX))
+            .collect()
+    };
+    numbers(a).cmp(&numbers(b)).then_with(|| a.cmp(b))
+}
+
+fn display_name(device: &UsbDevice, settings: &DeviceSettings) -> String {
+    settings
+        .nickname
+        .clone()
+        .unwrap_or_else(|| usb_ids::display_name(device))
+        .to_lowercase()
+}
+
+fn state_rank(state: &str) -> u8 {
+    match state {
+        "Attached" => 0,
+        "Shared" | "Shared (forced)" => 1,
+        "Not shared" => 2,
+        _ => 3,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DeviceConfig;
+    use crate::usbipd::parse_usbipd_list;
+
+    fn devices() -> Vec<UsbDevice> {
+        parse_usbipd_list(
+            "Connected:\n\
+             BUSID  VID:PID    DEVICE                                                        STATE\n\
+             2-1    2912:0008  Zebra scanner                                                 Not shared\n\
+             1-10   2912:0008  ATOL USB                                                      Shared (forced)\n\
+             1-9    2912:0008  Штрих-М                                                       Attached\n",
+        )
+    }
+
+    fn config() -> Config {
+        let mut config = Config {
+            auto_attach_devices: vec!["1-10".to_string()],
+            ..Config::default()
+        };
+        config.devices.insert(
+            "2-1".to_string(),
+            DeviceConfig {
+                nickname: Some("Касса".to_string()),
+                tags: vec!["склад".to_string()],
+                ..DeviceConfig::default()
+            },
+        );
+        config
+    }
+
+    fn bus_ids(shown: &[(UsbDevice, DeviceSettings)]) -> Vec<&str> {
+        shown
+            .iter()
+            .map(|(device, _)| device.bus_id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn sorts_by_topology_name_and_state() {
+        let devices = devices();
+        let mut view = DeviceView::default();
+        assert_eq!(
+            bus_ids(&view.apply(&devices, &config())),
+            ["1-9", "1-10", "2-1"]
+        );
+
+        // The nickname counts as the name.
+        view.sort = SortOrder::Name;
+        assert_eq!(
+            bus_ids(&view.apply(&devices, &config())),
+            ["1-10", "2-1", "1-9"]
+        );
+
+        view.sort = SortOrder::State;
+        assert_eq!(
+            bus_ids(&view.apply(&devices, &config())),
+            ["1-9", "1-10", "2-1"]
+        );
+    }
+
+    #[test]
+    fn filters_by_search_state_and_tag() {
+        let devices = devices();
+        let view = |view: DeviceView| bus_ids(&view.apply(&devices, &config())).join(" ");
+        let search = |text: &str| DeviceView {
+            search: text.to_string(),
+            ..DeviceView::default()
+        };
+        assert_eq!(view(search("касса")), "2-1");
+        assert_eq!(view(search("1-9")), "1-9");
+        assert_eq!(view(search("  ")), "1-9 1-10 2-1");
+        let state = |state| DeviceView {
+            state,
+            ..DeviceView::default()
+        };
+        assert_eq!(view(state(StateFilter::Attached)), "1-9");
+        assert_eq!(view(state(StateFilter::Shared)), "1-10");
+        assert_eq!(view(state(StateFilter::AutoAttach)), "1-10");
+        let tagged = DeviceView {
+            tag: Some("#Склад".to_string()),
+            ..DeviceView::default()
+        };
+        assert_eq!(view(tagged), "2-1");
+    }
+
+    #[test]
+    fn parses_saved_views_and_flags() {
+        assert!(DeviceView::default().is_default());
+        let view: DeviceView =
+            serde_json::from_str(r#"{ "state": "auto-attach", "sort": "name" }"#).unwrap();
+        assert_eq!(view.state, StateFilter::parse("auto-attach").unwrap());
+        assert_eq!(view.sort, SortOrder::parse("name").unwrap());
+        assert!(SortOrder::parse("size").is_err());
+        assert_eq!(known_tags(&config()), ["склад"]);
+    }
+}
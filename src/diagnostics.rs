@@ -0,0 +1,382 @@
+// Diagnostic bundle: one JSON file a user can attach to a support request
+// instead of a screenshot. The layout is described in README.markdown
+// ("Диагностический архив"); bump BUNDLE_FORMAT_VERSION on any change that
+// can break a parser.
+use crate::config::{parse_config_file, Config};
+use crate::doctor::{run_doctor, CheckResult};
+use crate::encoding::decode_output;
+use crate::executor::ExecutorEvent;
+use crate::logging;
+use crate::session;
+use crate::tools::{self, Tool, ToolSource, TOOLS};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const BUNDLE_FORMAT: &str = "usbipd_gui.diagnostics";
+pub const BUNDLE_FORMAT_VERSION: u32 = 1;
+#[cfg_attr(not(windows), allow(dead_code))]
+const OPERATION_LOG_CAPACITY: usize = 200;
+const LOG_TAIL: usize = 500;
+const REDACTED: &str = "<скрыто>";
+
+// Finished and warned operations of the running session, newest last.
+#[cfg_attr(not(windows), allow(dead_code))]
+#[derive(Default)]
+pub struct OperationLog {
+    entries: VecDeque<OperationEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OperationEntry {
+    pub time: u64,
+    pub label: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bus_id: Option<String>,
+    pub outcome: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+impl OperationLog {
+    pub fn record<T>(&mut self, event: &ExecutorEvent<T>) {
+        let (label, bus_id, outcome, message) = match event {
+            ExecutorEvent::Warning { label, message, .. } => {
+                (label, None, "warning", Some(message.clone()))
+            }
+            ExecutorEvent::Finished {
+                label,
+                bus_id,
+                outcome,
+                ..
+            } => (
+                label,
+                bus_id.clone(),
+                outcome.name(),
+                outcome.error().map(str::to_string),
+            ),
+            _ => return,
+        };
+        if self.entries.len() == OPERATION_LOG_CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(OperationEntry {
+            time: unix_time(),
+            label: label.clone(),
+            bus_id,
+            outcome: outcome.to_string(),
+            message,
+        });
+    }
+
+    pub fn entries(&self) -> Vec<OperationEntry> {
+        self.entries.iter().cloned().collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Bundle {
+    pub format: &'static str,
+    pub format_version: u32,
+    pub created_at: u64,
+    pub app: AppInfo,
+    pub environment: Environment,
+    pub config: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_error: Option<String>,
+    pub commands: BTreeMap<String, CommandCapture>,
+    pub doctor: Vec<CheckResult>,
+    pub operations: Vec<OperationEntry>,
+    pub log: Vec<Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AppInfo {
+    pub version: &'static str,
+    pub os: &'static str,
+    pub arch: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Environment {
+    pub tools: BTreeMap<String, Option<ToolLocation>>,
+    pub variables: BTreeMap<String, String>,
+    pub log_file: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ToolLocation {
+    pub path: String,
+    pub source: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CommandCapture {
+    pub command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Environment variables that change which tools and agent are used.
+const VARIABLES: &[&str] = &[
+    "USBIPD_PATH",
+    "USBIPD_WSL_PATH",
+    "USBIPD_POWERSHELL_PATH",
+    "USBIPD_AGENT_PATH",
+    "PROCESSOR_ARCHITECTURE",
+];
+
+pub fn collect_bundle(config: &Config, operations: Vec<OperationEntry>) -> Bundle {
+    let home = home_dir();
+    let home = home.as_deref();
+    let (config_value, config_error) = match parse_config_file() {
+        Ok(Some(parsed)) => (
+            serde_json::to_value(&parsed)
+                .ok()
+                .map(|value| redact_config(value, home)),
+            None,
+        ),
+        Ok(None) => (None, None),
+        Err(err) => (None, Some(err)),
+    };
+
+    let mut commands = BTreeMap::new();
+    for (name, tool, args) in [
+        ("usbipd_version", Tool::Usbipd, &["--version"][..]),
+        ("usbipd_list", Tool::Usbipd, &["list"][..]),
+        ("usbipd_state", Tool::Usbipd, &["state"][..]),
+        ("wsl_version", Tool::Wsl, &["--version"][..]),
+        ("wsl_list", Tool::Wsl, &["-l", "-v"][..]),
+    ] {
+        commands.insert(name.to_string(), capture(tool, args, home));
+    }
+
+    let tools = TOOLS
+        .iter()
+        .map(|&tool| {
+            let location = tools::locate(tool).map(|(path, source)| ToolLocation {
+                path: redact_text(&path.to_string_lossy(), home),
+                source: source_name(&source),
+            });
+            (tool.name().to_string(), location)
+        })
+        .collect();
+    let log_file = logging::log_file();
+    let log = log_file
+        .as_deref()
+        .map(|path| log_tail(path, home))
+        .unwrap_or_default();
+    let variables = VARIABLES
+        .iter()
+        .filter_map(|&name| {
+            let value = env::var(name).ok()?;
+            Some((name.to_string(), redact_text(&value, home)))
+        })
+        .collect();
+
+    Bundle {
+        format: BUNDLE_FORMAT,
+        format_version: BUNDLE_FORMAT_VERSION,
+        created_at: unix_time(),
+        app: AppInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            os: env::consts::OS,
+            arch: env::consts::ARCH,
+        },
+        environment: Environment {
+            tools,
+            variables,
+            log_file: log_file
+                .as_ref()
+                .map(|path| redact_text(&path.to_string_lossy(), home)),
+        },
+        config: config_value,
+        config_error,
+        commands,
+        doctor: run_doctor(config),
+        operations,
+        log,
+    }
+}
+
+// The last records of the current log file, which also covers earlier runs
+// and CLI invocations.
+fn log_tail(path: &Path, home: Option<&str>) -> Vec<Value> {
+    let contents = fs::read_to_string(path).unwrap_or_default();
+    let lines: Vec<&str> = contents.lines().collect();
+    lines[lines.len().saturating_sub(LOG_TAIL)..]
+        .iter()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .map(|record| redact_value(record, home, false))
+        .collect()
+}
+
+pub fn default_bundle_path() -> String {
+    format!("usbipd-diagnostics-{}.json", unix_time())
+}
+
+pub fn export_bundle(path: &str, bundle: &Bundle) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(bundle)
+        .map_err(|e| format!("Ошибка сериализации диагностики: {e}"))?;
+    fs::write(path, json).map_err(|e| format!("Не удалось сохранить {path}: {e}"))
+}
+
+fn capture(tool: Tool, args: &[&str], home: Option<&str>) -> CommandCapture {
+    let command = format!("{} {}", tool.name(), args.join(" "));
+    match session::output(tool, tools::command(tool).args(args)) {
+        Ok(output) => CommandCapture {
+            command,
+            exit_code: output.status.code(),
+            stdout: redact_text(&decode_output(&output.stdout), home),
+            stderr: redact_text(&decode_output(&output.stderr), home),
+            error: None,
+        },
+        Err(err) => CommandCapture {
+            command,
+            exit_code: None,
+            stdout: String::new(),
+            stderr: String::new(),
+            error: Some(redact_text(&err, home)),
+        },
+    }
+}
+
+fn source_name(source: &ToolSource) -> &'static str {
+    match source {
+        ToolSource::CommandLine => "command_line",
+        ToolSource::Environment => "environment",
+        ToolSource::Config => "config",
+        ToolSource::Path => "path",
+        ToolSource::InstallLocation => "install_location",
+    }
+}
+
+fn home_dir() -> Option<String> {
+    env::var("USERPROFILE")
+        .or_else(|_| env::var("HOME"))
+        .ok()
+        .filter(|home| home.len() > 1)
+}
+
+// The home directory carries the user name, so it is replaced wherever it
+// shows up.
+fn redact_text(text: &str, home: Option<&str>) -> String {
+    match home {
+        Some(home) => text.replace(home, "~"),
+        None => text.to_string(),
+    }
+}
+
+// Hook commands are free-form and may hold credentials.
+fn redact_config(value: Value, home: Option<&str>) -> Value {
+    redact_value(value, home, false)
+}
+
+fn redact_value(value: Value, home: Option<&str>, in_hooks: bool) -> Value {
+    match value {
+        Value::String(text) => Value::String(redact_text(&text, home)),
+        Value::Array(items) => Value::Array(
+            items
+                .into_iter()
+                .map(|item| redact_value(item, home, in_hooks))
+                .collect(),
+        ),
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, field)| {
+                    let field = if in_hooks && key == "command" {
+                        Value::String(REDACTED.to_string())
+                    } else {
+                        redact_value(field, home, in_hooks || key == "hooks")
+                    };
+                    (key, field)
+                })
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::OperationOutcome;
+    use serde_json::json;
+
+    #[test]
+    fn redacts_hook_commands_and_home_paths() {
+        let config = json!({
+            "wsl_distro": "Ubuntu",
+            "tools": {"usbipd": r"C:\Users\ivanov\tools\usbipd.exe"},
+            "hooks": {"pre_bind": {"command": "net use \\\\srv /user:ivanov secret", "timeout_secs": 30}},
+            "devices": {"2-7": {"nickname": "Ридер", "hooks": {"post_attach": {"command": "x", "target": "wsl"}}}}
+        });
+        let redacted = redact_config(config, Some(r"C:\Users\ivanov"));
+        assert_eq!(redacted["tools"]["usbipd"], r"~\tools\usbipd.exe");
+        assert_eq!(redacted["hooks"]["pre_bind"]["command"], REDACTED);
+        assert_eq!(redacted["hooks"]["pre_bind"]["timeout_secs"], 30);
+        assert_eq!(
+            redacted["devices"]["2-7"]["hooks"]["post_attach"]["command"],
+            REDACTED
+        );
+        assert_eq!(
+            redacted["devices"]["2-7"]["hooks"]["post_attach"]["target"],
+            "wsl"
+        );
+        assert_eq!(redacted["devices"]["2-7"]["nickname"], "Ридер");
+    }
+
+    #[test]
+    fn keeps_recent_finished_operations() {
+        let mut log = OperationLog::default();
+        log.record(&ExecutorEvent::<()>::Started {
+            id: 1,
+            label: "Bind 2-7".to_string(),
+        });
+        log.record(&ExecutorEvent::<()>::Finished {
+            id: 1,
+            label: "Bind 2-7".to_string(),
+            bus_id: Some("2-7".to_string()),
+            outcome: OperationOutcome::Failed("UAC отклонён".to_string()),
+        });
+        for id in 0..OPERATION_LOG_CAPACITY as u64 {
+            log.record(&ExecutorEvent::Finished {
+                id,
+                label: format!("Обновление {id}"),
+                bus_id: None,
+                outcome: OperationOutcome::Succeeded(()),
+            });
+        }
+        let entries = log.entries();
+        assert_eq!(entries.len(), OPERATION_LOG_CAPACITY);
+        assert_eq!(entries[0].label, "Обновление 0");
+
+        let mut log = OperationLog::default();
+        log.record(&ExecutorEvent::<()>::Finished {
+            id: 1,
+            label: "Bind 2-7".to_string(),
+            bus_id: Some("2-7".to_string()),
+            outcome: OperationOutcome::Failed("UAC отклонён".to_string()),
+        });
+        let entry = &log.entries()[0];
+        assert_eq!(entry.outcome, "failed");
+        assert_eq!(entry.message.as_deref(), Some("UAC отклонён"));
+    }
+}
@@ -0,0 +1,528 @@
+use crate::config::{parse_config_file, Config, STATE_DIR_ENV};
+use crate::encoding::decode_output;
+use crate::session;
+use crate::tools::{self, Tool, TOOLS};
+use crate::usb_ids;
+use crate::usbipd::usbipd_version;
+use crate::wsl::{
+    find_distro, list_wsl_distros, read_distro_sysfs, run_in_distro, wsl_version, WslDistro,
+    WslState,
+};
+use serde::Serialize;
+use std::env;
+use std::path::Path;
+
+// `usbipd attach --wsl` appeared in usbipd-win 4.0.
+const MIN_USBIPD_VERSION: [u32; 3] = [4, 0, 0];
+// First WSL kernel built with USB/IP support.
+const MIN_KERNEL_VERSION: [u32; 4] = [5, 10, 60, 1];
+const USBIPD_PORT: u16 = 3240;
+
+// Adapters installed by common VPN clients; usbipd traffic to the distro can
+// end up routed through them.
+const VPN_ADAPTERS: &[&str] = &[
+    "vpn",
+    "tap-windows",
+    "wintun",
+    "wireguard",
+    "anyconnect",
+    "fortinet",
+    "globalprotect",
+    "pangp",
+    "juniper",
+    "pulse secure",
+    "check point",
+    "sonicwall",
+    "zerotier",
+    "tailscale",
+    "hamachi",
+];
+
+// Tries the Windows host as seen from the distro (the default gateway in NAT
+// mode, localhost with mirrored networking).
+const PORT_SCRIPT: &str = r#"port=$1
+host=$(ip route show default 2>/dev/null | awk '{print $3; exit}')
+[ -n "$host" ] || host=$(awk '/^nameserver/ {print $2; exit}' /etc/resolv.conf 2>/dev/null)
+for h in $host 127.0.0.1; do
+  if command -v nc >/dev/null 2>&1; then
+    nc -z -w 3 "$h" "$port" 2>/dev/null && { echo "open $h"; exit 0; }
+  elif command -v bash >/dev/null 2>&1; then
+    timeout 3 bash -c "exec 3<>/dev/tcp/$h/$port" 2>/dev/null && { echo "open $h"; exit 0; }
+  else
+    echo untested; exit 0
+  fi
+done
+echo "closed ${host:-127.0.0.1}""#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CheckResult {
+    pub name: String,
+    pub status: CheckStatus,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remedy: Option<String>,
+}
+
+impl CheckResult {
+    fn pass(name: &str, message: impl Into<String>) -> Self {
+        Self::new(name, CheckStatus::Pass, message.into(), None)
+    }
+
+    fn warn(name: &str, message: impl Into<String>, remedy: impl Into<String>) -> Self {
+        Self::new(name, CheckStatus::Warn, message.into(), Some(remedy.into()))
+    }
+
+    fn fail(name: &str, message: impl Into<String>, remedy: impl Into<String>) -> Self {
+        Self::new(name, CheckStatus::Fail, message.into(), Some(remedy.into()))
+    }
+
+    fn new(name: &str, status: CheckStatus, message: String, remedy: Option<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            status,
+            message,
+            remedy,
+        }
+    }
+}
+
+pub fn run_doctor(config: &Config) -> Vec<CheckResult> {
+    let distros = list_wsl_distros();
+    let mut results = vec![
+        check_usbipd(),
+        check_wsl(),
+        check_distros(&distros, &config.wsl_distro),
+    ];
+
+    let distros = distros.unwrap_or_default();
+    let target = find_distro(&distros, &config.wsl_distro).filter(|distro| distro.version == 2);
+    match target {
+        Some(distro) => {
+            results.push(check_kernel(&distro.name));
+            results.push(check_port(&distro.name));
+        }
+        None => {
+            let skipped = format!("пропущено: нет дистрибутива WSL2 {}", config.wsl_distro);
+            let remedy = "исправьте ошибку дистрибутива выше";
+            results.push(CheckResult::warn("Ядро WSL", &skipped, remedy));
+            results.push(CheckResult::warn("Порт 3240", &skipped, remedy));
+        }
+    }
+
+    results.push(check_usbdk());
+    results.push(check_vpn());
+    results.push(check_config(&distros));
+    results.push(check_usb_ids());
+    results
+}
+
+fn check_usbipd() -> CheckResult {
+    const NAME: &str = "usbipd";
+    let version = match usbipd_version() {
+        Ok(version) => version,
+        Err(err) => return CheckResult::fail(NAME, err, "winget install usbipd"),
+    };
+    let path = tools::tool_path(Tool::Usbipd);
+    match parse_version(&version) {
+        Some(parsed) if parsed.as_slice() < MIN_USBIPD_VERSION.as_slice() => CheckResult::fail(
+            NAME,
+            format!("версия {version} слишком старая (нужна 4.0 или новее)"),
+            "winget upgrade usbipd",
+        ),
+        Some(_) => CheckResult::pass(NAME, format!("{version} ({})", path.display())),
+        None => CheckResult::warn(
+            NAME,
+            format!("не удалось разобрать версию: {version}"),
+            "проверьте, что указан путь к usbipd-win",
+        ),
+    }
+}
+
+fn check_wsl() -> CheckResult {
+    const NAME: &str = "WSL";
+    match wsl_version() {
+        Ok(text) => match parse_wsl_version(&text) {
+            Some(version) => CheckResult::pass(NAME, format!("версия {version}")),
+            None => CheckResult::pass(NAME, text.lines().next().unwrap_or("").trim()),
+        },
+        Err(err) if tools::locate(Tool::Wsl).is_none() => {
+            CheckResult::fail(NAME, err, "wsl --install")
+        }
+        Err(_) => CheckResult::warn(
+            NAME,
+            "встроенная версия WSL из состава Windows устарела",
+            "wsl --update",
+        ),
+    }
+}
+
+fn check_distros(distros: &Result<Vec<WslDistro>, String>, wsl_distro: &str) -> CheckResult {
+    const NAME: &str = "Дистрибутив";
+    let distros = match distros {
+        Ok(distros) if distros.is_empty() => {
+            return CheckResult::fail(
+                NAME,
+                "не установлено ни одного дистрибутива",
+                "wsl --install -d Ubuntu",
+            )
+        }
+        Ok(distros) => distros,
+        Err(err) => return CheckResult::fail(NAME, err.clone(), "wsl --install"),
+    };
+    let default = distros
+        .iter()
+        .find(|distro| distro.is_default)
+        .map(|distro| distro.name.as_str())
+        .unwrap_or("не задан");
+    match find_distro(distros, wsl_distro) {
+        None => {
+            let names: Vec<&str> = distros.iter().map(|distro| distro.name.as_str()).collect();
+            CheckResult::fail(
+                NAME,
+                format!(
+                    "{wsl_distro} из config.json не найден (установлены: {})",
+                    names.join(", ")
+                ),
+                "укажите существующий дистрибутив в поле wsl_distro",
+            )
+        }
+        Some(distro) if distro.version != 2 => CheckResult::fail(
+            NAME,
+            format!("{} работает как WSL{}", distro.name, distro.version),
+            format!("wsl --set-version {} 2", distro.name),
+        ),
+        Some(distro) => CheckResult::pass(
+            NAME,
+            format!(
+                "{} (WSL2, {}); по умолчанию: {default}",
+                distro.name,
+                if distro.state == WslState::Running {
+                    "запущен"
+                } else {
+                    "не запущен"
+                }
+            ),
+        ),
+    }
+}
+
+fn check_kernel(wsl_distro: &str) -> CheckResult {
+    const NAME: &str = "Ядро WSL";
+    let kernel = match run_in_distro(wsl_distro, &["uname", "-r"]) {
+        Ok(kernel) => kernel.trim().to_string(),
+        Err(err) => {
+            return CheckResult::fail(NAME, err, format!("проверьте запуск: wsl -d {wsl_distro}"))
+        }
+    };
+    let vhci_loaded = read_distro_sysfs(wsl_distro)
+        .map(|snapshot| snapshot.vhci_loaded)
+        .unwrap_or(false);
+    evaluate_kernel(&kernel, vhci_loaded)
+}
+
+fn evaluate_kernel(kernel: &str, vhci_loaded: bool) -> CheckResult {
+    const NAME: &str = "Ядро WSL";
+    let supported = parse_version(kernel)
+        .is_some_and(|version| version.as_slice() >= MIN_KERNEL_VERSION.as_slice());
+    if vhci_loaded {
+        CheckResult::pass(NAME, format!("{kernel}, драйвер vhci_hcd загружен"))
+    } else if supported {
+        CheckResult::warn(
+            NAME,
+            format!("{kernel}, драйвер vhci_hcd не загружен"),
+            "sudo modprobe vhci_hcd",
+        )
+    } else {
+        CheckResult::fail(
+            NAME,
+            format!("{kernel} не поддерживает USB/IP (нужно 5.10.60.1 или новее)"),
+            "wsl --update",
+        )
+    }
+}
+
+fn check_port(wsl_distro: &str) -> CheckResult {
+    let port = USBIPD_PORT.to_string();
+    match run_in_distro(wsl_distro, &["sh", "-c", PORT_SCRIPT, "sh", &port]) {
+        Ok(output) => evaluate_port(&output),
+        Err(err) => CheckResult::warn("Порт 3240", err, "проверьте порт вручную"),
+    }
+}
+
+fn evaluate_port(output: &str) -> CheckResult {
+    const NAME: &str = "Порт 3240";
+    let line = output.trim();
+    if let Some(host) = line.strip_prefix("open ") {
+        CheckResult::pass(NAME, format!("usbipd доступен из дистрибутива по {host}"))
+    } else if let Some(host) = line.strip_prefix("closed ") {
+        CheckResult::fail(
+            NAME,
+            format!("{host}:{USBIPD_PORT} недоступен из дистрибутива"),
+            "проверьте, что служба usbipd запущена (sc query usbipd), и разрешите входящие \
+             подключения: New-NetFirewallRule -DisplayName usbipd -Direction Inbound \
+             -Protocol TCP -LocalPort 3240 -Action Allow",
+        )
+    } else {
+        CheckResult::warn(
+            NAME,
+            "в дистрибутиве нет nc и bash, проверка невозможна",
+            "установите netcat в дистрибутиве",
+        )
+    }
+}
+
+fn check_usbdk() -> CheckResult {
+    const NAME: &str = "USBdk";
+    let driver = env::var("SystemRoot")
+        .ok()
+        .map(|root| Path::new(&root).join(r"System32\drivers\UsbDk.sys"))
+        .filter(|driver| driver.is_file());
+    match driver {
+        Some(driver) => CheckResult::warn(
+            NAME,
+            format!("установлен ({})", driver.display()),
+            "удалите USBdk, если устройства не привязываются или пропадают",
+        ),
+        None => CheckResult::pass(NAME, "не установлен"),
+    }
+}
+
+fn check_vpn() -> CheckResult {
+    const NAME: &str = "VPN";
+    let command = "Get-NetAdapter | Where-Object Status -eq 'Up' | \
+                   ForEach-Object InterfaceDescription";
+    let output = session::output(
+        Tool::Powershell,
+        tools::command(Tool::Powershell).args([
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            command,
+        ]),
+    );
+    let adapters = match output {
+        Ok(output) if output.status.success() => decode_output(&output.stdout),
+        Ok(output) => {
+            return CheckResult::warn(
+                NAME,
+                format!("Get-NetAdapter завершился с кодом {}", output.status),
+                "проверьте сетевые адаптеры вручную",
+            )
+        }
+        Err(err) => return CheckResult::warn(NAME, err, "проверьте сетевые адаптеры вручную"),
+    };
+    let vpn = find_vpn_adapters(&adapters);
+    if vpn.is_empty() {
+        CheckResult::pass(NAME, "активных VPN-адаптеров нет")
+    } else {
+        CheckResult::warn(
+            NAME,
+            format!("активны: {}", vpn.join(", ")),
+            "отключите VPN, если attach завершается ошибкой или зависает",
+        )
+    }
+}
+
+// The built-in copy is only a stub, so most names need the full file.
+fn check_usb_ids() -> CheckResult {
+    const NAME: &str = "usb.ids";
+    match usb_ids::user_file() {
+        Some(path) if path.is_file() => CheckResult::pass(NAME, path.display().to_string()),
+        Some(path) => CheckResult::warn(
+            NAME,
+            "не найден, названия известны только для немногих устройств",
+            format!(
+                "скачайте http://www.linux-usb.org/usb.ids в {}",
+                path.display()
+            ),
+        ),
+        None => CheckResult::warn(
+            NAME,
+            "каталог состояния не определён, названия известны только для немногих устройств",
+            format!("задайте {STATE_DIR_ENV}"),
+        ),
+    }
+}
+
+fn check_config(distros: &[WslDistro]) -> CheckResult {
+    const NAME: &str = "config.json";
+    let config = match parse_config_file() {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            return CheckResult::warn(
+                NAME,
+                "не найден, используются значения по умолчанию",
+                "скопируйте config.example.json в config.json",
+            )
+        }
+        Err(err) => {
+            return CheckResult::fail(
+                NAME,
+                format!("{err}; настройки из файла не применяются"),
+                "исправьте файл или удалите его",
+            )
+        }
+    };
+
+    let mut problems = Vec::new();
+    for tool in TOOLS {
+        if let Some(path) = config.tools.get(tool) {
+            if !Path::new(path).is_file() {
+                problems.push(format!("tools.{}: файл {path} не найден", tool.name()));
+            }
+        }
+    }
+    let mut keys: Vec<&String> = config.devices.keys().collect();
+    keys.sort();
+    for key in keys {
+        let wsl_distro = config.devices[key].wsl_distro.as_deref().unwrap_or("");
+        if !wsl_distro.is_empty()
+            && !distros.is_empty()
+            && find_distro(distros, wsl_distro).is_none()
+        {
+            problems.push(format!("devices.{key}: дистрибутив {wsl_distro} не найден"));
+        }
+    }
+    if problems.is_empty() {
+        CheckResult::pass(NAME, "корректен")
+    } else {
+        CheckResult::fail(NAME, problems.join("; "), "исправьте указанные поля")
+    }
+}
+
+// Leading dotted number of e.g. `4.3.0+52.Branch.master` or
+// `5.15.167.4-microsoft-standard-WSL2`.
+fn parse_version(text: &str) -> Option<Vec<u32>> {
+    let numeric: String = text
+        .trim()
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    let parts: Option<Vec<u32>> = numeric
+        .split('.')
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse().ok())
+        .collect();
+    parts.filter(|parts| !parts.is_empty())
+}
+
+// First line of `wsl --version`: `WSL version: 2.0.14.0` or, localized,
+// `Версия WSL: 2.0.14.0`.
+fn parse_wsl_version(text: &str) -> Option<String> {
+    let line = text.lines().next()?;
+    let version = line.rsplit(':').next()?.trim();
+    parse_version(version).map(|_| version.to_string())
+}
+
+fn find_vpn_adapters(descriptions: &str) -> Vec<String> {
+    descriptions
+        .lines()
+        .map(str::trim)
+        .filter(|line| {
+            let line = line.to_lowercase();
+            VPN_ADAPTERS.iter().any(|name| line.contains(name))
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+pub fn has_failures(results: &[CheckResult]) -> bool {
+    results
+        .iter()
+        .any(|result| result.status == CheckStatus::Fail)
+}
+
+pub fn format_report(results: &[CheckResult]) -> String {
+    let mut lines = Vec::new();
+    for result in results {
+        let status = match result.status {
+            CheckStatus::Pass => "[OK]    ",
+            CheckStatus::Warn => "[ВНИМ.] ",
+            CheckStatus::Fail => "[ОШИБКА]",
+        };
+        lines.push(format!("{status} {}: {}", result.name, result.message));
+        if let Some(remedy) = &result.remedy {
+            lines.push(format!("         → {remedy}"));
+        }
+    }
+    let count = |status| results.iter().filter(|r| r.status == status).count();
+    lines.push(format!(
+        "Итог: OK {}, предупреждений {}, ошибок {}",
+        count(CheckStatus::Pass),
+        count(CheckStatus::Warn),
+        count(CheckStatus::Fail)
+    ));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tool_versions() {
+        assert_eq!(parse_version("4.3.0+52.Branch.master"), Some(vec![4, 3, 0]));
+        assert_eq!(
+            parse_version("5.15.167.4-microsoft-standard-WSL2"),
+            Some(vec![5, 15, 167, 4])
+        );
+        assert_eq!(parse_version("usbipd"), None);
+        assert_eq!(
+            parse_wsl_version("Версия WSL: 2.3.26.0\nВерсия ядра: 5.15.167.4-1\n"),
+            Some("2.3.26.0".to_string())
+        );
+        assert_eq!(
+            parse_wsl_version("WSL version: 2.0.14.0\n"),
+            Some("2.0.14.0".to_string())
+        );
+    }
+
+    #[test]
+    fn evaluates_kernel_and_port() {
+        let kernel = "5.15.167.4-microsoft-standard-WSL2";
+        assert_eq!(evaluate_kernel(kernel, true).status, CheckStatus::Pass);
+        assert_eq!(evaluate_kernel(kernel, false).status, CheckStatus::Warn);
+        let old = evaluate_kernel("5.10.16.3-microsoft-standard-WSL2", false);
+        assert_eq!(old.status, CheckStatus::Fail);
+        assert_eq!(old.remedy.as_deref(), Some("wsl --update"));
+
+        assert_eq!(evaluate_port("open 172.22.0.1\n").status, CheckStatus::Pass);
+        let closed = evaluate_port("closed 172.22.0.1\n");
+        assert_eq!(closed.status, CheckStatus::Fail);
+        assert!(closed.message.contains("172.22.0.1:3240"));
+        assert_eq!(evaluate_port("untested\n").status, CheckStatus::Warn);
+    }
+
+    #[test]
+    fn finds_vpn_adapters_and_formats_report() {
+        let adapters = "Realtek PCIe GbE Family Controller\r\n\
+                        WireGuard Tunnel\r\n\
+                        Hyper-V Virtual Ethernet Adapter\r\n\
+                        TAP-Windows Adapter V9\r\n";
+        assert_eq!(
+            find_vpn_adapters(adapters),
+            ["WireGuard Tunnel", "TAP-Windows Adapter V9"]
+        );
+
+        let results = [
+            CheckResult::pass("usbipd", "4.3.0"),
+            CheckResult::warn("VPN", "активны: WireGuard Tunnel", "отключите VPN"),
+        ];
+        assert!(!has_failures(&results));
+        assert_eq!(
+            format_report(&results),
+            "[OK]     usbipd: 4.3.0\n\
+             [ВНИМ.]  VPN: активны: WireGuard Tunnel\n\
+             \u{20}        → отключите VPN\n\
+             Итог: OK 1, предупреждений 1, ошибок 0"
+        );
+    }
+}
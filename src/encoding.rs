@@ -0,0 +1,160 @@
+// Windows tools print in whatever encoding they like: usbipd follows the
+// console OEM code page, wsl.exe writes UTF-16LE (with a BOM only on some
+// builds) and cmd hooks may use the ANSI code page. Everything read from a
+// subprocess goes through `decode_output`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Cp866,
+    Cp1251,
+}
+
+// Bytes 0x80..=0xFF of each code page.
+const CP866_HIGH: &str = "АБВГДЕЖЗИЙКЛМНОПРСТУФХЦЧШЩЪЫЬЭЮЯ\
+                          абвгдежзийклмноп\
+                          ░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀\
+                          рстуфхцчшщъыьэюя\
+                          ЁёЄєЇїЎў°∙·√№¤■\u{A0}";
+const CP1251_HIGH: &str = "ЂЃ‚ѓ„…†‡€‰Љ‹ЊЌЋЏђ‘’“”•–—\u{FFFD}™љ›њќћџ\
+                           \u{A0}ЎўЈ¤Ґ¦§Ё©Є«¬\u{AD}®Ї°±Ііґµ¶·ё№є»јЅѕї\
+                           АБВГДЕЖЗИЙКЛМНОПРСТУФХЦЧШЩЪЫЬЭЮЯ\
+                           абвгдежзийклмнопрстуфхцчшщъыьэюя";
+
+pub fn detect_encoding(bytes: &[u8]) -> Encoding {
+    if bytes.starts_with(&[0xFF, 0xFE]) || looks_like_utf16le(bytes) {
+        Encoding::Utf16Le
+    } else if std::str::from_utf8(bytes).is_ok() {
+        Encoding::Utf8
+    } else if cyrillic_score(bytes, Encoding::Cp1251) > cyrillic_score(bytes, Encoding::Cp866) {
+        Encoding::Cp1251
+    } else {
+        Encoding::Cp866
+    }
+}
+
+pub fn decode_output(bytes: &[u8]) -> String {
+    decode_as(bytes, detect_encoding(bytes))
+}
+
+pub fn decode_as(bytes: &[u8], encoding: Encoding) -> String {
+    match encoding {
+        Encoding::Utf8 => {
+            let bytes = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]).unwrap_or(bytes);
+            String::from_utf8_lossy(bytes).to_string()
+        }
+        Encoding::Utf16Le => {
+            let bytes = bytes.strip_prefix(&[0xFF, 0xFE]).unwrap_or(bytes);
+            let units: Vec<u16> = bytes
+                .chunks_exact(2)
+                .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+                .take_while(|&unit| unit != 0)
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        Encoding::Cp866 => decode_single_byte(bytes, CP866_HIGH),
+        Encoding::Cp1251 => decode_single_byte(bytes, CP1251_HIGH),
+    }
+}
+
+fn decode_single_byte(bytes: &[u8], high: &str) -> String {
+    let high: Vec<char> = high.chars().collect();
+    bytes
+        .iter()
+        .map(|&byte| match byte {
+            0..=0x7F => byte as char,
+            _ => high[usize::from(byte - 0x80)],
+        })
+        .collect()
+}
+
+// Tool output is mostly ASCII, so UTF-16LE without a BOM shows up as zero
+// high bytes (Cyrillic text has 0x04 there).
+fn looks_like_utf16le(bytes: &[u8]) -> bool {
+    if bytes.len() < 2 || !bytes.len().is_multiple_of(2) {
+        return false;
+    }
+    let high_bytes = bytes.iter().skip(1).step_by(2);
+    let plausible = high_bytes
+        .filter(|&&byte| byte == 0x00 || byte == 0x04)
+        .count();
+    plausible * 4 >= bytes.len() / 2 * 3
+}
+
+// Both code pages put Cyrillic letters at different places; the one that
+// turns the text into mostly lowercase letters is the right one.
+fn cyrillic_score(bytes: &[u8], encoding: Encoding) -> usize {
+    let text = decode_as(bytes, encoding);
+    text.chars()
+        .map(|c| match c {
+            'а'..='я' | 'ё' => 2,
+            'А'..='Я' | 'Ё' => 1,
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: &str = "USB-устройство ввода";
+
+    fn encode_single_byte(text: &str, high: &str) -> Vec<u8> {
+        let high: Vec<char> = high.chars().collect();
+        text.chars()
+            .map(|c| match high.iter().position(|&h| h == c) {
+                Some(index) => 0x80 + index as u8,
+                None => c as u8,
+            })
+            .collect()
+    }
+
+    fn utf16le(text: &str) -> Vec<u8> {
+        text.encode_utf16()
+            .flat_map(|unit| unit.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn code_page_tables_cover_the_high_half() {
+        assert_eq!(CP866_HIGH.chars().count(), 128);
+        assert_eq!(CP1251_HIGH.chars().count(), 128);
+    }
+
+    #[test]
+    fn detects_unicode_output() {
+        let line = format!("2-11   1a2c:2124  {NAME}   Attached\r\n");
+        assert_eq!(detect_encoding(line.as_bytes()), Encoding::Utf8);
+        assert_eq!(decode_output(line.as_bytes()), line);
+
+        let mut with_bom = vec![0xEF, 0xBB, 0xBF];
+        with_bom.extend_from_slice(line.as_bytes());
+        assert_eq!(decode_output(&with_bom), line);
+
+        let utf16 = utf16le(&line);
+        assert_eq!(detect_encoding(&utf16), Encoding::Utf16Le);
+        assert_eq!(decode_output(&utf16), line);
+
+        let mut utf16_bom = vec![0xFF, 0xFE];
+        utf16_bom.extend(utf16le(&line));
+        assert_eq!(decode_output(&utf16_bom), line);
+    }
+
+    #[test]
+    fn detects_cyrillic_code_pages() {
+        let line = format!("2-11   1a2c:2124  {NAME}   Attached");
+
+        let oem = encode_single_byte(&line, CP866_HIGH);
+        assert_eq!(detect_encoding(&oem), Encoding::Cp866);
+        assert_eq!(decode_output(&oem), line);
+
+        let ansi = encode_single_byte(&line, CP1251_HIGH);
+        assert_eq!(detect_encoding(&ansi), Encoding::Cp1251);
+        assert_eq!(decode_output(&ansi), line);
+
+        let upper = encode_single_byte("ОШИБКА: Устройство не найдено", CP866_HIGH);
+        assert_eq!(decode_output(&upper), "ОШИБКА: Устройство не найдено");
+    }
+}
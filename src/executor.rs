@@ -0,0 +1,589 @@
+use crate::logging::{self, LogLevel, LogRecord};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub type OperationId = u64;
+
+pub const DEFAULT_OPERATION_TIMEOUT: Duration = Duration::from_secs(120);
+
+const SLEEP_SLICE: Duration = Duration::from_millis(50);
+
+// The error the interrupt check fails with once the deadline passes; only a
+// task error starting with it (rollback notes may follow) counts as a timeout.
+pub const TIMED_OUT: &str = "Превышено время выполнения операции";
+
+type Task<T> = Box<dyn FnOnce(&OperationContext) -> Result<T, String> + Send>;
+type ProgressSink = Arc<dyn Fn(OperationId, Report) + Send + Sync>;
+
+enum Report {
+    Progress(String),
+    Warning(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OperationOutcome<T> {
+    Succeeded(T),
+    Failed(String),
+    Cancelled,
+    TimedOut,
+}
+
+impl<T> OperationOutcome<T> {
+    // Stable names used in logs and diagnostics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OperationOutcome::Succeeded(_) => "succeeded",
+            OperationOutcome::Failed(_) => "failed",
+            OperationOutcome::Cancelled => "cancelled",
+            OperationOutcome::TimedOut => "timed_out",
+        }
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        match self {
+            OperationOutcome::Failed(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// Ids and labels are read by the window only.
+#[derive(Debug)]
+#[cfg_attr(not(windows), allow(dead_code))]
+pub enum ExecutorEvent<T> {
+    Queued {
+        id: OperationId,
+        label: String,
+    },
+    Started {
+        id: OperationId,
+        label: String,
+    },
+    Progress {
+        id: OperationId,
+        message: String,
+    },
+    // Something went wrong without failing the operation (e.g. a hook).
+    Warning {
+        label: String,
+        message: String,
+    },
+    Finished {
+        id: OperationId,
+        label: String,
+        bus_id: Option<String>,
+        outcome: OperationOutcome<T>,
+    },
+}
+
+pub struct Operation<T> {
+    label: String,
+    bus_id: Option<String>,
+    timeout: Duration,
+    quiet: bool,
+    task: Task<T>,
+}
+
+impl<T> Operation<T> {
+    pub fn new(
+        label: impl Into<String>,
+        task: impl FnOnce(&OperationContext) -> Result<T, String> + Send + 'static,
+    ) -> Self {
+        Self {
+            label: label.into(),
+            bus_id: None,
+            timeout: DEFAULT_OPERATION_TIMEOUT,
+            quiet: false,
+            task: Box::new(task),
+        }
+    }
+
+    // Operations on the same bus id never run concurrently.
+    pub fn for_device(mut self, bus_id: &str) -> Self {
+        self.bus_id = Some(bus_id.to_string());
+        self
+    }
+
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Periodic background work: successes are logged at debug level only.
+    #[cfg(windows)]
+    pub fn quiet(mut self) -> Self {
+        self.quiet = true;
+        self
+    }
+}
+
+pub struct OperationContext {
+    id: OperationId,
+    interrupt: Interrupt,
+    progress: ProgressSink,
+}
+
+#[derive(Clone)]
+struct Interrupt {
+    cancelled: Arc<AtomicBool>,
+    deadline: Instant,
+}
+
+impl Interrupt {
+    fn check(&self) -> Result<(), String> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            Err("Операция отменена".to_string())
+        } else if Instant::now() >= self.deadline {
+            Err(TIMED_OUT.to_string())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+thread_local! {
+    // The operation running on this worker thread, for code that is not
+    // handed its context (external commands, see session::run).
+    static CURRENT: RefCell<Option<Interrupt>> = const { RefCell::new(None) };
+}
+
+// Fails once the operation running on this thread is cancelled or out of
+// time; always Ok outside the executor.
+pub fn check_current() -> Result<(), String> {
+    CURRENT.with(|current| match &*current.borrow() {
+        Some(interrupt) => interrupt.check(),
+        None => Ok(()),
+    })
+}
+
+impl OperationContext {
+    pub fn progress(&self, message: impl Into<String>) {
+        (self.progress)(self.id, Report::Progress(message.into()));
+    }
+
+    pub fn warn(&self, message: impl Into<String>) {
+        (self.progress)(self.id, Report::Warning(message.into()));
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.interrupt.cancelled.load(Ordering::SeqCst)
+    }
+
+    // Tasks call this between steps; external commands still running when
+    // the operation is cancelled or times out are killed (see session::run).
+    pub fn check(&self) -> Result<(), String> {
+        self.interrupt.check()
+    }
+
+    pub fn sleep(&self, duration: Duration) -> Result<(), String> {
+        let until = Instant::now() + duration;
+        loop {
+            self.check()?;
+            let now = Instant::now();
+            if now >= until {
+                return Ok(());
+            }
+            thread::sleep(SLEEP_SLICE.min(until - now));
+        }
+    }
+}
+
+struct Job<T> {
+    id: OperationId,
+    operation: Operation<T>,
+}
+
+struct QueueState<T> {
+    next_id: OperationId,
+    queue: VecDeque<Job<T>>,
+    busy_devices: HashSet<String>,
+    running: HashMap<OperationId, (Option<String>, Arc<AtomicBool>)>,
+    shutdown: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<QueueState<T>>,
+    available: Condvar,
+    events: Mutex<Sender<ExecutorEvent<T>>>,
+    notify: Box<dyn Fn() + Send + Sync>,
+}
+
+impl<T> Shared<T> {
+    fn emit(&self, event: ExecutorEvent<T>) {
+        if let Ok(events) = self.events.lock() {
+            let _ = events.send(event);
+        }
+        (self.notify)();
+    }
+}
+
+pub struct Executor<T: Send + 'static> {
+    shared: Arc<Shared<T>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> Executor<T> {
+    // `notify` is invoked from worker threads after every event so the UI can
+    // wake up and drain the receiver on its own thread.
+    pub fn new(
+        worker_count: usize,
+        notify: impl Fn() + Send + Sync + 'static,
+    ) -> (Self, Receiver<ExecutorEvent<T>>) {
+        let (sender, receiver) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            state: Mutex::new(QueueState {
+                next_id: 1,
+                queue: VecDeque::new(),
+                busy_devices: HashSet::new(),
+                running: HashMap::new(),
+                shutdown: false,
+            }),
+            available: Condvar::new(),
+            events: Mutex::new(sender),
+            notify: Box::new(notify),
+        });
+
+        let workers = (0..worker_count.max(1))
+            .map(|_| {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || worker_loop(&shared))
+            })
+            .collect();
+
+        (Self { shared, workers }, receiver)
+    }
+
+    pub fn submit(&self, operation: Operation<T>) -> OperationId {
+        let label = operation.label.clone();
+        let id = {
+            let mut state = self.shared.state.lock().unwrap();
+            let id = state.next_id;
+            state.next_id += 1;
+            state.queue.push_back(Job { id, operation });
+            id
+        };
+        self.shared.emit(ExecutorEvent::Queued { id, label });
+        self.shared.available.notify_all();
+        id
+    }
+
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn cancel_device(&self, bus_id: &str) -> usize {
+        self.cancel_where(|_, device| device == Some(bus_id))
+    }
+
+    #[cfg(windows)]
+    pub fn cancel_all(&self) -> usize {
+        self.cancel_where(|_, _| true)
+    }
+
+    #[cfg(windows)]
+    pub fn is_device_busy(&self, bus_id: &str) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        state.busy_devices.contains(bus_id)
+            || state
+                .queue
+                .iter()
+                .any(|job| job.operation.bus_id.as_deref() == Some(bus_id))
+    }
+
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn cancel_where(&self, matches: impl Fn(OperationId, Option<&str>) -> bool) -> usize {
+        let (removed, running) = {
+            let mut state = self.shared.state.lock().unwrap();
+            let mut running = 0;
+            for (id, (bus_id, flag)) in &state.running {
+                if matches(*id, bus_id.as_deref()) {
+                    flag.store(true, Ordering::SeqCst);
+                    running += 1;
+                }
+            }
+            let (removed, kept): (VecDeque<Job<T>>, VecDeque<Job<T>>) = state
+                .queue
+                .drain(..)
+                .partition(|job| matches(job.id, job.operation.bus_id.as_deref()));
+            state.queue = kept;
+            (removed, running)
+        };
+
+        let count = removed.len() + running;
+        for job in removed {
+            let operation = &job.operation;
+            logging::operation(
+                &operation.label,
+                operation.bus_id.as_deref(),
+                None,
+                OperationOutcome::<T>::Cancelled.name(),
+                None,
+                operation.quiet,
+            );
+            self.shared.emit(ExecutorEvent::Finished {
+                id: job.id,
+                label: job.operation.label,
+                bus_id: job.operation.bus_id,
+                outcome: OperationOutcome::Cancelled,
+            });
+        }
+        count
+    }
+}
+
+impl<T: Send + 'static> Drop for Executor<T> {
+    fn drop(&mut self) {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.shutdown = true;
+            state.queue.clear();
+            for (_, flag) in state.running.values() {
+                flag.store(true, Ordering::SeqCst);
+            }
+        }
+        self.shared.available.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn worker_loop<T: Send + 'static>(shared: &Arc<Shared<T>>) {
+    loop {
+        let (job, cancelled) = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if state.shutdown {
+                    return;
+                }
+                let runnable = state.queue.iter().position(|job| {
+                    job.operation
+                        .bus_id
+                        .as_ref()
+                        .is_none_or(|bus_id| !state.busy_devices.contains(bus_id))
+                });
+                if let Some(index) = runnable {
+                    let job = state.queue.remove(index).unwrap();
+                    let cancelled = Arc::new(AtomicBool::new(false));
+                    if let Some(bus_id) = &job.operation.bus_id {
+                        state.busy_devices.insert(bus_id.clone());
+                    }
+                    state
+                        .running
+                        .insert(job.id, (job.operation.bus_id.clone(), cancelled.clone()));
+                    break (job, cancelled);
+                }
+                state = shared.available.wait(state).unwrap();
+            }
+        };
+
+        let Job { id, operation } = job;
+        let Operation {
+            label,
+            bus_id,
+            timeout,
+            quiet,
+            task,
+        } = operation;
+        let started = Instant::now();
+
+        shared.emit(ExecutorEvent::Started {
+            id,
+            label: label.clone(),
+        });
+
+        let progress_shared = Arc::clone(shared);
+        let progress_label = label.clone();
+        let context = OperationContext {
+            id,
+            interrupt: Interrupt {
+                cancelled,
+                deadline: Instant::now() + timeout,
+            },
+            progress: Arc::new(move |id, report| {
+                progress_shared.emit(match report {
+                    Report::Progress(message) => ExecutorEvent::Progress { id, message },
+                    Report::Warning(message) => {
+                        logging::log(LogRecord {
+                            operation: Some(progress_label.clone()),
+                            ..LogRecord::new(LogLevel::Warn, format!("{progress_label}: {message}"))
+                        });
+                        ExecutorEvent::Warning {
+                            label: progress_label.clone(),
+                            message,
+                        }
+                    }
+                });
+            }),
+        };
+
+        CURRENT.with(|current| *current.borrow_mut() = Some(context.interrupt.clone()));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| task(&context)));
+        CURRENT.with(|current| *current.borrow_mut() = None);
+        let outcome = match result {
+            Ok(Ok(value)) => OperationOutcome::Succeeded(value),
+            Ok(Err(_)) if context.is_cancelled() => OperationOutcome::Cancelled,
+            Ok(Err(err)) if err.starts_with(TIMED_OUT) => OperationOutcome::TimedOut,
+            Ok(Err(err)) => OperationOutcome::Failed(err),
+            Err(_) => OperationOutcome::Failed("Внутренняя ошибка операции".to_string()),
+        };
+
+        {
+            let mut state = shared.state.lock().unwrap();
+            state.running.remove(&id);
+            if let Some(bus_id) = &bus_id {
+                state.busy_devices.remove(bus_id);
+            }
+        }
+        shared.available.notify_all();
+
+        logging::operation(
+            &label,
+            bus_id.as_deref(),
+            Some(started.elapsed()),
+            outcome.name(),
+            outcome.error(),
+            quiet,
+        );
+        shared.emit(ExecutorEvent::Finished {
+            id,
+            label,
+            bus_id,
+            outcome,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wait_finished<T>(events: &Receiver<ExecutorEvent<T>>) -> (OperationId, OperationOutcome<T>) {
+        loop {
+            match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+                ExecutorEvent::Finished { id, outcome, .. } => return (id, outcome),
+                _ => continue,
+            }
+        }
+    }
+
+    #[test]
+    fn runs_operations_and_reports_progress() {
+        let (executor, events) = Executor::new(1, || {});
+        let id = executor.submit(Operation::new("sum", |ctx| {
+            ctx.progress("half way");
+            ctx.warn("almost there");
+            Ok(2 + 2)
+        }));
+
+        let mut saw_progress = false;
+        let mut saw_warning = false;
+        loop {
+            match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+                ExecutorEvent::Progress { message, .. } => saw_progress = message == "half way",
+                ExecutorEvent::Warning { label, message, .. } => {
+                    saw_warning = label == "sum" && message == "almost there"
+                }
+                ExecutorEvent::Finished {
+                    id: finished,
+                    outcome,
+                    ..
+                } => {
+                    assert_eq!(finished, id);
+                    assert_eq!(outcome, OperationOutcome::Succeeded(4));
+                    break;
+                }
+                _ => {}
+            }
+        }
+        assert!(saw_progress);
+        assert!(saw_warning);
+    }
+
+    #[test]
+    fn serializes_operations_on_same_device() {
+        let (executor, events) = Executor::new(4, || {});
+        let active = Arc::new(Mutex::new(0));
+        let overlapped = Arc::new(AtomicBool::new(false));
+
+        for _ in 0..3 {
+            let active = Arc::clone(&active);
+            let overlapped = Arc::clone(&overlapped);
+            executor.submit(
+                Operation::new("bind", move |ctx| {
+                    {
+                        let mut count = active.lock().unwrap();
+                        *count += 1;
+                        if *count > 1 {
+                            overlapped.store(true, Ordering::SeqCst);
+                        }
+                    }
+                    ctx.sleep(Duration::from_millis(30))?;
+                    *active.lock().unwrap() -= 1;
+                    Ok(())
+                })
+                .for_device("2-7"),
+            );
+        }
+
+        for _ in 0..3 {
+            assert_eq!(wait_finished(&events).1, OperationOutcome::Succeeded(()));
+        }
+        assert!(!overlapped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn cancels_running_and_queued_operations() {
+        let (executor, events) = Executor::new(1, || {});
+        let running = executor.submit(
+            Operation::new("long", |ctx| ctx.sleep(Duration::from_secs(30))).for_device("2-7"),
+        );
+        let queued = executor.submit(Operation::new("next", |_| Ok(())).for_device("2-7"));
+
+        while !matches!(
+            events.recv_timeout(Duration::from_secs(5)).unwrap(),
+            ExecutorEvent::Started { .. }
+        ) {}
+        assert_eq!(executor.cancel_device("2-7"), 2);
+
+        let mut outcomes = vec![wait_finished(&events), wait_finished(&events)];
+        outcomes.sort_by_key(|(id, _)| *id);
+        assert_eq!(
+            outcomes,
+            vec![
+                (running, OperationOutcome::Cancelled),
+                (queued, OperationOutcome::Cancelled)
+            ]
+        );
+    }
+
+    #[test]
+    fn times_out_slow_operations() {
+        let (executor, events) = Executor::new(1, || {});
+        executor.submit(
+            Operation::new("slow", |ctx| ctx.sleep(Duration::from_secs(30)))
+                .with_timeout(Duration::from_millis(50)),
+        );
+        assert_eq!(wait_finished(&events).1, OperationOutcome::TimedOut);
+    }
+
+    #[test]
+    fn keeps_task_errors_returned_after_the_deadline() {
+        let (executor, events) = Executor::new(1, || {});
+        executor.submit(
+            Operation::new("slow", |_| -> Result<(), String> {
+                thread::sleep(Duration::from_millis(100));
+                Err("usbipd: device busy".to_string())
+            })
+            .with_timeout(Duration::from_millis(20)),
+        );
+        assert_eq!(
+            wait_finished(&events).1,
+            OperationOutcome::Failed("usbipd: device busy".to_string())
+        );
+    }
+}
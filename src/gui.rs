@@ -0,0 +1,1843 @@
+use crate::config::{self, load_config, Config, DeviceSettings};
+use crate::device_view::{known_tags, SortOrder, StateFilter};
+use crate::diagnostics::{self, OperationLog};
+use crate::doctor::{self, format_report, has_failures, CheckResult, CheckStatus};
+use crate::executor::{Executor, ExecutorEvent, Operation, OperationId, OperationOutcome};
+use crate::history::{self, Action, HistoryFilter};
+use crate::inventory::{self, InventoryEntry};
+use crate::logging::{self, LogRecord};
+use crate::operations::{self, MoveOutcome};
+use crate::policy;
+use crate::usb_ids;
+use crate::usbipd::{
+    attach_auto_command, extract_bus_id, extract_state_from_display, format_device_display,
+    is_auto_attachable_state, is_bindable_state, is_unbindable_state, UsbDevice,
+};
+use crate::watcher::{AttachWatcher, KeepAlive};
+use crate::wsl::{describe_attach_check, list_wsl_distros, AttachCheck, WslDistro};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::iter::once;
+use std::os::windows::ffi::OsStrExt;
+use std::process::{Child, Command};
+use std::ptr;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+use usbipd_agent::sysfs::SysfsUsbDevice;
+use winapi::shared::minwindef::{LPARAM, LRESULT, UINT, WPARAM};
+use winapi::shared::windef::{COLORREF, HFONT, HMENU, HWND};
+use winapi::um::libloaderapi::GetModuleHandleW;
+use winapi::um::processthreadsapi::ExitProcess;
+use winapi::um::wingdi::{GetStockObject, SetBkMode, SetTextColor, DEFAULT_GUI_FONT, TRANSPARENT};
+use winapi::um::winuser::{
+    CreateWindowExW, DefWindowProcW, DispatchMessageW, DrawFocusRect, DrawTextW, FillRect,
+    GetDlgItem, GetMessageW, GetSysColor, GetSysColorBrush, GetWindowLongPtrW, GetWindowTextW,
+    InvalidateRect, KillTimer, LoadCursorW, LoadIconW, MessageBoxW, PostMessageW, PostQuitMessage,
+    RegisterClassW, SendMessageW, SetTimer, SetWindowLongPtrW, SetWindowTextW, ShowWindow,
+    TranslateMessage, UpdateWindow, BM_GETCHECK, BM_SETCHECK, BST_CHECKED, BST_UNCHECKED,
+    BS_AUTOCHECKBOX, BS_DEFPUSHBUTTON, CBN_SELCHANGE, CBS_DROPDOWNLIST, CB_ADDSTRING, CB_ERR,
+    CB_GETCOUNT, CB_GETCURSEL, CB_GETLBTEXT, CB_RESETCONTENT, CB_SETCURSEL, COLOR_GRAYTEXT,
+    COLOR_HIGHLIGHT, COLOR_HIGHLIGHTTEXT, COLOR_WINDOW, COLOR_WINDOWTEXT, CS_HREDRAW, CS_VREDRAW,
+    CW_USEDEFAULT, DRAWITEMSTRUCT, DT_NOPREFIX, DT_SINGLELINE, DT_VCENTER, EN_CHANGE,
+    ES_AUTOHSCROLL, IDC_ARROW, IDI_APPLICATION, IDYES, LBN_SELCHANGE, LBS_HASSTRINGS, LBS_NOTIFY,
+    LBS_OWNERDRAWFIXED, LB_ADDSTRING, LB_DELETESTRING, LB_GETCOUNT, LB_GETCURSEL, LB_GETTEXT,
+    LB_RESETCONTENT, LB_SETCURSEL, LB_SETITEMDATA, LB_SETTOPINDEX, MB_ICONERROR,
+    MB_ICONINFORMATION, MB_ICONWARNING, MB_OK, MB_YESNO, MEASUREITEMSTRUCT, MSG, ODS_FOCUS,
+    ODS_SELECTED, SS_LEFT, SW_SHOW, WM_APP, WM_COMMAND, WM_DESTROY, WM_DRAWITEM, WM_MEASUREITEM,
+    WM_SETFONT, WM_TIMER, WNDCLASSW, WS_BORDER, WS_CHILD, WS_CLIPCHILDREN, WS_OVERLAPPEDWINDOW,
+    WS_VISIBLE, WS_VSCROLL,
+};
+
+const WM_EXECUTOR_EVENT: UINT = WM_APP + 1;
+const EXECUTOR_WORKERS: usize = 2;
+const WATCH_TIMER_ID: usize = 1;
+const WATCH_INTERVAL_MS: UINT = 5000;
+// The view is saved once the search and filters have stayed unchanged for a
+// second, not on every keystroke; it is also saved on exit.
+const VIEW_SAVE_TIMER_ID: usize = 2;
+const VIEW_SAVE_DELAY_MS: UINT = 1000;
+// A hung `usbipd list` is killed well before the default operation timeout so
+// the periodic check resumes.
+const LIST_TIMEOUT: Duration = Duration::from_secs(30);
+// Item data of device list rows for devices that are not connected and for
+// devices bound with --force.
+const OFFLINE_ITEM: LPARAM = 1;
+const FORCED_ITEM: LPARAM = 2;
+const FORCED_STATE: &str = "Shared (forced)";
+const FORCED_TEXT_COLOR: COLORREF = 0x0000_50C0; // dark orange, 0x00BBGGRR
+const LIST_ITEM_HEIGHT: UINT = 18;
+// Older lines stay in the log file.
+const LOG_PANE_LINES: isize = 500;
+// Entries shown by the "История" button; the CLI can print all of them.
+const HISTORY_DIALOG_ENTRIES: usize = 30;
+
+enum OperationOutput {
+    Done,
+    Message(String),
+    Devices(Vec<UsbDevice>, Vec<WslDistro>),
+    // The device and why the detach was refused.
+    DetachBusy(UsbDevice, String),
+    // The device, the distro it was moved to and the attach check message.
+    Moved(UsbDevice, String, String),
+    // The device, the distro it was being moved to and why the detach from
+    // its current one was refused.
+    MoveBusy(UsbDevice, String, String),
+    Doctor(Vec<CheckResult>),
+}
+
+struct AppState {
+    auto_attach_processes: HashMap<String, Child>,
+    auto_attach_restored: bool,
+    // Supervisors stopped for a move; restarted with the new distro once the
+    // device list is refreshed.
+    auto_attach_suspended: HashSet<String>,
+    config: Config,
+    devices: Vec<UsbDevice>,
+    // Known devices that are not connected; listed greyed out after `devices`.
+    offline: Vec<InventoryEntry>,
+    executor: Executor<OperationOutput>,
+    events: Receiver<ExecutorEvent<OperationOutput>>,
+    keep_alive: KeepAlive,
+    log_records: Receiver<LogRecord>,
+    // Included in exported diagnostics.
+    operation_log: OperationLog,
+    watcher: AttachWatcher,
+    // The periodic background refresh; it stays out of the status line and
+    // error dialogs.
+    watch_operation: Option<OperationId>,
+}
+
+// Owned by the window through GWLP_USERDATA, see with_state.
+struct Window {
+    state: RefCell<AppState>,
+    deferred: Cell<bool>,
+}
+
+impl AppState {
+    fn new(hwnd: HWND) -> Self {
+        let hwnd_value = hwnd as isize;
+        let (executor, events) = Executor::new(EXECUTOR_WORKERS, move || unsafe {
+            PostMessageW(hwnd_value as HWND, WM_EXECUTOR_EVENT, 0, 0);
+        });
+        Self {
+            auto_attach_processes: HashMap::new(),
+            auto_attach_restored: false,
+            auto_attach_suspended: HashSet::new(),
+            config: load_config(),
+            devices: Vec::new(),
+            offline: Vec::new(),
+            executor,
+            events,
+            keep_alive: KeepAlive::default(),
+            log_records: logging::subscribe(),
+            operation_log: OperationLog::default(),
+            watcher: AttachWatcher::default(),
+            watch_operation: None,
+        }
+    }
+
+    // A config.json that failed to load is kept as is; the error goes to the log.
+    fn save_config(&self) {
+        if let Err(err) = config::save_config(&self.config) {
+            logging::error(err);
+        }
+    }
+
+    fn find_offline(&self, key: &str) -> Option<InventoryEntry> {
+        self.offline
+            .iter()
+            .find(|entry| entry.key() == key)
+            .cloned()
+    }
+
+    fn find_device(&self, bus_id: &str) -> Option<UsbDevice> {
+        self.devices
+            .iter()
+            .find(|device| device.bus_id == bus_id)
+            .cloned()
+    }
+
+    fn submit(&self, operation: Operation<OperationOutput>) {
+        self.executor.submit(operation);
+    }
+
+    fn list_operation(&self, label: &str) -> Operation<OperationOutput> {
+        let config = self.config.clone();
+        Operation::new(label, move |_| {
+            let devices = operations::list_devices(&config)?;
+            let distros = list_wsl_distros().unwrap_or_default();
+            Ok(OperationOutput::Devices(devices, distros))
+        })
+        .with_timeout(LIST_TIMEOUT)
+    }
+
+    fn refresh_devices(&self) {
+        self.submit(self.list_operation("Обновление списка"));
+    }
+
+    fn watch_devices(&mut self) {
+        if self.watch_operation.is_none() {
+            let operation = self.list_operation("Проверка устройств").quiet();
+            self.watch_operation = Some(self.executor.submit(operation));
+        }
+    }
+
+    // Runs on every device list: notices distros that were shut down,
+    // attaches the devices they dropped and keeps the remaining ones alive.
+    fn check_distros(&mut self, distros: &[WslDistro]) {
+        for distro in self.keep_alive.take_exited() {
+            logging::info(format!("Дистрибутив {distro} был остановлен"));
+            self.watcher.distro_stopped(&distro);
+        }
+        let lost = self.watcher.update(
+            &self.devices,
+            &self.config,
+            distros,
+            history::last_attach_target,
+        );
+        for (device, wsl_distro) in lost {
+            let bus_id = device.bus_id.clone();
+            let settings = DeviceSettings {
+                wsl_distro,
+                ..self.config.settings_for(&device)
+            };
+            logging::info(format!(
+                "Повторное подключение {bus_id} к {} после остановки дистрибутива",
+                settings.wsl_distro
+            ));
+            self.submit(
+                Operation::new(
+                    format!("Повторное подключение {bus_id}"),
+                    move |ctx| {
+                        operations::attach_and_verify(ctx, &device, &settings).map(|found| {
+                            attached_message(found, &device.vid_pid, &settings.wsl_distro)
+                        })
+                    },
+                )
+                .for_device(&bus_id),
+            );
+        }
+        let wanted = if self.config.keep_distro_alive {
+            self.watcher.attached_distros()
+        } else {
+            Vec::new()
+        };
+        self.keep_alive.update(&wanted, distros);
+    }
+
+    // Per-device settings may be keyed by VID:PID, so this waits for the
+    // first device list instead of running at startup.
+    fn restore_auto_attach(&mut self, hwnd: HWND) {
+        self.auto_attach_restored = true;
+        // usbipd waits for devices that are not plugged in yet.
+        let devices: Vec<UsbDevice> = self
+            .devices
+            .iter()
+            .cloned()
+            .chain(self.offline.iter().map(InventoryEntry::as_device))
+            .filter(|device| self.config.settings_for(device).auto_attach)
+            .collect();
+        for device in devices {
+            self.start_auto_attach(&device, hwnd);
+        }
+    }
+
+    fn start_auto_attach(&mut self, device: &UsbDevice, hwnd: HWND) {
+        let bus_id = device.bus_id.as_str();
+        if self.auto_attach_processes.contains_key(bus_id) {
+            logging::info(format!("Auto-Attach уже запущен для устройства {bus_id}"));
+            return;
+        }
+
+        let wsl_distro = self.config.settings_for(device).wsl_distro;
+        let command = attach_auto_command(bus_id, &wsl_distro);
+        logging::info(format!(
+            "Запуск Auto-Attach для устройства {bus_id}: {command}"
+        ));
+
+        let spawned = Command::new("cmd")
+            .args(["/C", &command])
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn();
+        let result = spawned.as_ref().map(|_| ()).map_err(|e| e.to_string());
+        history::record(Action::AutoAttachStart, device, Some(&wsl_distro), &result);
+        match spawned {
+            Ok(child) => {
+                self.auto_attach_processes.insert(bus_id.to_string(), child);
+                if !self.config.settings_for(device).auto_attach {
+                    self.config.set_auto_attach(device, true);
+                    self.save_config();
+                }
+            }
+            Err(e) => {
+                logging::error(format!("Ошибка запуска Auto-Attach для {bus_id}: {e}"));
+                show_error(hwnd, &format!("Ошибка запуска Auto-Attach: {e}"));
+            }
+        }
+    }
+
+    fn stop_auto_attach(&mut self, device: &UsbDevice) {
+        let bus_id = device.bus_id.as_str();
+        if let Some(mut child) = self.auto_attach_processes.remove(bus_id) {
+            let _ = child.kill();
+            let _ = child.wait();
+            history::record(Action::AutoAttachStop, device, None, &Ok(()));
+            logging::info(format!("Auto-Attach остановлен для устройства {bus_id}"));
+        }
+        if self.config.settings_for(device).auto_attach {
+            self.config.set_auto_attach(device, false);
+            self.save_config();
+        }
+    }
+
+    fn suspend_auto_attach(&mut self, bus_id: &str) {
+        if let Some(mut child) = self.auto_attach_processes.remove(bus_id) {
+            let _ = child.kill();
+            let _ = child.wait();
+            self.record_auto_attach_stop(bus_id);
+            self.auto_attach_suspended.insert(bus_id.to_string());
+        }
+    }
+
+    fn resume_auto_attach(&mut self, hwnd: HWND) {
+        let suspended: Vec<String> = self.auto_attach_suspended.drain().collect();
+        for bus_id in suspended {
+            // Until a move has finished the config still names the old distro.
+            if self.executor.is_device_busy(&bus_id) {
+                self.auto_attach_suspended.insert(bus_id);
+                continue;
+            }
+            let offline = self
+                .offline
+                .iter()
+                .find(|entry| entry.last_bus_id == bus_id)
+                .map(InventoryEntry::as_device);
+            if let Some(device) = self.find_device(&bus_id).or(offline) {
+                self.start_auto_attach(&device, hwnd);
+            }
+        }
+    }
+
+    fn record_auto_attach_stop(&self, bus_id: &str) {
+        let offline = self
+            .offline
+            .iter()
+            .find(|entry| entry.last_bus_id == bus_id)
+            .map(InventoryEntry::as_device);
+        if let Some(device) = self.find_device(bus_id).or(offline) {
+            history::record(Action::AutoAttachStop, &device, None, &Ok(()));
+        }
+    }
+
+    fn shutdown_auto_attach_processes(&mut self) {
+        let processes: Vec<(String, Child)> = self.auto_attach_processes.drain().collect();
+        for (bus_id, mut child) in processes {
+            let _ = child.kill();
+            let _ = child.wait();
+            self.record_auto_attach_stop(&bus_id);
+        }
+        self.keep_alive.shutdown();
+        self.save_config();
+    }
+}
+
+pub fn run() {
+    unsafe {
+        let class_name: Vec<u16> = OsStr::new("USBIPD_GUI")
+            .encode_wide()
+            .chain(once(0))
+            .collect();
+        let h_instance = GetModuleHandleW(ptr::null());
+        let h_icon = LoadIconW(ptr::null_mut(), IDI_APPLICATION);
+        let wc = WNDCLASSW {
+            style: CS_HREDRAW | CS_VREDRAW,
+            lpfnWndProc: Some(wnd_proc),
+            cbClsExtra: 0,
+            cbWndExtra: 0,
+            hInstance: h_instance,
+            hIcon: h_icon,
+            hCursor: LoadCursorW(ptr::null_mut(), IDC_ARROW),
+            hbrBackground: (COLOR_WINDOW + 1) as _,
+            lpszMenuName: ptr::null(),
+            lpszClassName: class_name.as_ptr(),
+        };
+        if RegisterClassW(&wc) == 0 {
+            ExitProcess(1);
+        }
+
+        let hwnd = CreateWindowExW(
+            0,
+            class_name.as_ptr(),
+            OsStr::new("USBIPD Manager")
+                .encode_wide()
+                .chain(once(0))
+                .collect::<Vec<u16>>()
+                .as_ptr(),
+            WS_OVERLAPPEDWINDOW | WS_VISIBLE | WS_CLIPCHILDREN,
+            CW_USEDEFAULT,
+            CW_USEDEFAULT,
+            800,
+            900,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            h_instance,
+            ptr::null_mut(),
+        );
+        if hwnd.is_null() {
+            ExitProcess(1);
+        }
+
+        let state = AppState::new(hwnd);
+        let config = state.config.clone();
+        let window_ptr = Box::into_raw(Box::new(Window {
+            state: RefCell::new(state),
+            deferred: Cell::new(false),
+        }));
+        SetWindowLongPtrW(
+            hwnd,
+            winapi::um::winuser::GWLP_USERDATA,
+            window_ptr as isize,
+        );
+
+        let hwnd_list = CreateWindowExW(
+            0,
+            OsStr::new("LISTBOX")
+                .encode_wide()
+                .chain(once(0))
+                .collect::<Vec<u16>>()
+                .as_ptr(),
+            ptr::null(),
+            WS_CHILD | WS_VISIBLE | WS_VSCROLL | LBS_NOTIFY | LBS_HASSTRINGS | LBS_OWNERDRAWFIXED,
+            10,
+            40,
+            760,
+            420,
+            hwnd,
+            100 as HMENU,
+            h_instance,
+            ptr::null_mut(),
+        );
+        if hwnd_list.is_null() {
+            SetWindowLongPtrW(hwnd, winapi::um::winuser::GWLP_USERDATA, 0);
+            let _ = Box::from_raw(window_ptr);
+            ExitProcess(1);
+        }
+
+        let font: HFONT = GetStockObject(DEFAULT_GUI_FONT.try_into().unwrap()) as HFONT;
+        SendMessageW(hwnd_list, WM_SETFONT, font as WPARAM, 1 as LPARAM);
+
+        // Search, filters and sorting above the list, restored from config.json.
+        let search_w: Vec<u16> = OsStr::new(&config.view.search)
+            .encode_wide()
+            .chain(once(0))
+            .collect();
+        let hwnd_search = CreateWindowExW(
+            0,
+            OsStr::new("EDIT")
+                .encode_wide()
+                .chain(once(0))
+                .collect::<Vec<u16>>()
+                .as_ptr(),
+            search_w.as_ptr(),
+            WS_CHILD | WS_VISIBLE | WS_BORDER | ES_AUTOHSCROLL,
+            10,
+            10,
+            300,
+            23,
+            hwnd,
+            301 as HMENU,
+            h_instance,
+            ptr::null_mut(),
+        );
+        SendMessageW(hwnd_search, WM_SETFONT, font as WPARAM, 1 as LPARAM);
+        for (id, x, width) in [(302, 320, 150), (303, 480, 140), (304, 630, 140)] {
+            let hwnd_combo = CreateWindowExW(
+                0,
+                OsStr::new("COMBOBOX")
+                    .encode_wide()
+                    .chain(once(0))
+                    .collect::<Vec<u16>>()
+                    .as_ptr(),
+                ptr::null(),
+                WS_CHILD | WS_VISIBLE | WS_VSCROLL | CBS_DROPDOWNLIST,
+                x,
+                10,
+                width,
+                200,
+                hwnd,
+                id as HMENU,
+                h_instance,
+                ptr::null_mut(),
+            );
+            SendMessageW(hwnd_combo, WM_SETFONT, font as WPARAM, 1 as LPARAM);
+        }
+        fill_view_controls(hwnd, &config);
+
+        let warning_text = OsStr::new(
+            "При проблемах с подключением нажмите «Диагностика»: будут проверены usbipd,\r\n\
+             WSL и ядро дистрибутива, порт 3240, USBdk, VPN и config.json.\r\n\
+             WSL-дистрибутив настраивается в config.json (поле wsl_distro).",
+        )
+        .encode_wide()
+        .chain(once(0))
+        .collect::<Vec<u16>>();
+        let hwnd_static = CreateWindowExW(
+            0,
+            OsStr::new("STATIC")
+                .encode_wide()
+                .chain(once(0))
+                .collect::<Vec<u16>>()
+                .as_ptr(),
+            warning_text.as_ptr(),
+            WS_CHILD | WS_VISIBLE | SS_LEFT,
+            10,
+            500,
+            760,
+            55,
+            hwnd,
+            200 as HMENU,
+            h_instance,
+            ptr::null_mut(),
+        );
+        SendMessageW(hwnd_static, WM_SETFONT, font as WPARAM, 1 as LPARAM);
+
+        let hwnd_status = CreateWindowExW(
+            0,
+            OsStr::new("STATIC")
+                .encode_wide()
+                .chain(once(0))
+                .collect::<Vec<u16>>()
+                .as_ptr(),
+            ptr::null(),
+            WS_CHILD | WS_VISIBLE | SS_LEFT,
+            10,
+            468,
+            760,
+            22,
+            hwnd,
+            201 as HMENU,
+            h_instance,
+            ptr::null_mut(),
+        );
+        SendMessageW(hwnd_status, WM_SETFONT, font as WPARAM, 1 as LPARAM);
+
+        let hwnd_distros = CreateWindowExW(
+            0,
+            OsStr::new("COMBOBOX")
+                .encode_wide()
+                .chain(once(0))
+                .collect::<Vec<u16>>()
+                .as_ptr(),
+            ptr::null(),
+            WS_CHILD | WS_VISIBLE | WS_VSCROLL | CBS_DROPDOWNLIST,
+            620,
+            572,
+            150,
+            200,
+            hwnd,
+            300 as HMENU,
+            h_instance,
+            ptr::null_mut(),
+        );
+        SendMessageW(hwnd_distros, WM_SETFONT, font as WPARAM, 1 as LPARAM);
+        fill_distro_list(hwnd_distros, &[], &config.wsl_distro);
+
+        let hwnd_log = CreateWindowExW(
+            0,
+            OsStr::new("LISTBOX")
+                .encode_wide()
+                .chain(once(0))
+                .collect::<Vec<u16>>()
+                .as_ptr(),
+            ptr::null(),
+            WS_CHILD | WS_VISIBLE | WS_VSCROLL | LBS_HASSTRINGS,
+            10,
+            715,
+            760,
+            130,
+            hwnd,
+            202 as HMENU,
+            h_instance,
+            ptr::null_mut(),
+        );
+        SendMessageW(hwnd_log, WM_SETFONT, font as WPARAM, 1 as LPARAM);
+
+        for (label, id, x, y, w, h) in [
+            ("Bind", 101, 10, 565, 100, 40),
+            ("Unbind", 102, 120, 565, 100, 40),
+            ("Attach", 103, 230, 565, 100, 40),
+            ("Detach", 104, 340, 565, 100, 40),
+            ("Auto Attach", 105, 10, 615, 130, 40),
+            ("Stop Auto-Attach", 107, 150, 615, 150, 40),
+            ("Обновить", 106, 310, 615, 100, 40),
+            ("Отмена", 108, 420, 615, 100, 40),
+            ("Подключить к WSL", 109, 450, 565, 160, 40),
+            ("Переместить в дистрибутив", 110, 530, 615, 240, 40),
+            ("Диагностика", 111, 10, 665, 130, 40),
+            ("Экспорт диагностики", 112, 150, 665, 180, 40),
+            ("История", 113, 340, 665, 100, 40),
+            ("Сменить режим bind", 115, 620, 665, 150, 40),
+        ] {
+            create_button(hwnd, h_instance, label, id, x, y, w, h);
+        }
+        // Bind and "Подключить к WSL" use --force when checked; it follows the
+        // device's force_bind setting when a device is selected.
+        CreateWindowExW(
+            0,
+            OsStr::new("BUTTON")
+                .encode_wide()
+                .chain(once(0))
+                .collect::<Vec<u16>>()
+                .as_ptr(),
+            OsStr::new("Bind с --force")
+                .encode_wide()
+                .chain(once(0))
+                .collect::<Vec<u16>>()
+                .as_ptr(),
+            WS_CHILD | WS_VISIBLE | BS_AUTOCHECKBOX,
+            450,
+            665,
+            160,
+            40,
+            hwnd,
+            114 as HMENU,
+            h_instance,
+            ptr::null_mut(),
+        );
+        set_force_checkbox(hwnd, config.force_bind);
+
+        for id in 101..=115 {
+            let hwnd_button = GetDlgItem(hwnd, id);
+            SendMessageW(hwnd_button, WM_SETFONT, font as WPARAM, 1 as LPARAM);
+        }
+
+        with_state(hwnd, |state| state.refresh_devices());
+
+        SetTimer(hwnd, WATCH_TIMER_ID, WATCH_INTERVAL_MS, None);
+
+        ShowWindow(hwnd, SW_SHOW);
+        UpdateWindow(hwnd);
+
+        if let Some(err) = config::load_error() {
+            show_warning(
+                hwnd,
+                &format!("{err}\r\n\r\nИспользуются настройки по умолчанию. Файл не будет перезаписан, пока его не исправят."),
+            );
+        }
+
+        let mut msg: MSG = std::mem::zeroed();
+        while GetMessageW(&mut msg, ptr::null_mut(), 0, 0) > 0 {
+            TranslateMessage(&msg);
+            DispatchMessageW(&msg);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+unsafe fn create_button(
+    parent: HWND,
+    h_instance: winapi::shared::minwindef::HINSTANCE,
+    label: &str,
+    id: i32,
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+) {
+    CreateWindowExW(
+        0,
+        OsStr::new("BUTTON")
+            .encode_wide()
+            .chain(once(0))
+            .collect::<Vec<u16>>()
+            .as_ptr(),
+        OsStr::new(label)
+            .encode_wide()
+            .chain(once(0))
+            .collect::<Vec<u16>>()
+            .as_ptr(),
+        WS_CHILD | WS_VISIBLE | BS_DEFPUSHBUTTON,
+        x,
+        y,
+        width,
+        height,
+        parent,
+        id as HMENU,
+        h_instance,
+        ptr::null_mut(),
+    );
+}
+
+unsafe extern "system" fn wnd_proc(
+    hwnd: HWND,
+    msg: UINT,
+    wparam: WPARAM,
+    lparam: LPARAM,
+) -> LRESULT {
+    match msg {
+        WM_COMMAND => {
+            let control_id = (wparam & 0xFFFF) as u16;
+            let notification = ((wparam >> 16) & 0xFFFF) as u16;
+            let hwnd_list = GetDlgItem(hwnd, 100);
+
+            with_state(hwnd, |state| match control_id {
+                100 if notification == LBN_SELCHANGE => {
+                    show_selected_details(hwnd, hwnd_list, state);
+                    sync_force_checkbox(hwnd, hwnd_list, state);
+                }
+                301 if notification == EN_CHANGE => {
+                    handle_view_changed(hwnd, hwnd_list, state, control_id)
+                }
+                302..=304 if notification == CBN_SELCHANGE => {
+                    handle_view_changed(hwnd, hwnd_list, state, control_id)
+                }
+                101 => handle_bind(hwnd, hwnd_list, state),
+                102 => handle_unbind(hwnd, hwnd_list, state),
+                103 => handle_attach(hwnd, hwnd_list, state),
+                104 => handle_detach(hwnd, hwnd_list, state),
+                105 => handle_auto_attach(hwnd, hwnd_list, state),
+                107 => handle_stop_auto_attach(hwnd, hwnd_list, state),
+                106 => state.refresh_devices(),
+                108 => handle_cancel(hwnd, hwnd_list, state),
+                109 => handle_connect_to_wsl(hwnd, hwnd_list, state),
+                110 => handle_move_to_distro(hwnd, hwnd_list, state),
+                111 => handle_doctor(state),
+                112 => handle_export_diagnostics(state),
+                113 => handle_history(hwnd, hwnd_list),
+                115 => handle_rebind(hwnd, hwnd_list, state),
+                _ => {}
+            });
+            0
+        }
+        WM_MEASUREITEM => {
+            let item = &mut *(lparam as *mut MEASUREITEMSTRUCT);
+            item.itemHeight = LIST_ITEM_HEIGHT;
+            1
+        }
+        WM_DRAWITEM if wparam == 100 => {
+            draw_list_item(&*(lparam as *const DRAWITEMSTRUCT));
+            1
+        }
+        WM_EXECUTOR_EVENT => {
+            with_state(hwnd, |state| {
+                handle_executor_events(hwnd, state);
+                show_log_records(hwnd, state);
+            });
+            0
+        }
+        WM_TIMER if wparam == WATCH_TIMER_ID => {
+            with_state(hwnd, |state| {
+                state.watch_devices();
+                show_log_records(hwnd, state);
+            });
+            0
+        }
+        WM_TIMER if wparam == VIEW_SAVE_TIMER_ID => {
+            KillTimer(hwnd, VIEW_SAVE_TIMER_ID);
+            with_state(hwnd, |state| state.save_config());
+            0
+        }
+        WM_DESTROY => {
+            KillTimer(hwnd, WATCH_TIMER_ID);
+            KillTimer(hwnd, VIEW_SAVE_TIMER_ID);
+            let window_ptr =
+                GetWindowLongPtrW(hwnd, winapi::um::winuser::GWLP_USERDATA) as *mut Window;
+            // Still borrowed only if the window is destroyed from inside a
+            // modal loop; the process is about to exit then anyway.
+            if !window_ptr.is_null() && (*window_ptr).state.try_borrow_mut().is_ok() {
+                SetWindowLongPtrW(hwnd, winapi::um::winuser::GWLP_USERDATA, 0);
+                let window = Box::from_raw(window_ptr);
+                let mut state = window.state.into_inner();
+                state.executor.cancel_all();
+                state.shutdown_auto_attach_processes();
+            }
+            PostQuitMessage(0);
+            0
+        }
+        _ => DefWindowProcW(hwnd, msg, wparam, lparam),
+    }
+}
+
+// Runs `f` with the window's state. Message boxes run a modal loop that
+// dispatches timer and executor messages back to wnd_proc while a handler
+// still holds the state; those are deferred until the handler returns.
+unsafe fn with_state(hwnd: HWND, f: impl FnOnce(&mut AppState)) {
+    let window_ptr = GetWindowLongPtrW(hwnd, winapi::um::winuser::GWLP_USERDATA) as *const Window;
+    if window_ptr.is_null() {
+        return;
+    }
+    let window = &*window_ptr;
+    let Ok(mut state) = window.state.try_borrow_mut() else {
+        window.deferred.set(true);
+        return;
+    };
+    f(&mut state);
+    drop(state);
+    if window.deferred.replace(false) {
+        PostMessageW(hwnd, WM_EXECUTOR_EVENT, 0, 0);
+    }
+}
+
+fn handle_executor_events(hwnd: HWND, state: &mut AppState) {
+    let hwnd_list = unsafe { GetDlgItem(hwnd, 100) };
+    let events: Vec<_> = state.events.try_iter().collect();
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+    let mut busy = Vec::new();
+    let mut reports = Vec::new();
+    let mut needs_refresh = false;
+
+    for event in events {
+        let watch = state.watch_operation;
+        if !matches!(&event, ExecutorEvent::Finished { id, .. } if watch == Some(*id)) {
+            state.operation_log.record(&event);
+        }
+        match event {
+            ExecutorEvent::Queued { id, .. }
+            | ExecutorEvent::Started { id, .. }
+            | ExecutorEvent::Progress { id, .. }
+                if watch == Some(id) => {}
+            ExecutorEvent::Queued { label, .. } => set_status(hwnd, &format!("В очереди: {label}")),
+            ExecutorEvent::Started { label, .. } => {
+                set_status(hwnd, &format!("Выполняется: {label}"))
+            }
+            ExecutorEvent::Progress { message, .. } => set_status(hwnd, &message),
+            ExecutorEvent::Warning { label, message, .. } => {
+                warnings.push(format!("{label}: {message}"))
+            }
+            ExecutorEvent::Finished { id, outcome, .. } if watch == Some(id) => {
+                state.watch_operation = None;
+                // A failed background check is already in the log and should
+                // not interrupt the user.
+                if let OperationOutcome::Succeeded(OperationOutput::Devices(devices, distros)) =
+                    outcome
+                {
+                    let offline = inventory::offline(&devices);
+                    if devices != state.devices || offline != state.offline {
+                        fill_usb_list(hwnd_list, &devices, &offline, &state.config);
+                        state.devices = devices;
+                        state.offline = offline;
+                    }
+                    state.check_distros(&distros);
+                    update_distro_list(hwnd, &distros, &state.config.wsl_distro);
+                }
+            }
+            ExecutorEvent::Finished { label, outcome, .. } => match outcome {
+                OperationOutcome::Succeeded(OperationOutput::Devices(devices, distros)) => {
+                    let offline = inventory::offline(&devices);
+                    fill_usb_list(hwnd_list, &devices, &offline, &state.config);
+                    state.devices = devices;
+                    state.offline = offline;
+                    if !state.auto_attach_restored {
+                        state.restore_auto_attach(hwnd);
+                    }
+                    state.resume_auto_attach(hwnd);
+                    state.check_distros(&distros);
+                    update_distro_list(hwnd, &distros, &state.config.wsl_distro);
+                    set_status(hwnd, "");
+                }
+                OperationOutcome::Succeeded(OperationOutput::Done) => {
+                    set_status(hwnd, &format!("Готово: {label}"));
+                    needs_refresh = true;
+                }
+                OperationOutcome::Succeeded(OperationOutput::Message(message)) => {
+                    set_status(hwnd, &format!("Готово: {label} — {message}"));
+                    needs_refresh = true;
+                }
+                OperationOutcome::Succeeded(OperationOutput::Doctor(results)) => {
+                    set_status(hwnd, &format!("Готово: {label}"));
+                    reports.push(results);
+                }
+                OperationOutcome::Succeeded(OperationOutput::DetachBusy(device, refusal)) => {
+                    set_status(hwnd, &format!("Устройство занято: {label}"));
+                    busy.push((device, None, refusal));
+                }
+                // The distro is saved only now, so a failed move leaves the
+                // device configured for the distro it is still attached to.
+                OperationOutcome::Succeeded(OperationOutput::Moved(device, target, message)) => {
+                    state.config.set_device_distro(&device, &target);
+                    state.save_config();
+                    set_status(hwnd, &format!("Готово: {label} — {message}"));
+                    needs_refresh = true;
+                }
+                OperationOutcome::Succeeded(OperationOutput::MoveBusy(device, target, refusal)) => {
+                    set_status(hwnd, &format!("Устройство занято: {label}"));
+                    busy.push((device, Some(target), refusal));
+                    needs_refresh = true;
+                }
+                OperationOutcome::Failed(err) => {
+                    set_status(hwnd, &format!("Ошибка: {label}"));
+                    errors.push(format!("{label}: {err}"));
+                    needs_refresh = true;
+                }
+                OperationOutcome::Cancelled => {
+                    set_status(hwnd, &format!("Отменено: {label}"));
+                    needs_refresh = true;
+                }
+                OperationOutcome::TimedOut => {
+                    set_status(hwnd, &format!("Превышено время ожидания: {label}"));
+                    errors.push(format!("{label}: превышено время ожидания"));
+                    needs_refresh = true;
+                }
+            },
+        }
+    }
+
+    if needs_refresh {
+        state.refresh_devices();
+    }
+    for error in errors {
+        show_error(hwnd, &error);
+    }
+    if !warnings.is_empty() {
+        show_warning(hwnd, &warnings.join("\n"));
+    }
+    for results in reports {
+        show_report(hwnd, &results);
+    }
+    for (device, target, refusal) in busy {
+        let question = format!(
+            "{refusal}\n\nОтключить принудительно? \
+             Файловые системы будут отмонтированы, несохранённые данные могут быть потеряны."
+        );
+        if confirm(hwnd, &question) {
+            match target {
+                Some(target) => submit_move(state, device, target, true),
+                None => submit_detach(state, device, true),
+            }
+        }
+    }
+}
+
+fn handle_bind(hwnd: HWND, hwnd_list: HWND, state: &AppState) {
+    let Some(bus_id) = get_selected_connected(hwnd, hwnd_list, state) else {
+        return;
+    };
+
+    let state_str = get_list_item_state(hwnd_list).unwrap_or_else(|| "Unknown".to_string());
+    if !is_bindable_state(&state_str) {
+        show_error(hwnd, "Устройство уже привязано");
+        return;
+    }
+
+    let Some(device) = state.devices.iter().find(|device| device.bus_id == bus_id) else {
+        show_error(hwnd, "Устройство не найдено, обновите список");
+        return;
+    };
+
+    logging::debug(format!("Попытка выполнить bind для bus_id: {bus_id}"));
+    let device = device.clone();
+    let mut settings = state.config.settings_for(&device);
+    settings.force_bind = is_force_checked(hwnd);
+    if !confirm_bind(hwnd, state, &device, &settings) {
+        return;
+    }
+    state.submit(
+        Operation::new(format!("Bind {bus_id}"), move |ctx| {
+            operations::bind(ctx, &device, &settings).map(|()| OperationOutput::Done)
+        })
+        .for_device(&bus_id),
+    );
+}
+
+// Deny and confirm rules from `protect`, and the last keyboard or mouse left
+// to Windows.
+fn confirm_bind(
+    hwnd: HWND,
+    state: &AppState,
+    device: &UsbDevice,
+    settings: &DeviceSettings,
+) -> bool {
+    let mut confirmations = match policy::bind_confirmations(
+        &state.config.protect,
+        &settings.protection,
+        device,
+        &state.devices,
+    ) {
+        Ok(confirmations) => confirmations,
+        Err(reason) => {
+            show_error(hwnd, &reason);
+            return false;
+        }
+    };
+    if let Some(err) = config::load_error() {
+        confirmations.insert(0, format!("Правила protect не загружены: {err}"));
+    }
+    confirmations.is_empty()
+        || confirm(
+            hwnd,
+            &format!(
+                "{}\r\n\r\nВсё равно привязать {}?",
+                confirmations.join("\r\n"),
+                device.bus_id
+            ),
+        )
+}
+
+// Switches a bound device between a normal and a forced binding.
+fn handle_rebind(hwnd: HWND, hwnd_list: HWND, state: &AppState) {
+    let Some(bus_id) = get_selected_connected(hwnd, hwnd_list, state) else {
+        return;
+    };
+    let Some(device) = state.find_device(&bus_id) else {
+        show_error(hwnd, "Устройство не найдено, обновите список");
+        return;
+    };
+    let force = match device.state.as_str() {
+        "Shared" => true,
+        FORCED_STATE => false,
+        "Attached" => {
+            show_error(
+                hwnd,
+                "Устройство подключено к WSL, сначала выполните Detach",
+            );
+            return;
+        }
+        _ => {
+            show_error(hwnd, "Устройство не привязано");
+            return;
+        }
+    };
+    let mut settings = state.config.settings_for(&device);
+    settings.force_bind = force;
+    if force {
+        if !confirm_bind(hwnd, state, &device, &settings) {
+            return;
+        }
+        if !confirm(
+            hwnd,
+            &format!(
+                "Перепривязать {bus_id} с --force? Драйвер Windows будет заменён, \
+                 устройство перестанет работать в Windows."
+            ),
+        ) {
+            return;
+        }
+    }
+    state.submit(
+        Operation::new(format!("Rebind {bus_id}"), move |ctx| {
+            operations::rebind(ctx, &device, &settings).map(|()| OperationOutput::Done)
+        })
+        .for_device(&bus_id),
+    );
+}
+
+fn handle_unbind(hwnd: HWND, hwnd_list: HWND, state: &mut AppState) {
+    let Some(bus_id) = get_selected_connected(hwnd, hwnd_list, state) else {
+        return;
+    };
+
+    let state_str = get_list_item_state(hwnd_list).unwrap_or_else(|| "Unknown".to_string());
+    if !is_unbindable_state(&state_str) {
+        show_error(
+            hwnd,
+            "Устройство не привязано или не в подходящем состоянии",
+        );
+        return;
+    }
+
+    let Some(device) = state.find_device(&bus_id) else {
+        show_error(hwnd, "Устройство не найдено, обновите список");
+        return;
+    };
+
+    state.stop_auto_attach(&device);
+    state.watcher.forget(&bus_id);
+    state.submit(
+        Operation::new(format!("Unbind {bus_id}"), move |ctx| {
+            operations::unbind(ctx, &device).map(|()| OperationOutput::Done)
+        })
+        .for_device(&bus_id),
+    );
+}
+
+fn handle_attach(hwnd: HWND, hwnd_list: HWND, state: &AppState) {
+    let Some(bus_id) = get_selected_connected(hwnd, hwnd_list, state) else {
+        return;
+    };
+
+    let Some(device) = state.devices.iter().find(|device| device.bus_id == bus_id) else {
+        show_error(hwnd, "Устройство не найдено, обновите список");
+        return;
+    };
+
+    let device = device.clone();
+    let settings = state.config.settings_for(&device);
+    logging::debug(format!(
+        "Attach: bus_id = {bus_id}, wsl = {}",
+        settings.wsl_distro
+    ));
+    state.submit(
+        Operation::new(format!("Attach {bus_id}"), move |ctx| {
+            operations::attach_and_verify(ctx, &device, &settings)
+                .map(|found| attached_message(found, &device.vid_pid, &settings.wsl_distro))
+        })
+        .for_device(&bus_id),
+    );
+}
+
+fn attached_message(found: SysfsUsbDevice, vid_pid: &str, wsl_distro: &str) -> OperationOutput {
+    OperationOutput::Message(describe_attach_check(
+        &AttachCheck::Present(found),
+        vid_pid,
+        wsl_distro,
+    ))
+}
+
+fn handle_detach(hwnd: HWND, hwnd_list: HWND, state: &mut AppState) {
+    let Some(bus_id) = get_selected_connected(hwnd, hwnd_list, state) else {
+        return;
+    };
+
+    let Some(device) = state.find_device(&bus_id) else {
+        show_error(hwnd, "Устройство не найдено, обновите список");
+        return;
+    };
+
+    submit_detach(state, device, false);
+}
+
+fn submit_detach(state: &mut AppState, device: UsbDevice, force: bool) {
+    let bus_id = device.bus_id.clone();
+    state.watcher.forget(&bus_id);
+    let settings = state.config.settings_for(&device);
+    state.submit(
+        Operation::new(format!("Detach {bus_id}"), move |ctx| {
+            let outcome = operations::safe_detach(ctx, &device, &settings, force)?;
+            Ok(
+                match outcome.refusal(&device.bus_id, &settings.wsl_distro) {
+                    Some(refusal) => OperationOutput::DetachBusy(device, refusal),
+                    None => OperationOutput::Done,
+                },
+            )
+        })
+        .for_device(&bus_id),
+    );
+}
+
+fn handle_connect_to_wsl(hwnd: HWND, hwnd_list: HWND, state: &AppState) {
+    let Some(bus_id) = get_selected_connected(hwnd, hwnd_list, state) else {
+        return;
+    };
+
+    let Some(device) = state.devices.iter().find(|device| device.bus_id == bus_id) else {
+        show_error(hwnd, "Устройство не найдено, обновите список");
+        return;
+    };
+
+    let device = device.clone();
+    let mut settings = state.config.settings_for(&device);
+    settings.force_bind = is_force_checked(hwnd);
+    if is_bindable_state(&device.state) && !confirm_bind(hwnd, state, &device, &settings) {
+        return;
+    }
+    state.submit(
+        Operation::new(
+            format!("Подключение {bus_id} к {}", settings.wsl_distro),
+            move |ctx| {
+                operations::connect_to_wsl(ctx, &device, &settings)
+                    .map(|found| attached_message(found, &device.vid_pid, &settings.wsl_distro))
+            },
+        )
+        .for_device(&bus_id),
+    );
+}
+
+fn handle_move_to_distro(hwnd: HWND, hwnd_list: HWND, state: &mut AppState) {
+    let Some(bus_id) = get_selected_device(hwnd_list) else {
+        show_error(hwnd, "Устройство не выбрано");
+        return;
+    };
+    let Some(target) = get_selected_distro(hwnd) else {
+        show_error(hwnd, "Дистрибутив не выбран");
+        return;
+    };
+    // A device that is not plugged in only gets the setting.
+    if let Some(entry) = state.find_offline(&bus_id) {
+        let device = entry.as_device();
+        state.suspend_auto_attach(&device.bus_id);
+        state.config.set_device_distro(&device, &target);
+        state.save_config();
+        set_status(hwnd, &format!("{bus_id} будет подключаться к {target}"));
+        state.refresh_devices();
+        return;
+    }
+    let Some(device) = state.find_device(&bus_id) else {
+        show_error(hwnd, "Устройство не найдено, обновите список");
+        return;
+    };
+
+    // The move binds a device that is not shared yet.
+    let settings = state.config.settings_for(&device);
+    if is_bindable_state(&device.state) && !confirm_bind(hwnd, state, &device, &settings) {
+        return;
+    }
+    submit_move(state, device, target, false);
+}
+
+fn submit_move(state: &mut AppState, device: UsbDevice, target: String, force: bool) {
+    let bus_id = device.bus_id.clone();
+    // A running supervisor would re-attach the device to the old distro as
+    // soon as it is detached; it is resumed once the move has finished.
+    let settings = state.config.settings_for(&device);
+    state.suspend_auto_attach(&bus_id);
+    state.watcher.forget(&bus_id);
+    state.submit(
+        Operation::new(
+            format!("Перемещение {bus_id} в {target}"),
+            move |ctx| {
+                let outcome = operations::move_to_distro(ctx, &device, &settings, &target, force)?;
+                Ok(match outcome {
+                    MoveOutcome::Moved(found) => {
+                        let message = describe_attach_check(
+                            &AttachCheck::Present(found),
+                            &device.vid_pid,
+                            &target,
+                        );
+                        OperationOutput::Moved(device, target, message)
+                    }
+                    MoveOutcome::NotDetached(outcome) => {
+                        let refusal = outcome
+                            .refusal(&device.bus_id, &settings.wsl_distro)
+                            .unwrap_or_default();
+                        OperationOutput::MoveBusy(device, target, refusal)
+                    }
+                })
+            },
+        )
+        .for_device(&bus_id),
+    );
+}
+
+fn handle_auto_attach(hwnd: HWND, hwnd_list: HWND, state: &mut AppState) {
+    let Some(bus_id) = get_selected_device(hwnd_list) else {
+        show_error(hwnd, "Устройство не выбрано");
+        return;
+    };
+
+    // usbipd starts waiting for a bound device that is not plugged in and
+    // attaches it once it appears.
+    if let Some(entry) = state.find_offline(&bus_id) {
+        if entry.last_state == "Not shared" {
+            show_error(
+                hwnd,
+                "Устройство не было привязано: подключите его и выполните Bind",
+            );
+            return;
+        }
+        state.start_auto_attach(&entry.as_device(), hwnd);
+        state.refresh_devices();
+        return;
+    }
+
+    let state_str = get_list_item_state(hwnd_list).unwrap_or_else(|| "Unknown".to_string());
+    if !is_auto_attachable_state(&state_str) {
+        show_error(
+            hwnd,
+            "Устройство должно быть в состоянии Shared для Auto-Attach",
+        );
+        return;
+    }
+
+    let Some(device) = state.find_device(&bus_id) else {
+        show_error(hwnd, "Устройство не найдено, обновите список");
+        return;
+    };
+
+    state.start_auto_attach(&device, hwnd);
+    state.refresh_devices();
+}
+
+fn handle_stop_auto_attach(hwnd: HWND, hwnd_list: HWND, state: &mut AppState) {
+    let Some(bus_id) = get_selected_device(hwnd_list) else {
+        show_error(hwnd, "Устройство не выбрано");
+        return;
+    };
+
+    let offline = state.find_offline(&bus_id).map(|entry| entry.as_device());
+    if let Some(device) = state.find_device(&bus_id).or(offline) {
+        state.stop_auto_attach(&device);
+    }
+    state.refresh_devices();
+}
+
+fn handle_doctor(state: &AppState) {
+    let config = state.config.clone();
+    state.submit(Operation::new("Диагностика", move |_| {
+        Ok(OperationOutput::Doctor(doctor::run_doctor(&config)))
+    }));
+}
+
+fn handle_export_diagnostics(state: &AppState) {
+    let config = state.config.clone();
+    let operations = state.operation_log.entries();
+    state.submit(Operation::new(
+        "Экспорт диагностики",
+        move |_| {
+            let path = diagnostics::default_bundle_path();
+            let bundle = diagnostics::collect_bundle(&config, operations);
+            diagnostics::export_bundle(&path, &bundle)?;
+            Ok(OperationOutput::Message(format!("сохранено в {path}")))
+        },
+    ));
+}
+
+// History of the selected device, or of all devices when none is selected.
+fn handle_history(hwnd: HWND, hwnd_list: HWND) {
+    // Devices that are not connected are listed by VID:PID[/serial].
+    let device = get_selected_device(hwnd_list)
+        .map(|key| key.split('/').next().unwrap_or_default().to_string());
+    let filter = HistoryFilter {
+        device: device.clone(),
+        limit: Some(HISTORY_DIALOG_ENTRIES),
+        ..HistoryFilter::default()
+    };
+    let entries = match history::query(&filter) {
+        Ok(entries) => entries,
+        Err(err) => {
+            show_error(hwnd, &err);
+            return;
+        }
+    };
+    let title = match &device {
+        Some(bus_id) => format!("История {bus_id}"),
+        None => "История".to_string(),
+    };
+    let text = if entries.is_empty() {
+        "Записей нет".to_string()
+    } else {
+        let lines: Vec<String> = entries.iter().rev().map(|entry| entry.display()).collect();
+        lines.join("\r\n")
+    };
+    let title_w: Vec<u16> = OsStr::new(&title).encode_wide().chain(once(0)).collect();
+    let text_w: Vec<u16> = OsStr::new(&text).encode_wide().chain(once(0)).collect();
+    unsafe {
+        MessageBoxW(
+            hwnd,
+            text_w.as_ptr(),
+            title_w.as_ptr(),
+            MB_OK | MB_ICONINFORMATION,
+        );
+    }
+}
+
+fn handle_cancel(hwnd: HWND, hwnd_list: HWND, state: &AppState) {
+    let cancelled = match get_selected_device(hwnd_list) {
+        Some(bus_id) if state.executor.is_device_busy(&bus_id) => {
+            state.executor.cancel_device(&bus_id)
+        }
+        _ => state.executor.cancel_all(),
+    };
+    if cancelled == 0 {
+        set_status(hwnd, "Нет выполняемых операций");
+    }
+}
+
+// Reads only the control that sent the notification; the others still hold
+// the saved view.
+fn handle_view_changed(hwnd: HWND, hwnd_list: HWND, state: &mut AppState, control_id: u16) {
+    let mut view = state.config.view.clone();
+    unsafe {
+        let hwnd_control = GetDlgItem(hwnd, control_id as i32);
+        match control_id {
+            301 => {
+                let mut buffer = [0u16; 256];
+                let len = GetWindowTextW(hwnd_control, buffer.as_mut_ptr(), buffer.len() as i32);
+                view.search = String::from_utf16_lossy(&buffer[..len.max(0) as usize]);
+            }
+            302 => {
+                let index = SendMessageW(hwnd_control, CB_GETCURSEL, 0, 0);
+                view.state = StateFilter::ALL
+                    .get(index.max(0) as usize)
+                    .copied()
+                    .unwrap_or_default();
+            }
+            303 => {
+                let index = SendMessageW(hwnd_control, CB_GETCURSEL, 0, 0);
+                // The first entry shows every tag.
+                view.tag = if index > 0 {
+                    combo_text(hwnd_control, index)
+                } else {
+                    None
+                };
+            }
+            _ => {
+                let index = SendMessageW(hwnd_control, CB_GETCURSEL, 0, 0);
+                view.sort = SortOrder::ALL
+                    .get(index.max(0) as usize)
+                    .copied()
+                    .unwrap_or_default();
+            }
+        }
+    }
+    if view == state.config.view {
+        return;
+    }
+    state.config.view = view;
+    // Setting the timer again restarts it.
+    unsafe {
+        SetTimer(hwnd, VIEW_SAVE_TIMER_ID, VIEW_SAVE_DELAY_MS, None);
+    }
+    let shown = fill_usb_list(hwnd_list, &state.devices, &state.offline, &state.config);
+    let total = state.devices.len() + state.offline.len();
+    if shown < total {
+        set_status(hwnd, &format!("Показано {shown} из {total}"));
+    } else {
+        set_status(hwnd, "");
+    }
+}
+
+fn fill_view_controls(hwnd: HWND, config: &Config) {
+    let view = &config.view;
+    let mut tags = known_tags(config);
+    if let Some(tag) = &view.tag {
+        if !tags
+            .iter()
+            .any(|known| known.to_lowercase() == tag.to_lowercase())
+        {
+            tags.push(tag.clone());
+        }
+    }
+    let tag_index = view
+        .tag
+        .as_ref()
+        .and_then(|tag| {
+            tags.iter()
+                .position(|known| known.to_lowercase() == tag.to_lowercase())
+        })
+        .map_or(0, |index| index + 1);
+    let state_index = StateFilter::ALL
+        .iter()
+        .position(|state| *state == view.state)
+        .unwrap_or_default();
+    let sort_index = SortOrder::ALL
+        .iter()
+        .position(|sort| *sort == view.sort)
+        .unwrap_or_default();
+    let tag_labels: Vec<String> = once("Все теги".to_string())
+        .chain(tags.iter().map(|tag| format!("#{tag}")))
+        .collect();
+    let state_labels: Vec<String> = StateFilter::ALL
+        .iter()
+        .map(|state| state.label().to_string())
+        .collect();
+    let sort_labels: Vec<String> = SortOrder::ALL
+        .iter()
+        .map(|sort| sort.label().to_string())
+        .collect();
+    for (id, labels, selected) in [
+        (302, state_labels, state_index),
+        (303, tag_labels, tag_index),
+        (304, sort_labels, sort_index),
+    ] {
+        unsafe {
+            let hwnd_combo = GetDlgItem(hwnd, id);
+            SendMessageW(hwnd_combo, CB_RESETCONTENT, 0, 0);
+            for label in labels {
+                let label_w: Vec<u16> = OsStr::new(&label).encode_wide().chain(once(0)).collect();
+                SendMessageW(hwnd_combo, CB_ADDSTRING, 0, label_w.as_ptr() as LPARAM);
+            }
+            SendMessageW(hwnd_combo, CB_SETCURSEL, selected as WPARAM, 0);
+        }
+    }
+}
+
+// Keeps the selected device selected, since the list is also refilled in the
+// background. Only the devices that pass the view are listed; returns how
+// many.
+fn fill_usb_list(
+    hwnd_list: HWND,
+    devices: &[UsbDevice],
+    offline: &[InventoryEntry],
+    config: &Config,
+) -> usize {
+    let selected = get_selected_device(hwnd_list);
+    let shown = config.view.apply(devices, config);
+    let offline = config.view.apply_offline(offline, config);
+    unsafe {
+        SendMessageW(hwnd_list, LB_RESETCONTENT, 0, 0);
+
+        for (index, (device, settings)) in shown.iter().enumerate() {
+            let display = format_device_display(
+                device,
+                settings.nickname.as_deref(),
+                &settings.tags,
+                settings.auto_attach,
+            );
+            let display_w: Vec<u16> = OsStr::new(&display).encode_wide().chain(once(0)).collect();
+            let result = SendMessageW(hwnd_list, LB_ADDSTRING, 0, display_w.as_ptr() as LPARAM);
+            if result == -1 {
+                logging::error(format!("Ошибка добавления строки: {display}"));
+            } else if device.state == FORCED_STATE {
+                SendMessageW(hwnd_list, LB_SETITEMDATA, result as WPARAM, FORCED_ITEM);
+            }
+            if selected.as_deref() == Some(device.bus_id.as_str()) {
+                SendMessageW(hwnd_list, LB_SETCURSEL, index as WPARAM, 0);
+            }
+        }
+
+        for entry in &offline {
+            let device = entry.as_device();
+            let settings = config.settings_for(&device);
+            let device = UsbDevice {
+                bus_id: entry.key(),
+                ..device
+            };
+            let display = format_device_display(
+                &device,
+                settings.nickname.as_deref(),
+                &settings.tags,
+                settings.auto_attach,
+            );
+            let display_w: Vec<u16> = OsStr::new(&display).encode_wide().chain(once(0)).collect();
+            let index = SendMessageW(hwnd_list, LB_ADDSTRING, 0, display_w.as_ptr() as LPARAM);
+            if index < 0 {
+                continue;
+            }
+            SendMessageW(hwnd_list, LB_SETITEMDATA, index as WPARAM, OFFLINE_ITEM);
+            if selected.as_deref() == Some(device.bus_id.as_str()) {
+                SendMessageW(hwnd_list, LB_SETCURSEL, index as WPARAM, 0);
+            }
+        }
+
+        let _ = SendMessageW(hwnd_list, LB_GETCOUNT, 0, 0);
+        let _ = InvalidateRect(hwnd_list, ptr::null(), 1);
+        UpdateWindow(hwnd_list);
+    }
+    shown.len() + offline.len()
+}
+
+// Appends new log records to the log pane and keeps the latest one visible.
+fn show_log_records(hwnd: HWND, state: &AppState) {
+    let records: Vec<LogRecord> = state.log_records.try_iter().collect();
+    if records.is_empty() {
+        return;
+    }
+    unsafe {
+        let hwnd_log = GetDlgItem(hwnd, 202);
+        for record in records {
+            let line_w: Vec<u16> = OsStr::new(&record.display())
+                .encode_wide()
+                .chain(once(0))
+                .collect();
+            SendMessageW(hwnd_log, LB_ADDSTRING, 0, line_w.as_ptr() as LPARAM);
+        }
+        let mut count = SendMessageW(hwnd_log, LB_GETCOUNT, 0, 0);
+        while count > LOG_PANE_LINES {
+            SendMessageW(hwnd_log, LB_DELETESTRING, 0, 0);
+            count -= 1;
+        }
+        SendMessageW(hwnd_log, LB_SETTOPINDEX, (count - 1).max(0) as WPARAM, 0);
+    }
+}
+
+// VID:PID, the usb.ids names and notes are too long for the list, so the
+// selected device's go to the status line.
+fn show_selected_details(hwnd: HWND, hwnd_list: HWND, state: &AppState) {
+    let Some(bus_id) = get_selected_device(hwnd_list) else {
+        return;
+    };
+    let Some(device) = state
+        .find_device(&bus_id)
+        .or_else(|| state.find_offline(&bus_id).map(|entry| entry.as_device()))
+    else {
+        return;
+    };
+    let info = usb_ids::describe(&device);
+    let mut details = vec![device.vid_pid.clone()];
+    details.extend(info.name());
+    details.extend(info.class.map(|class| format!("класс {class}")));
+    if device.state == FORCED_STATE {
+        details.push("привязано с --force, драйвер Windows заменён".to_string());
+    }
+    details.extend(state.config.settings_for(&device).notes);
+    set_status(hwnd, &details.join(" — "));
+}
+
+// Selection for actions that need the device plugged in.
+fn get_selected_connected(hwnd: HWND, hwnd_list: HWND, state: &AppState) -> Option<String> {
+    let Some(bus_id) = get_selected_device(hwnd_list) else {
+        show_error(hwnd, "Устройство не выбрано");
+        return None;
+    };
+    if state.find_offline(&bus_id).is_some() {
+        show_error(hwnd, "Устройство не подключено");
+        return None;
+    }
+    Some(bus_id)
+}
+
+// The device list is owner-drawn only to grey out devices that are not
+// connected and to set forced bindings apart; everything else looks like a
+// plain list box.
+unsafe fn draw_list_item(item: &DRAWITEMSTRUCT) {
+    if item.itemID == u32::MAX {
+        return;
+    }
+    let selected = item.itemState & ODS_SELECTED != 0;
+    let background = if selected {
+        COLOR_HIGHLIGHT
+    } else {
+        COLOR_WINDOW
+    };
+    FillRect(item.hDC, &item.rcItem, GetSysColorBrush(background));
+    let color = if item.itemData == OFFLINE_ITEM as usize {
+        COLOR_GRAYTEXT
+    } else if selected {
+        COLOR_HIGHLIGHTTEXT
+    } else {
+        COLOR_WINDOWTEXT
+    };
+    let text_color = if item.itemData == FORCED_ITEM as usize && !selected {
+        FORCED_TEXT_COLOR
+    } else {
+        GetSysColor(color)
+    };
+    SetTextColor(item.hDC, text_color);
+    SetBkMode(item.hDC, TRANSPARENT as i32);
+
+    let mut buffer = [0u16; 512];
+    let len = SendMessageW(
+        item.hwndItem,
+        LB_GETTEXT,
+        item.itemID as WPARAM,
+        buffer.as_mut_ptr() as LPARAM,
+    );
+    let mut rect = item.rcItem;
+    rect.left += 2;
+    DrawTextW(
+        item.hDC,
+        buffer.as_ptr(),
+        len.max(0) as i32,
+        &mut rect,
+        DT_SINGLELINE | DT_VCENTER | DT_NOPREFIX,
+    );
+    if item.itemState & ODS_FOCUS != 0 {
+        DrawFocusRect(item.hDC, &item.rcItem);
+    }
+}
+
+fn set_force_checkbox(hwnd: HWND, checked: bool) {
+    let check = if checked { BST_CHECKED } else { BST_UNCHECKED };
+    unsafe {
+        SendMessageW(GetDlgItem(hwnd, 114), BM_SETCHECK, check as WPARAM, 0);
+    }
+}
+
+fn is_force_checked(hwnd: HWND) -> bool {
+    unsafe { SendMessageW(GetDlgItem(hwnd, 114), BM_GETCHECK, 0, 0) == BST_CHECKED as LRESULT }
+}
+
+// A bound device shows the mode it is bound in, any other the mode its Bind
+// would use.
+fn sync_force_checkbox(hwnd: HWND, hwnd_list: HWND, state: &AppState) {
+    let Some(device) = get_selected_device(hwnd_list).and_then(|bus_id| state.find_device(&bus_id))
+    else {
+        return;
+    };
+    let checked = match device.state.as_str() {
+        FORCED_STATE => true,
+        "Shared" => false,
+        _ => state.config.settings_for(&device).force_bind,
+    };
+    set_force_checkbox(hwnd, checked);
+}
+
+fn set_status(hwnd: HWND, text: &str) {
+    let text_w: Vec<u16> = OsStr::new(text).encode_wide().chain(once(0)).collect();
+    unsafe {
+        SetWindowTextW(GetDlgItem(hwnd, 201), text_w.as_ptr());
+    }
+}
+
+// The list comes with every device refresh. It is only refilled when the
+// installed distros change, so a background refresh does not reset the choice.
+fn update_distro_list(hwnd: HWND, distros: &[WslDistro], configured: &str) {
+    let hwnd_distros = unsafe { GetDlgItem(hwnd, 300) };
+    let selected = get_selected_distro(hwnd).unwrap_or_else(|| configured.to_string());
+    let mut names: Vec<String> = distros.iter().map(|distro| distro.name.clone()).collect();
+    if !names.contains(&selected) {
+        names.insert(0, selected.clone());
+    }
+    let count = unsafe { SendMessageW(hwnd_distros, CB_GETCOUNT, 0, 0) };
+    let shown: Vec<String> = (0..count.max(0))
+        .filter_map(|index| combo_text(hwnd_distros, index))
+        .collect();
+    if shown != names {
+        fill_distro_list(hwnd_distros, &names, &selected);
+    }
+}
+
+fn fill_distro_list(hwnd_distros: HWND, distros: &[String], selected: &str) {
+    unsafe {
+        SendMessageW(hwnd_distros, CB_RESETCONTENT, 0, 0);
+        let mut distros = distros.to_vec();
+        if !distros.iter().any(|distro| distro == selected) {
+            distros.insert(0, selected.to_string());
+        }
+        for (index, distro) in distros.iter().enumerate() {
+            let distro_w: Vec<u16> = OsStr::new(distro).encode_wide().chain(once(0)).collect();
+            SendMessageW(hwnd_distros, CB_ADDSTRING, 0, distro_w.as_ptr() as LPARAM);
+            if distro == selected {
+                SendMessageW(hwnd_distros, CB_SETCURSEL, index as WPARAM, 0);
+            }
+        }
+    }
+}
+
+fn combo_text(hwnd_combo: HWND, index: LRESULT) -> Option<String> {
+    let mut buffer = [0u16; 256];
+    let len = unsafe {
+        SendMessageW(
+            hwnd_combo,
+            CB_GETLBTEXT,
+            index as WPARAM,
+            buffer.as_mut_ptr() as LPARAM,
+        )
+    };
+    if len <= 0 {
+        return None;
+    }
+    let text = String::from_utf16_lossy(&buffer[..len as usize]);
+    Some(text.trim_start_matches('#').to_string())
+}
+
+fn get_selected_distro(hwnd: HWND) -> Option<String> {
+    unsafe {
+        let hwnd_distros = GetDlgItem(hwnd, 300);
+        let index = SendMessageW(hwnd_distros, CB_GETCURSEL, 0, 0);
+        if index == CB_ERR as LRESULT {
+            return None;
+        }
+
+        let mut buffer = [0u16; 256];
+        let len = SendMessageW(
+            hwnd_distros,
+            CB_GETLBTEXT,
+            index as WPARAM,
+            buffer.as_mut_ptr() as LPARAM,
+        );
+        if len > 0 {
+            return Some(String::from_utf16_lossy(&buffer[..len as usize]));
+        }
+        None
+    }
+}
+
+fn get_selected_device(hwnd_list: HWND) -> Option<String> {
+    unsafe {
+        if hwnd_list.is_null() {
+            return None;
+        }
+        let index = SendMessageW(hwnd_list, LB_GETCURSEL, 0, 0);
+        if index == -1 {
+            return None;
+        }
+
+        let mut buffer = [0u16; 512];
+        let len = SendMessageW(
+            hwnd_list,
+            LB_GETTEXT,
+            index as WPARAM,
+            buffer.as_mut_ptr() as LPARAM,
+        );
+        if len > 0 {
+            let text = String::from_utf16_lossy(&buffer[..len as usize]);
+            return extract_bus_id(&text);
+        }
+        None
+    }
+}
+
+fn get_list_item_state(hwnd_list: HWND) -> Option<String> {
+    unsafe {
+        let index = SendMessageW(hwnd_list, LB_GETCURSEL, 0, 0);
+        if index == -1 {
+            return None;
+        }
+
+        let mut buffer = [0u16; 512];
+        let len = SendMessageW(
+            hwnd_list,
+            LB_GETTEXT,
+            index as WPARAM,
+            buffer.as_mut_ptr() as LPARAM,
+        );
+        if len > 0 {
+            let text = String::from_utf16_lossy(&buffer[..len as usize]);
+            return extract_state_from_display(&text);
+        }
+        None
+    }
+}
+
+fn confirm(hwnd: HWND, message: &str) -> bool {
+    let title: Vec<u16> = OsStr::new("Подтверждение")
+        .encode_wide()
+        .chain(once(0))
+        .collect();
+    let message_w: Vec<u16> = OsStr::new(message).encode_wide().chain(once(0)).collect();
+    unsafe {
+        MessageBoxW(
+            hwnd,
+            message_w.as_ptr(),
+            title.as_ptr(),
+            MB_YESNO | MB_ICONWARNING,
+        ) == IDYES
+    }
+}
+
+fn show_warning(hwnd: HWND, message: &str) {
+    let title: Vec<u16> = OsStr::new("Предупреждение")
+        .encode_wide()
+        .chain(once(0))
+        .collect();
+    let message_w: Vec<u16> = OsStr::new(message).encode_wide().chain(once(0)).collect();
+    unsafe {
+        MessageBoxW(
+            hwnd,
+            message_w.as_ptr(),
+            title.as_ptr(),
+            MB_OK | MB_ICONWARNING,
+        );
+    }
+}
+
+fn show_report(hwnd: HWND, results: &[CheckResult]) {
+    let icon = if has_failures(results) {
+        MB_ICONERROR
+    } else if results
+        .iter()
+        .any(|result| result.status == CheckStatus::Warn)
+    {
+        MB_ICONWARNING
+    } else {
+        MB_ICONINFORMATION
+    };
+    let title: Vec<u16> = OsStr::new("Диагностика")
+        .encode_wide()
+        .chain(once(0))
+        .collect();
+    let message_w: Vec<u16> = OsStr::new(&format_report(results))
+        .encode_wide()
+        .chain(once(0))
+        .collect();
+    unsafe {
+        MessageBoxW(hwnd, message_w.as_ptr(), title.as_ptr(), MB_OK | icon);
+    }
+}
+
+fn show_error(hwnd: HWND, message: &str) {
+    let title: Vec<u16> = OsStr::new("Ошибка").encode_wide().chain(once(0)).collect();
+    let message_w: Vec<u16> = OsStr::new(message).encode_wide().chain(once(0)).collect();
+    unsafe {
+        MessageBoxW(
+            hwnd,
+            message_w.as_ptr(),
+            title.as_ptr(),
+            MB_OK | MB_ICONERROR,
+        );
+    }
+}
@@ -0,0 +1,523 @@
+// Append-only audit trail of the actions that change who has a device:
+// bind, unbind, attach, detach and Auto-Attach start/stop, from both the CLI
+// and the GUI. One JSON object per line in history.jsonl in the state
+// directory; old entries are dropped according to `HistoryRetention`.
+use crate::config::state_dir;
+use crate::logging::{self, format_time};
+use crate::usbipd::UsbDevice;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Bind,
+    Unbind,
+    Attach,
+    Detach,
+    AutoAttachStart,
+    AutoAttachStop,
+}
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Bind => "bind",
+            Action::Unbind => "unbind",
+            Action::Attach => "attach",
+            Action::Detach => "detach",
+            Action::AutoAttachStart => "auto_attach_start",
+            Action::AutoAttachStop => "auto_attach_stop",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub time: String,
+    pub user: String,
+    pub action: Action,
+    pub bus_id: String,
+    pub vid_pid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    pub device_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distro: Option<String>,
+    pub succeeded: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl HistoryEntry {
+    pub fn display(&self) -> String {
+        let time = self.time.get(..19).unwrap_or(&self.time).replace('T', " ");
+        let mut line = format!(
+            "{time} {} {} {} {} ({})",
+            self.user,
+            self.action.name(),
+            self.bus_id,
+            self.vid_pid,
+            self.device_name
+        );
+        if let Some(distro) = &self.distro {
+            line.push_str(&format!(" → {distro}"));
+        }
+        match &self.error {
+            _ if self.succeeded => line.push_str(": выполнено"),
+            Some(error) => line.push_str(&format!(": ошибка: {error}")),
+            None => line.push_str(": ошибка"),
+        }
+        line
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryRetention {
+    #[serde(default = "default_max_age_days")]
+    pub max_age_days: u64,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+impl Default for HistoryRetention {
+    fn default() -> Self {
+        Self {
+            max_age_days: default_max_age_days(),
+            max_entries: default_max_entries(),
+        }
+    }
+}
+
+impl HistoryRetention {
+    pub fn is_default(&self) -> bool {
+        *self == HistoryRetention::default()
+    }
+}
+
+fn default_max_age_days() -> u64 {
+    90
+}
+
+fn default_max_entries() -> usize {
+    10_000
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryFilter {
+    // Bus id or VID:PID.
+    pub device: Option<String>,
+    // Time in the `format_time` layout (see `parse_since`); entries before it
+    // are skipped.
+    pub since: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl HistoryFilter {
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        let device = self.device.as_deref().is_none_or(|device| {
+            entry.bus_id == device || entry.vid_pid.eq_ignore_ascii_case(device)
+        });
+        let since = self
+            .since
+            .as_deref()
+            .is_none_or(|since| entry.time.as_str() >= since);
+        device && since
+    }
+}
+
+struct History {
+    path: Option<PathBuf>,
+    retention: Option<HistoryRetention>,
+}
+
+static HISTORY: Mutex<History> = Mutex::new(History {
+    path: None,
+    retention: None,
+});
+
+pub fn default_history_path() -> Option<PathBuf> {
+    state_dir().map(|dir| dir.join(HISTORY_FILE))
+}
+
+// Without a call nothing is recorded.
+pub fn start(path: Option<PathBuf>) {
+    let mut history = HISTORY.lock().unwrap();
+    history.path = path;
+    history.retention = None;
+}
+
+// Called with every loaded config; the file is pruned once per process and
+// again whenever the limits change.
+pub fn set_retention(retention: &HistoryRetention) {
+    let mut history = HISTORY.lock().unwrap();
+    if history.retention.as_ref() == Some(retention) {
+        return;
+    }
+    history.retention = Some(retention.clone());
+    let Some(path) = history.path.clone() else {
+        return;
+    };
+    let Ok(entries) = read_entries(&path) else {
+        return;
+    };
+    let kept = apply_retention(entries.clone(), retention, SystemTime::now());
+    if kept.len() != entries.len() {
+        if let Err(err) = write_entries(&path, &kept) {
+            logging::warn(err);
+        }
+    }
+}
+
+pub fn record(
+    action: Action,
+    device: &UsbDevice,
+    distro: Option<&str>,
+    result: &Result<(), String>,
+) {
+    let entry = HistoryEntry {
+        time: format_time(SystemTime::now()),
+        user: current_user(),
+        action,
+        bus_id: device.bus_id.clone(),
+        vid_pid: device.vid_pid.clone(),
+        serial: device.serial.clone(),
+        device_name: device.device_name.clone(),
+        distro: distro.map(str::to_string),
+        succeeded: result.is_ok(),
+        error: result.as_ref().err().cloned(),
+    };
+    let Some(path) = HISTORY.lock().unwrap().path.clone() else {
+        return;
+    };
+    if let Err(err) = append_entry(&path, &entry) {
+        logging::warn(err);
+    }
+}
+
+// Oldest first.
+pub fn query(filter: &HistoryFilter) -> Result<Vec<HistoryEntry>, String> {
+    let Some(path) = HISTORY.lock().unwrap().path.clone() else {
+        return Ok(Vec::new());
+    };
+    let mut entries: Vec<HistoryEntry> = read_entries(&path)?
+        .into_iter()
+        .filter(|entry| filter.matches(entry))
+        .collect();
+    if let Some(limit) = filter.limit {
+        entries.drain(..entries.len().saturating_sub(limit));
+    }
+    Ok(entries)
+}
+
+// The distro the device was last attached to, from the CLI or the GUI.
+#[cfg(windows)]
+pub fn last_attach_target(device: &UsbDevice) -> Option<String> {
+    let filter = HistoryFilter {
+        device: Some(device.bus_id.clone()),
+        ..HistoryFilter::default()
+    };
+    attach_target(&query(&filter).ok()?, device)
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+fn attach_target(entries: &[HistoryEntry], device: &UsbDevice) -> Option<String> {
+    entries
+        .iter()
+        .rev()
+        .find(|entry| {
+            entry.succeeded
+                && entry.bus_id == device.bus_id
+                && entry.vid_pid.eq_ignore_ascii_case(&device.vid_pid)
+                && matches!(entry.action, Action::Attach | Action::AutoAttachStart)
+        })
+        .and_then(|entry| entry.distro.clone())
+}
+
+// "2024-05-01", an RFC 3339 UTC time (seconds may be left out), or a period
+// back from now such as "30m", "12h" or "7d". Returned in the layout of the
+// stored timestamps so the two compare as strings.
+pub fn parse_since(value: &str, now: SystemTime) -> Result<String, String> {
+    let invalid = || {
+        format!("Неверное значение --since: {value} (ожидается ГГГГ-ММ-ДД, время RFC 3339 или 30m/12h/7d)")
+    };
+    let value = value.trim();
+    if let Some(unit) = value.chars().last().filter(|unit| "smhd".contains(*unit)) {
+        if let Ok(count) = value[..value.len() - 1].parse::<u64>() {
+            let secs = match unit {
+                's' => 1,
+                'm' => 60,
+                'h' => 3600,
+                _ => 86_400,
+            };
+            let since = now
+                .checked_sub(Duration::from_secs(count * secs))
+                .ok_or_else(invalid)?;
+            return Ok(format_time(since));
+        }
+    }
+    let since = parse_utc_time(value).ok_or_else(invalid)?;
+    Ok(format_time(since))
+}
+
+// "YYYY-MM-DD" or "YYYY-MM-DDTHH:MM[:SS[.fff]]Z", with every field checked.
+fn parse_utc_time(value: &str) -> Option<SystemTime> {
+    let digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    let number = |part: &str| digits(part).then(|| part.parse::<u32>().ok()).flatten();
+    let (date, time) = match value.split_once('T') {
+        Some((date, time)) => (date, Some(time.strip_suffix('Z')?)),
+        None => (value, None),
+    };
+    let mut fields = date.split('-');
+    let (year, month, day) = (fields.next()?, fields.next()?, fields.next()?);
+    if fields.next().is_some() || year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    let (year, month, day) = (number(year)?, number(month)?, number(day)?);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if !(1..=month_days).contains(&day) {
+        return None;
+    }
+
+    let (mut hour, mut minute, mut second, mut millis) = (0, 0, 0, 0u32);
+    if let Some(time) = time {
+        let (time, fraction) = match time.split_once('.') {
+            Some((time, fraction)) => (time, Some(fraction)),
+            None => (time, None),
+        };
+        let mut fields = time.split(':');
+        let (h, m, s) = (fields.next()?, fields.next()?, fields.next());
+        if fields.next().is_some() || h.len() != 2 || m.len() != 2 {
+            return None;
+        }
+        (hour, minute) = (number(h)?, number(m)?);
+        if let Some(s) = s {
+            if s.len() != 2 {
+                return None;
+            }
+            second = number(s)?;
+        }
+        if let Some(fraction) = fraction {
+            // A fraction needs seconds; only milliseconds are kept.
+            s?;
+            if !digits(fraction) {
+                return None;
+            }
+            millis = format!("{fraction:0<3}")[..3].parse().ok()?;
+        }
+        if hour > 23 || minute > 59 || second > 59 {
+            return None;
+        }
+    }
+
+    // Civil date to days, after Howard Hinnant's `days_from_civil`.
+    let (year, month, day) = (i64::from(year), i64::from(month), i64::from(day));
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86_400 + i64::from(hour * 3600 + minute * 60 + second);
+    // Everything recorded is later than the epoch.
+    let secs = u64::try_from(secs).unwrap_or(0);
+    Some(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(u64::from(millis)))
+}
+
+fn current_user() -> String {
+    let user = env::var("USERNAME").or_else(|_| env::var("USER"));
+    match (user, env::var("USERDOMAIN")) {
+        (Ok(user), Ok(domain)) if !domain.is_empty() => format!("{domain}\\{user}"),
+        (Ok(user), _) => user,
+        _ => "?".to_string(),
+    }
+}
+
+fn apply_retention(
+    mut entries: Vec<HistoryEntry>,
+    retention: &HistoryRetention,
+    now: SystemTime,
+) -> Vec<HistoryEntry> {
+    let max_age = Duration::from_secs(retention.max_age_days * 86_400);
+    if let Some(oldest) = now.checked_sub(max_age) {
+        let oldest = format_time(oldest);
+        entries.retain(|entry| entry.time >= oldest);
+    }
+    entries.drain(..entries.len().saturating_sub(retention.max_entries));
+    entries
+}
+
+// Lines that do not parse (a write cut short by a crash) are skipped.
+fn read_entries(path: &PathBuf) -> Result<Vec<HistoryEntry>, String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("Не удалось прочитать {}: {e}", path.display())),
+    }
+}
+
+fn append_entry(path: &PathBuf, entry: &HistoryEntry) -> Result<(), String> {
+    let line =
+        serde_json::to_string(entry).map_err(|e| format!("Ошибка сериализации истории: {e}"))?;
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{line}"))
+        .map_err(|e| format!("Не удалось записать историю в {}: {e}", path.display()))
+}
+
+// Written to a temporary file first so a crash cannot leave half a history.
+fn write_entries(path: &PathBuf, entries: &[HistoryEntry]) -> Result<(), String> {
+    let mut contents = String::new();
+    for entry in entries {
+        let line = serde_json::to_string(entry)
+            .map_err(|e| format!("Ошибка сериализации истории: {e}"))?;
+        contents.push_str(&line);
+        contents.push('\n');
+    }
+    let temp = path.with_extension("jsonl.tmp");
+    fs::write(&temp, contents)
+        .and_then(|_| fs::rename(&temp, path))
+        .map_err(|e| format!("Не удалось сократить историю {}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(time: &str, bus_id: &str) -> HistoryEntry {
+        HistoryEntry {
+            time: time.to_string(),
+            user: "WS\\ivanov".to_string(),
+            action: Action::Attach,
+            bus_id: bus_id.to_string(),
+            vid_pid: "2912:0008".to_string(),
+            serial: None,
+            device_name: "ATOL USB".to_string(),
+            distro: Some("Ubuntu".to_string()),
+            succeeded: false,
+            error: Some("UAC отклонён".to_string()),
+        }
+    }
+
+    #[test]
+    fn parses_since_values() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_714_566_896);
+        assert_eq!(
+            parse_since("2024-05-01", now).unwrap(),
+            "2024-05-01T00:00:00.000Z"
+        );
+        assert_eq!(parse_since("2h", now).unwrap(), "2024-05-01T10:34:56.000Z");
+        assert_eq!(parse_since("7d", now).unwrap(), "2024-04-24T12:34:56.000Z");
+        assert_eq!(
+            parse_since("2024-05-01T08:00:00Z", now).unwrap(),
+            "2024-05-01T08:00:00.000Z"
+        );
+        assert_eq!(
+            parse_since("2024-05-01T10:00Z", now).unwrap(),
+            "2024-05-01T10:00:00.000Z"
+        );
+        assert_eq!(
+            parse_since("2024-02-29T23:59:59.5Z", now).unwrap(),
+            "2024-02-29T23:59:59.500Z"
+        );
+        assert!(parse_since("вчера", now).is_err());
+        assert!(parse_since("2024-05-01T08:00:00+03:00", now).is_err());
+        assert!(parse_since("2024-13-45T99:00:00Z", now).is_err());
+        assert!(parse_since("2023-02-29", now).is_err());
+        assert!(parse_since("2024-05-01T24:00:00Z", now).is_err());
+        assert!(parse_since("2024-05-01T10:00.5Z", now).is_err());
+        assert!(parse_since("2024-5-1", now).is_err());
+    }
+
+    #[test]
+    fn filters_and_prunes_entries() {
+        let entries = vec![
+            entry("2024-01-01T00:00:00.000Z", "2-7"),
+            entry("2024-04-30T00:00:00.000Z", "2-9"),
+            entry("2024-05-01T00:00:00.000Z", "2-7"),
+        ];
+        let filter = HistoryFilter {
+            device: Some("2-7".to_string()),
+            since: Some("2024-02-01T00:00:00.000Z".to_string()),
+            limit: None,
+        };
+        let matched: Vec<_> = entries.iter().filter(|e| filter.matches(e)).collect();
+        assert_eq!(matched, vec![&entries[2]]);
+        let by_vid_pid = HistoryFilter {
+            device: Some("2912:0008".to_string()),
+            ..HistoryFilter::default()
+        };
+        assert!(entries.iter().all(|e| by_vid_pid.matches(e)));
+        // An entry at exactly the boundary is kept whatever the input layout.
+        let now = UNIX_EPOCH + Duration::from_secs(1_714_566_896);
+        let at_midnight = HistoryFilter {
+            since: Some(parse_since("2024-05-01T00:00Z", now).unwrap()),
+            ..HistoryFilter::default()
+        };
+        let matched: Vec<_> = entries.iter().filter(|e| at_midnight.matches(e)).collect();
+        assert_eq!(matched, vec![&entries[2]]);
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_714_566_896);
+        let retention = HistoryRetention {
+            max_age_days: 30,
+            max_entries: 1,
+        };
+        let kept = apply_retention(entries.clone(), &retention, now);
+        assert_eq!(kept, vec![entries[2].clone()]);
+        assert_eq!(
+            entries[0].display(),
+            "2024-01-01 00:00:00 WS\\ivanov attach 2-7 2912:0008 (ATOL USB) → Ubuntu: ошибка: UAC отклонён"
+        );
+    }
+
+    #[test]
+    fn finds_the_distro_of_the_last_attach() {
+        let attach = |distro: &str, succeeded: bool| HistoryEntry {
+            distro: Some(distro.to_string()),
+            succeeded,
+            ..entry("2024-05-01T00:00:00.000Z", "2-7")
+        };
+        let detach = HistoryEntry {
+            action: Action::Detach,
+            ..attach("Ubuntu", true)
+        };
+        let entries = vec![
+            attach("Ubuntu", true),
+            detach,
+            attach("Debian", true),
+            attach("Alpine", false),
+        ];
+        let device = crate::usbipd::parse_usbipd_line(
+            "2-7    2912:0008  ATOL USB                                                      Attached",
+        )
+        .unwrap();
+        assert_eq!(attach_target(&entries, &device), Some("Debian".to_string()));
+        let other = UsbDevice {
+            bus_id: "2-9".to_string(),
+            ..device
+        };
+        assert_eq!(attach_target(&entries, &other), None);
+    }
+}
@@ -0,0 +1,255 @@
+use crate::encoding::decode_output;
+use crate::executor;
+use crate::tools::{self, Tool};
+use crate::usb_ids;
+use crate::usbipd::UsbDevice;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const HOOK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+fn default_hook_timeout() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    PreBind,
+    PostAttach,
+    PreDetach,
+    PostDetach,
+}
+
+impl HookEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            HookEvent::PreBind => "pre_bind",
+            HookEvent::PostAttach => "post_attach",
+            HookEvent::PreDetach => "pre_detach",
+            HookEvent::PostDetach => "post_detach",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookTarget {
+    #[default]
+    Windows,
+    Wsl,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hook {
+    pub command: String,
+    #[serde(default)]
+    pub target: HookTarget,
+    #[serde(default = "default_hook_timeout")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hooks {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_bind: Option<Hook>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_attach: Option<Hook>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_detach: Option<Hook>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_detach: Option<Hook>,
+}
+
+impl Hooks {
+    pub fn get(&self, event: HookEvent) -> Option<&Hook> {
+        match event {
+            HookEvent::PreBind => self.pre_bind.as_ref(),
+            HookEvent::PostAttach => self.post_attach.as_ref(),
+            HookEvent::PreDetach => self.pre_detach.as_ref(),
+            HookEvent::PostDetach => self.post_detach.as_ref(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Hooks::default()
+    }
+}
+
+pub fn hook_env(event: HookEvent, device: &UsbDevice, wsl_distro: &str) -> Vec<(String, String)> {
+    let (vid, pid) = device
+        .vid_pid
+        .split_once(':')
+        .unwrap_or((&device.vid_pid, ""));
+    let nodes: Vec<&str> = device.nodes.iter().map(|node| node.path.as_str()).collect();
+    let info = usb_ids::describe(device);
+    [
+        ("USBIPD_EVENT", event.name()),
+        ("USBIPD_BUS_ID", &device.bus_id),
+        ("USBIPD_VID_PID", &device.vid_pid),
+        ("USBIPD_VID", vid),
+        ("USBIPD_PID", pid),
+        ("USBIPD_SERIAL", device.serial.as_deref().unwrap_or("")),
+        ("USBIPD_DEVICE_NAME", &device.device_name),
+        ("USBIPD_VENDOR", info.vendor.as_deref().unwrap_or("")),
+        ("USBIPD_PRODUCT", info.product.as_deref().unwrap_or("")),
+        ("USBIPD_DEVICE_CLASS", info.class.as_deref().unwrap_or("")),
+        ("USBIPD_WSL_DISTRO", wsl_distro),
+        ("USBIPD_DEVICE_NODES", &nodes.join(" ")),
+    ]
+    .iter()
+    .map(|(name, value)| (name.to_string(), value.to_string()))
+    .collect()
+}
+
+// Environment variables do not cross into WSL by themselves, so distro hooks
+// get them through `env` in front of the shell.
+pub fn hook_command(hook: &Hook, env: &[(String, String)], wsl_distro: &str) -> Command {
+    match hook.target {
+        HookTarget::Windows => {
+            let mut command = Command::new("cmd");
+            command
+                .args(["/C", &hook.command])
+                .envs(env.iter().cloned());
+            command
+        }
+        HookTarget::Wsl => {
+            let mut command = tools::command(Tool::Wsl);
+            command
+                .args(["-d", wsl_distro, "--exec", "env"])
+                .args(env.iter().map(|(name, value)| format!("{name}={value}")))
+                .args(["sh", "-c", &hook.command]);
+            command
+        }
+    }
+}
+
+pub fn run_hook(hook: &Hook, env: &[(String, String)], wsl_distro: &str) -> Result<(), String> {
+    run_with_timeout(
+        hook_command(hook, env, wsl_distro),
+        Duration::from_secs(hook.timeout_secs),
+    )
+}
+
+// Hooks may leave background processes (a serial monitor) holding stderr, so
+// it is only collected briefly once the hook itself has exited.
+pub fn run_with_timeout(mut command: Command, timeout: Duration) -> Result<(), String> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Не удалось запустить: {e}"))?;
+
+    let (stderr_tx, stderr_rx) = mpsc::channel();
+    if let Some(mut stderr) = child.stderr.take() {
+        thread::spawn(move || {
+            let mut bytes = Vec::new();
+            let _ = stderr.read_to_end(&mut bytes);
+            let _ = stderr_tx.send(decode_output(&bytes));
+        });
+    }
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!(
+                    "превышено время ожидания ({} с)",
+                    timeout.as_secs()
+                ));
+            }
+            Ok(None) => {
+                if let Err(err) = executor::check_current() {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(err);
+                }
+                thread::sleep(HOOK_POLL_INTERVAL)
+            }
+            Err(e) => return Err(format!("ошибка ожидания: {e}")),
+        }
+    };
+
+    if status.success() {
+        Ok(())
+    } else {
+        let stderr = stderr_rx
+            .recv_timeout(Duration::from_millis(200))
+            .unwrap_or_default();
+        Err(format!("код {status}: {}", stderr.trim()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbipd::parse_usbipd_line;
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn passes_device_metadata_to_distro_hooks() {
+        let mut device = parse_usbipd_line(
+            "2-7    2912:0008  ATOL USB                                                      Attached",
+        )
+        .unwrap();
+        device.serial = Some("00106".to_string());
+        let env = hook_env(HookEvent::PostAttach, &device, "Ubuntu");
+        let hook = Hook {
+            command: "chmod 666 $USBIPD_DEVICE_NODES".to_string(),
+            target: HookTarget::Wsl,
+            timeout_secs: 5,
+        };
+
+        let command = hook_command(&hook, &env, "Ubuntu");
+        assert_eq!(command.get_program(), tools::tool_path(Tool::Wsl));
+        let args = args(&command);
+        assert_eq!(args[..4], ["-d", "Ubuntu", "--exec", "env"]);
+        assert!(args.contains(&"USBIPD_EVENT=post_attach".to_string()));
+        assert!(args.contains(&"USBIPD_VID=2912".to_string()));
+        assert!(args.contains(&"USBIPD_SERIAL=00106".to_string()));
+        assert_eq!(
+            args[args.len() - 3..],
+            ["sh", "-c", "chmod 666 $USBIPD_DEVICE_NODES"]
+        );
+    }
+
+    #[test]
+    fn parses_hooks_with_defaults() {
+        let hooks: Hooks = serde_json::from_str(
+            r#"{"post_attach": {"command": "sudo udevadm trigger", "target": "wsl"}}"#,
+        )
+        .unwrap();
+        let hook = hooks.get(HookEvent::PostAttach).unwrap();
+        assert_eq!(hook.target, HookTarget::Wsl);
+        assert_eq!(hook.timeout_secs, 30);
+        assert!(hooks.get(HookEvent::PreDetach).is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_failures_and_timeouts() {
+        let mut failing = Command::new("sh");
+        failing.args(["-c", "echo broken >&2; exit 3"]);
+        let err = run_with_timeout(failing, Duration::from_secs(5)).unwrap_err();
+        assert!(err.contains("broken"), "{err}");
+
+        let mut slow = Command::new("sh");
+        slow.args(["-c", "sleep 5"]);
+        let started = Instant::now();
+        assert!(run_with_timeout(slow, Duration::from_millis(200)).is_err());
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+}
@@ -0,0 +1,306 @@
+// Every device the manager has seen, kept after it is unplugged so the list
+// can still show it (greyed out) and its settings can be changed before it
+// comes back. Stored as inventory.json in the state directory and updated
+// from each device list.
+use crate::config::{state_dir, DeviceSettings};
+use crate::logging::{self, format_time};
+use crate::usb_ids;
+use crate::usbipd::UsbDevice;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+const INVENTORY_FILE: &str = "inventory.json";
+pub const OFFLINE_STATE: &str = "Offline";
+// The device list is refreshed every few seconds; last_seen is only moved on
+// (and the file rewritten) once it is this much out of date.
+const LAST_SEEN_RESOLUTION: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InventoryEntry {
+    pub vid_pid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    pub device_name: String,
+    pub first_seen: String,
+    pub last_seen: String,
+    pub last_bus_id: String,
+    pub last_state: String,
+}
+
+impl InventoryEntry {
+    // Shown in place of the bus id for devices that are not connected:
+    // VID:PID, plus the serial number when there is one.
+    pub fn key(&self) -> String {
+        match &self.serial {
+            Some(serial) => format!("{}/{serial}", self.vid_pid),
+            None => self.vid_pid.clone(),
+        }
+    }
+
+    // The device as it was last seen, for settings lookups and Auto-Attach.
+    pub fn as_device(&self) -> UsbDevice {
+        UsbDevice {
+            bus_id: self.last_bus_id.clone(),
+            vid_pid: self.vid_pid.clone(),
+            device_name: self.device_name.clone(),
+            state: OFFLINE_STATE.to_string(),
+            serial: self.serial.clone(),
+            nodes: Vec::new(),
+            classes: Vec::new(),
+        }
+    }
+
+    pub fn display(&self, settings: &DeviceSettings, connected: bool) -> String {
+        let device_name = usb_ids::display_name(&self.as_device());
+        let mut name = match &settings.nickname {
+            Some(nickname) => format!("{nickname} ({device_name})"),
+            None => device_name,
+        };
+        for tag in &settings.tags {
+            name.push_str(&format!(" #{tag}"));
+        }
+        let status = if connected {
+            format!("{} [{}]", self.last_bus_id, self.last_state)
+        } else {
+            format!("не подключено, последний BUSID {}", self.last_bus_id)
+        };
+        format!(
+            "{}: {name} — {status}; впервые {}, последний раз {}",
+            self.key(),
+            self.first_seen,
+            self.last_seen
+        )
+    }
+
+    // Devices without a serial number can only be told apart by port.
+    fn is(&self, device: &UsbDevice) -> bool {
+        self.vid_pid.eq_ignore_ascii_case(&device.vid_pid)
+            && self.serial == device.serial
+            && (self.serial.is_some() || self.last_bus_id == device.bus_id)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Inventory {
+    pub devices: Vec<InventoryEntry>,
+}
+
+impl Inventory {
+    // Returns whether anything worth saving changed.
+    pub fn observe(&mut self, devices: &[UsbDevice], now: SystemTime) -> bool {
+        let time = format_time(now);
+        let stale = now
+            .checked_sub(LAST_SEEN_RESOLUTION)
+            .map(format_time)
+            .unwrap_or_default();
+        let mut matched = vec![false; self.devices.len()];
+        let mut changed = false;
+        for device in devices {
+            let index = (0..self.devices.len())
+                .find(|&i| !matched[i] && self.devices[i].is(device))
+                .or_else(|| {
+                    // A device without a serial number moved to another port.
+                    (0..self.devices.len()).find(|&i| {
+                        let entry = &self.devices[i];
+                        !matched[i]
+                            && device.serial.is_none()
+                            && entry.serial.is_none()
+                            && entry.vid_pid.eq_ignore_ascii_case(&device.vid_pid)
+                            && !devices.iter().any(|other| entry.is(other))
+                    })
+                });
+            let Some(index) = index else {
+                self.devices.push(InventoryEntry {
+                    vid_pid: device.vid_pid.clone(),
+                    serial: device.serial.clone(),
+                    device_name: device.device_name.clone(),
+                    first_seen: time.clone(),
+                    last_seen: time.clone(),
+                    last_bus_id: device.bus_id.clone(),
+                    last_state: device.state.clone(),
+                });
+                matched.push(true);
+                changed = true;
+                continue;
+            };
+            matched[index] = true;
+            let entry = &mut self.devices[index];
+            let updated = InventoryEntry {
+                device_name: device.device_name.clone(),
+                last_bus_id: device.bus_id.clone(),
+                last_state: device.state.clone(),
+                ..entry.clone()
+            };
+            if updated != *entry || entry.last_seen < stale {
+                *entry = InventoryEntry {
+                    last_seen: time.clone(),
+                    ..updated
+                };
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    // Known devices that are not in `devices`, most recently seen first.
+    pub fn offline(&self, devices: &[UsbDevice]) -> Vec<InventoryEntry> {
+        let mut offline: Vec<InventoryEntry> = self
+            .devices
+            .iter()
+            .filter(|entry| !devices.iter().any(|device| entry.is(device)))
+            .cloned()
+            .collect();
+        offline.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        offline
+    }
+}
+
+struct Store {
+    path: Option<PathBuf>,
+    inventory: Inventory,
+}
+
+static STORE: Mutex<Store> = Mutex::new(Store {
+    path: None,
+    inventory: Inventory {
+        devices: Vec::new(),
+    },
+});
+
+pub fn default_inventory_path() -> Option<PathBuf> {
+    state_dir().map(|dir| dir.join(INVENTORY_FILE))
+}
+
+// Without a call the inventory lives only in memory.
+pub fn start(path: Option<PathBuf>) {
+    let inventory = match &path {
+        Some(path) => load(path).unwrap_or_else(|err| {
+            logging::warn(err);
+            Inventory::default()
+        }),
+        None => Inventory::default(),
+    };
+    *STORE.lock().unwrap() = Store { path, inventory };
+}
+
+pub fn observe(devices: &[UsbDevice]) {
+    let mut store = STORE.lock().unwrap();
+    if !store.inventory.observe(devices, SystemTime::now()) {
+        return;
+    }
+    if let Some(path) = &store.path {
+        if let Err(err) = save(path, &store.inventory) {
+            logging::warn(err);
+        }
+    }
+}
+
+pub fn offline(devices: &[UsbDevice]) -> Vec<InventoryEntry> {
+    STORE.lock().unwrap().inventory.offline(devices)
+}
+
+pub fn entries() -> Vec<InventoryEntry> {
+    STORE.lock().unwrap().inventory.devices.clone()
+}
+
+fn load(path: &PathBuf) -> Result<Inventory, String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .map_err(|e| format!("Ошибка разбора {}: {e}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Inventory::default()),
+        Err(e) => Err(format!("Не удалось прочитать {}: {e}", path.display())),
+    }
+}
+
+// Written to a temporary file first so a crash cannot lose the inventory.
+fn save(path: &PathBuf, inventory: &Inventory) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(inventory)
+        .map_err(|e| format!("Ошибка сериализации списка устройств: {e}"))?;
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    let temp = path.with_extension("json.tmp");
+    fs::write(&temp, json)
+        .and_then(|_| fs::rename(&temp, path))
+        .map_err(|e| format!("Не удалось сохранить {}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbipd::parse_usbipd_line;
+    use std::time::UNIX_EPOCH;
+
+    fn device(line: &str, serial: Option<&str>) -> UsbDevice {
+        UsbDevice {
+            serial: serial.map(str::to_string),
+            ..parse_usbipd_line(line).unwrap()
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_714_566_896 + secs)
+    }
+
+    #[test]
+    fn tracks_devices_across_ports_and_unplugging() {
+        let mut inventory = Inventory::default();
+        let reader = device(
+            "2-7    2912:0008  ATOL USB                                                      Shared",
+            Some("00106"),
+        );
+        let mouse = device(
+            "2-9    1a2c:2124  USB Input Device                                              Shared",
+            None,
+        );
+        assert!(inventory.observe(&[reader.clone(), mouse.clone()], at(0)));
+        assert!(!inventory.observe(&[reader.clone(), mouse.clone()], at(5)));
+        assert!(inventory
+            .offline(&[reader.clone(), mouse.clone()])
+            .is_empty());
+
+        // The reader moves to another port, the mouse is unplugged.
+        let moved = [device(
+            "1-3    2912:0008  ATOL USB                                                      Shared",
+            Some("00106"),
+        )];
+        assert!(inventory.observe(&moved, at(120)));
+        assert_eq!(inventory.devices.len(), 2);
+        assert_eq!(inventory.devices[0].last_bus_id, "1-3");
+        assert_eq!(inventory.devices[0].first_seen, "2024-05-01T12:34:56.000Z");
+        assert_eq!(inventory.devices[0].last_seen, "2024-05-01T12:36:56.000Z");
+
+        let offline = inventory.offline(&moved);
+        assert_eq!(offline.len(), 1);
+        assert_eq!(offline[0].key(), "1a2c:2124");
+        assert_eq!(offline[0].as_device().bus_id, "2-9");
+        assert_eq!(offline[0].as_device().state, OFFLINE_STATE);
+    }
+
+    #[test]
+    fn tells_apart_devices_without_serials_by_port() {
+        let mut inventory = Inventory::default();
+        let first = device(
+            "2-1    1a2c:2124  USB Input Device                                              Shared",
+            None,
+        );
+        let second = device(
+            "2-2    1a2c:2124  USB Input Device                                              Shared",
+            None,
+        );
+        inventory.observe(&[first.clone(), second.clone()], at(0));
+        assert_eq!(inventory.devices.len(), 2);
+
+        // One of them is plugged into a new port while the other is away.
+        let replugged = [device(
+            "2-5    1a2c:2124  USB Input Device                                              Shared",
+            None,
+        )];
+        inventory.observe(&replugged, at(10));
+        assert_eq!(inventory.devices.len(), 2);
+        assert_eq!(inventory.offline(&replugged).len(), 1);
+    }
+}
@@ -0,0 +1,351 @@
+// Leveled log shared by the CLI and the GUI. Every record goes to a JSON-lines
+// file in the per-user log directory (rotated by size), to the GUI's log pane
+// through `subscribe`, and, unless it describes an operation the caller
+// reports itself, to stderr.
+use crate::config::state_dir;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const LOG_DIR_ENV: &str = "USBIPD_GUI_LOG_DIR";
+const LOG_FILE: &str = "usbipd_gui.log";
+const MAX_LOG_SIZE: u64 = 1024 * 1024;
+// The current file plus usbipd_gui.1.log ... usbipd_gui.4.log.
+const MAX_LOG_FILES: usize = 5;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogRecord {
+    pub time: String,
+    pub level: LogLevel,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operation: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bus_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<String>,
+}
+
+impl LogRecord {
+    pub fn new(level: LogLevel, message: impl Into<String>) -> Self {
+        Self {
+            time: format_time(SystemTime::now()),
+            level,
+            message: message.into(),
+            operation: None,
+            bus_id: None,
+            duration_ms: None,
+            outcome: None,
+        }
+    }
+
+    // One line for the log pane: time of day, level and message.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn display(&self) -> String {
+        let time = self.time.get(11..19).unwrap_or(&self.time);
+        format!("{time} {:<5} {}", self.level.name(), self.message)
+    }
+}
+
+struct Logger {
+    level: LogLevel,
+    dir: Option<PathBuf>,
+    file: Option<File>,
+    size: u64,
+    subscribers: Vec<Sender<LogRecord>>,
+}
+
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    level: LogLevel::Info,
+    dir: None,
+    file: None,
+    size: 0,
+    subscribers: Vec::new(),
+});
+
+// $USBIPD_GUI_LOG_DIR, else the logs directory in the state directory.
+pub fn default_log_dir() -> Option<PathBuf> {
+    match env::var(LOG_DIR_ENV) {
+        Ok(dir) if !dir.trim().is_empty() => Some(PathBuf::from(dir)),
+        _ => state_dir().map(|dir| dir.join("logs")),
+    }
+}
+
+// Without a call the log is kept only in memory (stderr and subscribers).
+pub fn start(dir: Option<PathBuf>) {
+    let mut logger = LOGGER.lock().unwrap();
+    logger.dir = dir;
+    logger.file = None;
+}
+
+pub fn log_file() -> Option<PathBuf> {
+    LOGGER
+        .lock()
+        .unwrap()
+        .dir
+        .as_ref()
+        .map(|dir| dir.join(LOG_FILE))
+}
+
+pub fn set_level(level: LogLevel) {
+    LOGGER.lock().unwrap().level = level;
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn subscribe() -> Receiver<LogRecord> {
+    let (sender, receiver) = mpsc::channel();
+    LOGGER.lock().unwrap().subscribers.push(sender);
+    receiver
+}
+
+pub fn log(record: LogRecord) {
+    let mut logger = LOGGER.lock().unwrap();
+    if record.level > logger.level {
+        return;
+    }
+    if record.operation.is_none() {
+        eprintln!("{}", record.message);
+    }
+    logger.write(&record);
+    logger
+        .subscribers
+        .retain(|subscriber| subscriber.send(record.clone()).is_ok());
+}
+
+pub fn error(message: impl Into<String>) {
+    log(LogRecord::new(LogLevel::Error, message));
+}
+
+pub fn warn(message: impl Into<String>) {
+    log(LogRecord::new(LogLevel::Warn, message));
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn info(message: impl Into<String>) {
+    log(LogRecord::new(LogLevel::Info, message));
+}
+
+#[cfg(windows)]
+pub fn debug(message: impl Into<String>) {
+    log(LogRecord::new(LogLevel::Debug, message));
+}
+
+// `outcome` is one of the OperationOutcome names; routine background work
+// passes `quiet` so its successes stay at debug level.
+pub fn operation(
+    label: &str,
+    bus_id: Option<&str>,
+    elapsed: Option<Duration>,
+    outcome: &str,
+    error: Option<&str>,
+    quiet: bool,
+) {
+    let (level, text) = match outcome {
+        "succeeded" if quiet => (LogLevel::Debug, "выполнено"),
+        "succeeded" => (LogLevel::Info, "выполнено"),
+        "failed" => (LogLevel::Error, "ошибка"),
+        "timed_out" => (LogLevel::Error, "превышено время ожидания"),
+        "cancelled" => (LogLevel::Warn, "отменено"),
+        _ => (LogLevel::Info, outcome),
+    };
+    let mut message = format!("{label}: {text}");
+    if let Some(elapsed) = elapsed {
+        message.push_str(&format!(" за {} мс", elapsed.as_millis()));
+    }
+    if let Some(error) = error {
+        message.push_str(&format!(": {error}"));
+    }
+    log(LogRecord {
+        operation: Some(label.to_string()),
+        bus_id: bus_id.map(str::to_string),
+        duration_ms: elapsed.map(|elapsed| elapsed.as_millis() as u64),
+        outcome: Some(outcome.to_string()),
+        ..LogRecord::new(level, message)
+    });
+}
+
+impl Logger {
+    fn write(&mut self, record: &LogRecord) {
+        let Some(dir) = self.dir.clone() else {
+            return;
+        };
+        let Ok(line) = serde_json::to_string(record) else {
+            return;
+        };
+        if self.file.is_none() && !self.open(&dir) {
+            return;
+        }
+        // Also on the first write of a process: the log it finds may
+        // already be full.
+        if self.size > 0 && self.size + line.len() as u64 >= MAX_LOG_SIZE {
+            self.file = None;
+            rotate_files(&dir, MAX_LOG_FILES);
+            if !self.open(&dir) {
+                return;
+            }
+        }
+        if let Some(file) = &mut self.file {
+            if writeln!(file, "{line}").is_ok() {
+                self.size += line.len() as u64 + 1;
+            }
+        }
+    }
+
+    fn open(&mut self, dir: &Path) -> bool {
+        let opened = fs::create_dir_all(dir).and_then(|_| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(LOG_FILE))
+        });
+        match opened {
+            Ok(file) => {
+                self.size = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+                self.file = Some(file);
+                true
+            }
+            Err(e) => {
+                eprintln!("Журнал в {} недоступен: {e}", dir.display());
+                self.dir = None;
+                false
+            }
+        }
+    }
+}
+
+fn rotated_name(index: usize) -> String {
+    if index == 0 {
+        LOG_FILE.to_string()
+    } else {
+        format!("usbipd_gui.{index}.log")
+    }
+}
+
+// usbipd_gui.log becomes usbipd_gui.1.log and so on; the oldest is dropped.
+fn rotate_files(dir: &Path, keep: usize) {
+    let _ = fs::remove_file(dir.join(rotated_name(keep - 1)));
+    for index in (0..keep - 1).rev() {
+        let _ = fs::rename(
+            dir.join(rotated_name(index)),
+            dir.join(rotated_name(index + 1)),
+        );
+    }
+}
+
+// RFC 3339 in UTC: 2024-05-01T12:34:56.789Z.
+pub fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rest) = (secs / 86_400, secs % 86_400);
+    // Days to civil date, after Howard Hinnant's `civil_from_days`.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_utc_timestamps() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_714_566_896_789);
+        assert_eq!(format_time(time), "2024-05-01T12:34:56.789Z");
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let leap = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(format_time(leap), "2000-02-29T00:00:00.000Z");
+
+        let record = LogRecord {
+            time: "2024-05-01T12:34:56.789Z".to_string(),
+            ..LogRecord::new(LogLevel::Warn, "Bind 2-7: отменено")
+        };
+        assert_eq!(record.display(), "12:34:56 WARN  Bind 2-7: отменено");
+        assert!(LogLevel::Error < LogLevel::Debug);
+    }
+
+    #[test]
+    fn rotates_log_files() {
+        let dir = env::temp_dir().join(format!("usbipd-gui-logs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (index, text) in ["new", "older", "oldest"].iter().enumerate() {
+            fs::write(dir.join(rotated_name(index)), text).unwrap();
+        }
+        rotate_files(&dir, 3);
+        assert!(!dir.join(rotated_name(0)).exists());
+        assert_eq!(
+            fs::read_to_string(dir.join(rotated_name(1))).unwrap(),
+            "new"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join(rotated_name(2))).unwrap(),
+            "older"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_a_full_log_on_first_write() {
+        let dir = env::temp_dir().join(format!("usbipd-gui-full-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(LOG_FILE), vec![b'x'; MAX_LOG_SIZE as usize]).unwrap();
+        let mut logger = Logger {
+            level: LogLevel::Info,
+            dir: Some(dir.clone()),
+            file: None,
+            size: 0,
+            subscribers: Vec::new(),
+        };
+        logger.write(&LogRecord::new(LogLevel::Info, "Bind 2-7: успешно"));
+        assert_eq!(
+            fs::metadata(dir.join(rotated_name(1))).unwrap().len(),
+            MAX_LOG_SIZE
+        );
+        let log = fs::read_to_string(dir.join(LOG_FILE)).unwrap();
+        assert!(log.contains("Bind 2-7: успешно"));
+        assert_eq!(log.lines().count(), 1);
+        drop(logger);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,432 @@
+use crate::config::{Config, DeviceSettings};
+use crate::executor::OperationContext;
+use crate::history::{self, Action};
+use crate::hooks::{hook_env, run_hook, HookEvent, Hooks};
+use crate::inventory;
+use crate::policy::Protection;
+use crate::usbipd::{
+    fetch_usb_devices_with_serials, get_device_state, is_bindable_state, run_usbipd_attach,
+    run_usbipd_bind, run_usbipd_detach, run_usbipd_unbind, UsbDevice,
+};
+use crate::wsl::{
+    check_attached, describe_attach_check, describe_holders, find_device_holders, find_distro,
+    list_wsl_distros, resolve_device_nodes, start_distro, unmount, AttachCheck, WslState,
+};
+use std::time::{Duration, Instant};
+use usbipd_agent::procfs::Holder;
+use usbipd_agent::sysfs::SysfsUsbDevice;
+
+pub const STATE_WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const ENUMERATION_TIMEOUT: Duration = Duration::from_secs(10);
+const STATE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+const ENUMERATION_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DetachOutcome {
+    Detached,
+    Busy(Vec<Holder>),
+    // Open handles could not be checked (no agent, distro error).
+    Unverified(String),
+}
+
+impl DetachOutcome {
+    // Why a detach without `force` was refused.
+    pub fn refusal(&self, bus_id: &str, wsl_distro: &str) -> Option<String> {
+        match self {
+            DetachOutcome::Detached => None,
+            DetachOutcome::Busy(holders) => Some(format!(
+                "Устройство {bus_id} используется в {wsl_distro}:\n{}",
+                describe_holders(holders)
+            )),
+            DetachOutcome::Unverified(err) => Some(format!(
+                "Не удалось проверить, используется ли устройство {bus_id} в {wsl_distro}: {err}"
+            )),
+        }
+    }
+}
+
+pub fn bind(
+    ctx: &OperationContext,
+    device: &UsbDevice,
+    settings: &DeviceSettings,
+) -> Result<(), String> {
+    let bus_id = device.bus_id.as_str();
+    // Confirmations are asked for before the operation is queued; a denied
+    // device is refused whatever queued it.
+    if let Protection::Deny(reason) = &settings.protection {
+        let result = Err(format!("Привязка запрещена: {reason}"));
+        history::record(Action::Bind, device, None, &result);
+        return result;
+    }
+    run_hooks(
+        ctx,
+        &settings.hooks,
+        HookEvent::PreBind,
+        device,
+        &settings.wsl_distro,
+    );
+    ctx.progress(format!("bind {bus_id}: ожидание подтверждения UAC"));
+    let result = run_usbipd_bind(bus_id, settings.force_bind);
+    history::record(Action::Bind, device, None, &result);
+    result?;
+    ctx.progress(format!("bind {bus_id}: ожидание смены состояния"));
+    if !wait_for_device_state(ctx, bus_id, |state| !is_bindable_state(state))? {
+        ctx.warn(state_timeout(bus_id, "Shared"));
+    }
+    Ok(())
+}
+
+// usbipd keeps the mode a device was bound with, so switching between a
+// normal and a forced binding means unbinding and binding again. Attached
+// devices are left alone, and a failed bind restores the original mode.
+pub fn rebind(
+    ctx: &OperationContext,
+    device: &UsbDevice,
+    settings: &DeviceSettings,
+) -> Result<(), String> {
+    let bus_id = device.bus_id.as_str();
+    let forced = match device.state.as_str() {
+        "Shared" => false,
+        "Shared (forced)" => true,
+        "Attached" => {
+            return Err(format!(
+                "Устройство {bus_id} подключено к WSL, сначала выполните detach"
+            ))
+        }
+        state => return Err(format!("Устройство {bus_id} не привязано ({state})")),
+    };
+    if forced == settings.force_bind {
+        return Ok(());
+    }
+    if let Protection::Deny(reason) = &settings.protection {
+        return Err(format!("Привязка запрещена: {reason}"));
+    }
+    unbind(ctx, device)?;
+    bind(ctx, device, settings).map_err(|err| {
+        ctx.progress(format!("Откат: bind {bus_id}"));
+        let result = run_usbipd_bind(bus_id, forced);
+        history::record(Action::Bind, device, None, &result);
+        match result {
+            Ok(()) => err,
+            Err(e) => format!("{err}\nОшибки отката: bind: {e}"),
+        }
+    })
+}
+
+pub fn unbind(ctx: &OperationContext, device: &UsbDevice) -> Result<(), String> {
+    let bus_id = device.bus_id.as_str();
+    ctx.progress(format!("unbind {bus_id}: ожидание подтверждения UAC"));
+    let result = run_usbipd_unbind(bus_id);
+    history::record(Action::Unbind, device, None, &result);
+    result?;
+    ctx.progress(format!("unbind {bus_id}: ожидание смены состояния"));
+    if !wait_for_device_state(ctx, bus_id, is_bindable_state)? {
+        ctx.warn(state_timeout(bus_id, "Not shared"));
+    }
+    Ok(())
+}
+
+pub fn attach(
+    ctx: &OperationContext,
+    device: &UsbDevice,
+    settings: &DeviceSettings,
+) -> Result<(), String> {
+    let (bus_id, wsl_distro) = (device.bus_id.as_str(), settings.wsl_distro.as_str());
+    let distro = list_wsl_distros()
+        .ok()
+        .and_then(|distros| find_distro(&distros, wsl_distro).cloned());
+    if let Some(distro) = &distro {
+        if distro.version == 1 {
+            ctx.warn(format!(
+                "{wsl_distro} работает как WSL1, usbipd подключает устройства только к WSL2 \
+                 (wsl --set-version {wsl_distro} 2)"
+            ));
+        }
+        if settings.start_distro && distro.state != WslState::Running {
+            ctx.progress(format!("Запуск {wsl_distro}"));
+            start_distro(wsl_distro)?;
+        }
+    }
+    ctx.check()?;
+    ctx.progress(format!("attach {bus_id} → {wsl_distro}"));
+    let result = run_usbipd_attach(bus_id, wsl_distro);
+    history::record(Action::Attach, device, Some(wsl_distro), &result);
+    result
+}
+
+pub fn attach_and_verify(
+    ctx: &OperationContext,
+    device: &UsbDevice,
+    settings: &DeviceSettings,
+) -> Result<SysfsUsbDevice, String> {
+    let wsl_distro = settings.wsl_distro.as_str();
+    attach(ctx, device, settings)?;
+    let found = verify_attached(ctx, device, wsl_distro)?;
+    run_post_attach_hooks(ctx, &settings.hooks, device, &found, wsl_distro);
+    Ok(found)
+}
+
+// Attached devices are looked up in the distro each one is configured for.
+pub fn list_devices(config: &Config) -> Result<Vec<UsbDevice>, String> {
+    let mut devices = fetch_usb_devices_with_serials()?;
+    inventory::observe(&devices);
+    let mut distros: Vec<String> = devices
+        .iter()
+        .map(|device| config.settings_for(device).wsl_distro)
+        .collect();
+    distros.sort();
+    distros.dedup();
+
+    for distro in distros {
+        let mut targets: Vec<UsbDevice> = devices
+            .iter()
+            .filter(|device| config.settings_for(device).wsl_distro == distro)
+            .cloned()
+            .collect();
+        resolve_device_nodes(&distro, &mut targets);
+        for target in targets {
+            if let Some(device) = devices.iter_mut().find(|d| d.bus_id == target.bus_id) {
+                device.nodes = target.nodes;
+            }
+        }
+    }
+    Ok(devices)
+}
+
+pub fn detach(ctx: &OperationContext, device: &UsbDevice, wsl_distro: &str) -> Result<(), String> {
+    ctx.progress(format!("detach {}", device.bus_id));
+    let result = run_usbipd_detach(&device.bus_id);
+    history::record(Action::Detach, device, Some(wsl_distro), &result);
+    result
+}
+
+// Pulling a device that a process still uses corrupts filesystems and kills
+// flashing sessions, so look for open handles and unmount first. Without
+// `force` a busy device, or one that could not be checked, is left attached.
+pub fn safe_detach(
+    ctx: &OperationContext,
+    device: &UsbDevice,
+    settings: &DeviceSettings,
+    force: bool,
+) -> Result<DetachOutcome, String> {
+    let (bus_id, wsl_distro, hooks) = (
+        device.bus_id.as_str(),
+        settings.wsl_distro.as_str(),
+        &settings.hooks,
+    );
+    if device.state == "Attached" {
+        ctx.progress(format!(
+            "Проверка открытых дескрипторов {bus_id} в {wsl_distro}"
+        ));
+        match find_device_holders(wsl_distro, device) {
+            Ok((_, holders)) if !holders.is_empty() && !force => {
+                return Ok(DetachOutcome::Busy(holders));
+            }
+            Ok((mounts, _)) => {
+                for mount in mounts {
+                    ctx.check()?;
+                    ctx.progress(format!("Размонтирование {}", mount.target));
+                    unmount(wsl_distro, &mount.target, force)?;
+                }
+            }
+            Err(err) if force => {
+                ctx.progress(format!("Проверка невозможна ({err}), отключение без неё"))
+            }
+            Err(err) => return Ok(DetachOutcome::Unverified(err)),
+        }
+    }
+    ctx.check()?;
+    // Only once the detach is certain, so a refused detach and its forced
+    // retry do not run the hooks twice.
+    run_hooks(ctx, hooks, HookEvent::PreDetach, device, wsl_distro);
+    detach(ctx, device, wsl_distro)?;
+    run_hooks(ctx, hooks, HookEvent::PostDetach, device, wsl_distro);
+    Ok(DetachOutcome::Detached)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MoveOutcome {
+    Moved(SysfsUsbDevice),
+    // The detach from the current distro was refused; the device stays
+    // attached there.
+    NotDetached(DetachOutcome),
+}
+
+// Detaches from the current distro (without `force`, refusing while the
+// device is in use there) and connects it to `target`.
+pub fn move_to_distro(
+    ctx: &OperationContext,
+    device: &UsbDevice,
+    settings: &DeviceSettings,
+    target: &str,
+    force: bool,
+) -> Result<MoveOutcome, String> {
+    let bus_id = device.bus_id.as_str();
+    let target_settings = DeviceSettings {
+        wsl_distro: target.to_string(),
+        ..settings.clone()
+    };
+    if device.state == "Attached" {
+        if settings.wsl_distro == target {
+            return verify_attached(ctx, device, target).map(MoveOutcome::Moved);
+        }
+        let outcome = safe_detach(ctx, device, settings, force)?;
+        if outcome != DetachOutcome::Detached {
+            return Ok(MoveOutcome::NotDetached(outcome));
+        }
+        // Attaching a device that is still attached elsewhere would fail.
+        if !wait_for_device_state(ctx, bus_id, |state| state != "Attached")? {
+            return Err(format!(
+                "Устройство {bus_id} не отключилось от {} за {} с",
+                settings.wsl_distro,
+                STATE_WAIT_TIMEOUT.as_secs()
+            ));
+        }
+    }
+    ctx.check()?;
+    connect_to_wsl(ctx, device, &target_settings).map(MoveOutcome::Moved)
+}
+
+// Bind (if needed), attach and check that the device shows up inside the
+// distro. Steps performed here are rolled back when a later step fails.
+pub fn connect_to_wsl(
+    ctx: &OperationContext,
+    device: &UsbDevice,
+    settings: &DeviceSettings,
+) -> Result<SysfsUsbDevice, String> {
+    let (bus_id, wsl_distro) = (device.bus_id.as_str(), settings.wsl_distro.as_str());
+    let state =
+        get_device_state(bus_id)?.ok_or_else(|| format!("Устройство {bus_id} не найдено"))?;
+
+    let mut bound_here = false;
+    if is_bindable_state(&state) {
+        bind(ctx, device, settings)?;
+        bound_here = true;
+    }
+
+    let mut attached_here = false;
+    let result = (|| {
+        if state != "Attached" {
+            ctx.check()?;
+            attach(ctx, device, settings)?;
+            attached_here = true;
+        }
+        verify_attached(ctx, device, wsl_distro)
+    })();
+
+    if let Ok(found) = &result {
+        run_post_attach_hooks(ctx, &settings.hooks, device, found, wsl_distro);
+    }
+
+    result.map_err(|err| {
+        let mut rollback_errors = Vec::new();
+        if attached_here {
+            ctx.progress(format!("Откат: detach {bus_id}"));
+            let result = run_usbipd_detach(bus_id);
+            history::record(Action::Detach, device, Some(wsl_distro), &result);
+            if let Err(e) = result {
+                rollback_errors.push(format!("detach: {e}"));
+            }
+        }
+        if bound_here {
+            ctx.progress(format!("Откат: unbind {bus_id}"));
+            let result = run_usbipd_unbind(bus_id);
+            history::record(Action::Unbind, device, None, &result);
+            if let Err(e) = result {
+                rollback_errors.push(format!("unbind: {e}"));
+            }
+        }
+        if rollback_errors.is_empty() {
+            err
+        } else {
+            format!("{err}\nОшибки отката: {}", rollback_errors.join("; "))
+        }
+    })
+}
+
+// Hook failures are reported as warnings and never fail the operation.
+pub fn run_hooks(
+    ctx: &OperationContext,
+    hooks: &[Hooks],
+    event: HookEvent,
+    device: &UsbDevice,
+    wsl_distro: &str,
+) {
+    for hook in hooks.iter().filter_map(|hooks| hooks.get(event)) {
+        ctx.progress(format!("Хук {}: {}", event.name(), hook.command));
+        let env = hook_env(event, device, wsl_distro);
+        if let Err(err) = run_hook(hook, &env, wsl_distro) {
+            ctx.warn(format!(
+                "Хук {} для {} завершился с ошибкой: {err}",
+                event.name(),
+                device.bus_id
+            ));
+        }
+    }
+}
+
+fn run_post_attach_hooks(
+    ctx: &OperationContext,
+    hooks: &[Hooks],
+    device: &UsbDevice,
+    found: &SysfsUsbDevice,
+    wsl_distro: &str,
+) {
+    let attached = UsbDevice {
+        nodes: found.nodes.clone(),
+        ..device.clone()
+    };
+    run_hooks(ctx, hooks, HookEvent::PostAttach, &attached, wsl_distro);
+}
+
+// `usbipd attach` succeeding does not mean Linux saw the device (missing
+// vhci_hcd, restarted distro), so poll the distro's sysfs until it shows up.
+pub fn verify_attached(
+    ctx: &OperationContext,
+    device: &UsbDevice,
+    wsl_distro: &str,
+) -> Result<SysfsUsbDevice, String> {
+    let vid_pid = device.vid_pid.as_str();
+    ctx.progress(format!("Проверка {vid_pid} в {wsl_distro}"));
+    let deadline = Instant::now() + ENUMERATION_TIMEOUT;
+    loop {
+        ctx.check()?;
+        let check = check_attached(wsl_distro, vid_pid, device.serial.as_deref())?;
+        match check {
+            AttachCheck::Present(device) => return Ok(device),
+            AttachCheck::Missing { .. } if Instant::now() >= deadline => {
+                return Err(describe_attach_check(&check, vid_pid, wsl_distro));
+            }
+            AttachCheck::Missing { .. } => ctx.sleep(ENUMERATION_POLL_INTERVAL)?,
+        }
+    }
+}
+
+// usbipd reports success before the state changes; a bind or unbind whose
+// state lags behind is only worth a warning.
+fn state_timeout(bus_id: &str, expected: &str) -> String {
+    format!(
+        "Устройство {bus_id} не перешло в состояние {expected} за {} с",
+        STATE_WAIT_TIMEOUT.as_secs()
+    )
+}
+
+// Returns Ok(false) when the device did not reach the state within
+// STATE_WAIT_TIMEOUT; cancellation and the operation timeout surface as Err.
+pub fn wait_for_device_state(
+    ctx: &OperationContext,
+    bus_id: &str,
+    predicate: fn(&str) -> bool,
+) -> Result<bool, String> {
+    let deadline = Instant::now() + STATE_WAIT_TIMEOUT;
+    while Instant::now() < deadline {
+        ctx.check()?;
+        if let Ok(Some(state)) = get_device_state(bus_id) {
+            if predicate(&state) {
+                return Ok(true);
+            }
+        }
+        ctx.sleep(STATE_POLL_INTERVAL)?;
+    }
+    Ok(false)
+}
@@ -0,0 +1,312 @@
+// Devices that must not be taken away from Windows by mistake: binding one
+// is refused (`deny`) or needs an explicit confirmation (`confirm`). Binding
+// the last keyboard or mouse left to Windows is confirmed as well.
+use crate::usb_ids;
+use crate::usbipd::UsbDevice;
+use serde::{Deserialize, Serialize};
+
+const HID_CLASS: u8 = 0x03;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtectionPolicy {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<DeviceRule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub confirm: Vec<DeviceRule>,
+    #[serde(default = "default_true")]
+    pub warn_last_input_device: bool,
+}
+
+// Every field that is set has to match; patterns may use `*` and `?` and
+// ignore case.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceRule {
+    // "046d:c52b", or "046d:*" for a whole vendor.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vid_pid: Option<String>,
+    // Class name from usb.ids ("Human Interface Device"), its code ("03") or
+    // "HID".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
+    // Matched against the Windows name, the usb.ids name and the nickname.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // Shown instead of the generic explanation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Protection {
+    #[default]
+    Allowed,
+    Confirm(String),
+    Deny(String),
+}
+
+impl Default for ProtectionPolicy {
+    fn default() -> Self {
+        Self {
+            deny: Vec::new(),
+            confirm: Vec::new(),
+            warn_last_input_device: true,
+        }
+    }
+}
+
+impl ProtectionPolicy {
+    pub fn is_default(&self) -> bool {
+        *self == ProtectionPolicy::default()
+    }
+
+    // `deny` wins over `confirm`.
+    pub fn check(&self, device: &UsbDevice, nickname: Option<&str>) -> Protection {
+        let reason = |rule: &DeviceRule| {
+            rule.reason.clone().unwrap_or_else(|| {
+                format!(
+                    "{} ({}) защищено политикой protect",
+                    usb_ids::display_name(device),
+                    device.vid_pid
+                )
+            })
+        };
+        if let Some(rule) = self.deny.iter().find(|rule| rule.matches(device, nickname)) {
+            return Protection::Deny(reason(rule));
+        }
+        match self
+            .confirm
+            .iter()
+            .find(|rule| rule.matches(device, nickname))
+        {
+            Some(rule) => Protection::Confirm(reason(rule)),
+            None => Protection::Allowed,
+        }
+    }
+}
+
+impl DeviceRule {
+    pub fn matches(&self, device: &UsbDevice, nickname: Option<&str>) -> bool {
+        if self.vid_pid.is_none() && self.class.is_none() && self.name.is_none() {
+            return false;
+        }
+        let vid_pid_matches = self
+            .vid_pid
+            .as_deref()
+            .is_none_or(|pattern| glob_match(pattern, &device.vid_pid));
+        // Any interface class counts: a keyboard receiver is often a
+        // composite device with more than one.
+        let class_matches = self.class.as_deref().is_none_or(|pattern| {
+            let codes = usb_ids::class_codes(device);
+            let names = usb_ids::describe(device).class.unwrap_or_default();
+            names.split(", ").any(|name| glob_match(pattern, name))
+                || u8::from_str_radix(pattern, 16).is_ok_and(|code| codes.contains(&code))
+                || (pattern.eq_ignore_ascii_case("hid") && codes.contains(&HID_CLASS))
+        });
+        let name_matches = self.name.as_deref().is_none_or(|pattern| {
+            [
+                Some(device.device_name.clone()),
+                Some(usb_ids::display_name(device)),
+                usb_ids::describe(device).name(),
+                nickname.map(str::to_string),
+            ]
+            .into_iter()
+            .flatten()
+            .any(|name| glob_match(pattern, &name))
+        });
+        vid_pid_matches && class_matches && name_matches
+    }
+}
+
+// Binding takes a keyboard or mouse away from Windows once it is attached
+// (or at once with --force); a remote machine cannot be reached again if it
+// was the last one.
+pub fn last_input_device_warning(device: &UsbDevice, devices: &[UsbDevice]) -> Option<String> {
+    if !is_input_device(device) {
+        return None;
+    }
+    let others_left = devices.iter().any(|other| {
+        other.bus_id != device.bus_id
+            && is_input_device(other)
+            && !matches!(other.state.as_str(), "Attached" | "Shared (forced)")
+    });
+    (!others_left).then(|| {
+        format!(
+            "{} — последнее устройство ввода, оставшееся у Windows: после привязки \
+             клавиатура или мышь могут перестать работать",
+            usb_ids::display_name(device)
+        )
+    })
+}
+
+fn is_input_device(device: &UsbDevice) -> bool {
+    usb_ids::class_codes(device).contains(&HID_CLASS)
+}
+
+// What has to be confirmed before `device` is bound, or why it may not be.
+pub fn bind_confirmations(
+    policy: &ProtectionPolicy,
+    protection: &Protection,
+    device: &UsbDevice,
+    devices: &[UsbDevice],
+) -> Result<Vec<String>, String> {
+    let mut confirmations = Vec::new();
+    match protection {
+        Protection::Deny(reason) => return Err(format!("Привязка запрещена: {reason}")),
+        Protection::Confirm(reason) => confirmations.push(reason.clone()),
+        Protection::Allowed => {}
+    }
+    if policy.warn_last_input_device {
+        confirmations.extend(last_input_device_warning(device, devices));
+    }
+    Ok(confirmations)
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    // Position after the last `*` and the text position it is tried at.
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, t));
+            p += 1;
+        } else if let Some((after_star, tried)) = star {
+            p = after_star;
+            t = tried + 1;
+            star = Some((after_star, tried + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbipd::parse_usbipd_line;
+
+    fn device(line: &str) -> UsbDevice {
+        parse_usbipd_line(line).unwrap()
+    }
+
+    #[test]
+    fn matches_rules_by_vid_pid_class_and_name() {
+        // As usbipd names a receiver with a keyboard and a mouse interface.
+        let keyboard = device(
+            "1-2    046d:c52b  USB Input Device, USB Input Device                            Not shared",
+        );
+        let reader = device(
+            "2-7    058f:9540  Alcorlink USB Smart Card Reader                               Shared",
+        );
+        let rule = |rule: DeviceRule| rule.matches(&keyboard, None);
+        assert!(rule(DeviceRule {
+            vid_pid: Some("046D:*".to_string()),
+            ..DeviceRule::default()
+        }));
+        assert!(rule(DeviceRule {
+            class: Some("hid".to_string()),
+            ..DeviceRule::default()
+        }));
+        assert!(rule(DeviceRule {
+            class: Some("03".to_string()),
+            name: Some("*unifying*".to_string()),
+            ..DeviceRule::default()
+        }));
+        assert!(!rule(DeviceRule {
+            class: Some("Mass Storage".to_string()),
+            ..DeviceRule::default()
+        }));
+        assert!(!rule(DeviceRule::default()));
+        // A composite device is HID through the interface classes Windows
+        // reports.
+        let composite = UsbDevice {
+            classes: vec![0x01, 0x03],
+            ..device(
+                "1-5    046d:0a44  USB Composite Device                                          Not shared",
+            )
+        };
+        assert!(DeviceRule {
+            class: Some("HID".to_string()),
+            ..DeviceRule::default()
+        }
+        .matches(&composite, None));
+        assert!(DeviceRule {
+            name: Some("касс?".to_string()),
+            ..DeviceRule::default()
+        }
+        .matches(&reader, Some("Касса")));
+    }
+
+    #[test]
+    fn deny_wins_over_confirm() {
+        let keyboard = device(
+            "1-2    046d:c52b  USB Input Device                                              Not shared",
+        );
+        let hid = DeviceRule {
+            class: Some("HID".to_string()),
+            ..DeviceRule::default()
+        };
+        let mut policy = ProtectionPolicy {
+            confirm: vec![hid.clone()],
+            ..ProtectionPolicy::default()
+        };
+        assert!(matches!(
+            policy.check(&keyboard, None),
+            Protection::Confirm(_)
+        ));
+        policy.deny.push(DeviceRule {
+            reason: Some("Клавиатура сервера".to_string()),
+            ..hid
+        });
+        let protection = policy.check(&keyboard, None);
+        assert_eq!(
+            protection,
+            Protection::Deny("Клавиатура сервера".to_string())
+        );
+        assert_eq!(
+            bind_confirmations(&policy, &protection, &keyboard, &[]),
+            Err("Привязка запрещена: Клавиатура сервера".to_string())
+        );
+    }
+
+    #[test]
+    fn warns_about_the_last_input_device() {
+        let keyboard = device(
+            "1-2    046d:c52b  USB Input Device                                              Not shared",
+        );
+        let mouse = UsbDevice {
+            classes: vec![0x03],
+            ..device(
+                "1-3    046d:c077  USB Composite Device                                          Not shared",
+            )
+        };
+        let policy = ProtectionPolicy::default();
+        let confirm = |devices: &[UsbDevice]| {
+            bind_confirmations(&policy, &Protection::Allowed, &keyboard, devices)
+                .unwrap()
+                .len()
+        };
+        assert_eq!(confirm(&[keyboard.clone(), mouse.clone()]), 0);
+        let attached = UsbDevice {
+            state: "Attached".to_string(),
+            ..mouse
+        };
+        assert_eq!(confirm(&[keyboard.clone(), attached]), 1);
+        let printer = device(
+            "1-4    04a9:26b4  Canon MF4010                                                  Not shared",
+        );
+        assert_eq!(
+            bind_confirmations(&policy, &Protection::Allowed, &printer, &[]),
+            Ok(Vec::new())
+        );
+    }
+}
@@ -0,0 +1,374 @@
+// Record and replay of the external commands the manager runs (usbipd, wsl,
+// powershell and the agent), so a user's environment can be captured for a
+// bug report and the same run reproduced elsewhere, e.g. on Linux.
+use crate::agent::{agent_path, AGENT_BINARY};
+use crate::executor;
+use crate::logging;
+use crate::tools::{self, Tool, TOOLS};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Output is kept byte for byte: text when it is valid UTF-8, raw bytes
+// otherwise (wsl.exe's UTF-16, OEM code pages).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Captured {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Captured {
+    fn new(bytes: &[u8]) -> Captured {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Captured::Text(text.to_string()),
+            Err(_) => Captured::Bytes(bytes.to_vec()),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Captured::Text(text) => text.as_bytes().to_vec(),
+            Captured::Bytes(bytes) => bytes.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedCommand {
+    pub tool: String,
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<String>,
+    // Set when the tool could not be started at all.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    pub stdout: Captured,
+    pub stderr: Captured,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Recording {
+    pub app_version: String,
+    pub os: String,
+    pub commands: Vec<RecordedCommand>,
+}
+
+// Commands are answered in recorded order: the first unused entry with the
+// same command line wins, so repeated `usbipd list` calls see the device
+// states change as they did. Once they run out the last one is repeated.
+pub struct Replay {
+    recording: Recording,
+    used: Vec<bool>,
+}
+
+impl Replay {
+    pub fn new(recording: Recording) -> Replay {
+        let used = vec![false; recording.commands.len()];
+        Replay { recording, used }
+    }
+
+    pub fn answer(
+        &mut self,
+        tool: &str,
+        args: &[String],
+        input: Option<&str>,
+    ) -> Option<&RecordedCommand> {
+        let matches = |command: &RecordedCommand| {
+            command.tool == tool && command.args == args && command.input.as_deref() == input
+        };
+        let commands = &self.recording.commands;
+        let index = (0..commands.len())
+            .find(|&i| !self.used[i] && matches(&commands[i]))
+            .or_else(|| (0..commands.len()).rev().find(|&i| matches(&commands[i])))?;
+        self.used[index] = true;
+        Some(&self.recording.commands[index])
+    }
+}
+
+enum Session {
+    Record { path: PathBuf, recording: Recording },
+    Replay(Replay),
+}
+
+static SESSION: Mutex<Option<Session>> = Mutex::new(None);
+
+pub fn start_recording(path: &str) -> Result<(), String> {
+    let recording = Recording {
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        os: std::env::consts::OS.to_string(),
+        commands: Vec::new(),
+    };
+    save(&PathBuf::from(path), &recording)?;
+    *SESSION.lock().unwrap() = Some(Session::Record {
+        path: PathBuf::from(path),
+        recording,
+    });
+    Ok(())
+}
+
+pub fn start_replay(path: &str) -> Result<(), String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Не удалось прочитать запись {path}: {e}"))?;
+    let recording: Recording = serde_json::from_str(&contents)
+        .map_err(|e| format!("Ошибка разбора записи {path}: {e}"))?;
+    *SESSION.lock().unwrap() = Some(Session::Replay(Replay::new(recording)));
+    Ok(())
+}
+
+fn save(path: &PathBuf, recording: &Recording) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(recording)
+        .map_err(|e| format!("Ошибка сериализации записи: {e}"))?;
+    fs::write(path, json)
+        .map_err(|e| format!("Не удалось сохранить запись {}: {e}", path.display()))
+}
+
+// Tool and agent locations differ between machines, so they are written as
+// if everything were found in PATH.
+pub fn canonical_arg(arg: &str) -> String {
+    let agent = agent_path();
+    if arg == agent {
+        return AGENT_BINARY.to_string();
+    }
+    let mut arg = arg.to_string();
+    for tool in TOOLS {
+        let path = tools::shell_path(tool);
+        if path != tool.name() {
+            arg = arg.replace(&path, tool.name());
+        }
+    }
+    arg
+}
+
+pub fn output(tool: Tool, command: &mut Command) -> Result<Output, String> {
+    run(tool, command, None)
+}
+
+// For commands that read a request from stdin (the agent).
+pub fn output_with_input(tool: Tool, command: &mut Command, input: &str) -> Result<Output, String> {
+    run(tool, command, Some(input))
+}
+
+fn run(tool: Tool, command: &mut Command, input: Option<&str>) -> Result<Output, String> {
+    // Canonical arguments take path lookups, so they are only worked out
+    // for a session that records or replays them.
+    let in_session = SESSION.lock().unwrap().is_some();
+    let args: Vec<String> = command
+        .get_args()
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            if in_session {
+                canonical_arg(&arg)
+            } else {
+                arg.to_string()
+            }
+        })
+        .collect();
+
+    if let Some(Session::Replay(replay)) = SESSION.lock().unwrap().as_mut() {
+        let recorded = replay.answer(tool.name(), &args, input).ok_or_else(|| {
+            format!(
+                "Команда отсутствует в записи: {} {}",
+                tool.name(),
+                args.join(" ")
+            )
+        })?;
+        if let Some(error) = &recorded.error {
+            return Err(error.clone());
+        }
+        return Ok(Output {
+            status: exit_status(recorded.exit_code.unwrap_or(-1)),
+            stdout: recorded.stdout.to_bytes(),
+            stderr: recorded.stderr.to_bytes(),
+        });
+    }
+
+    let result = spawn(tool, command, input);
+    if let Some(Session::Record { path, recording }) = SESSION.lock().unwrap().as_mut() {
+        let empty = Captured::Text(String::new());
+        recording.commands.push(RecordedCommand {
+            tool: tool.name().to_string(),
+            args,
+            input: input.map(str::to_string),
+            error: result.as_ref().err().cloned(),
+            exit_code: result.as_ref().ok().and_then(|output| output.status.code()),
+            stdout: result
+                .as_ref()
+                .map_or(empty.clone(), |o| Captured::new(&o.stdout)),
+            stderr: result.as_ref().map_or(empty, |o| Captured::new(&o.stderr)),
+        });
+        // Written after every command so a crash still leaves the record.
+        if let Err(err) = save(path, recording) {
+            logging::error(err);
+        }
+    }
+    result
+}
+
+fn spawn(tool: Tool, command: &mut Command, input: Option<&str>) -> Result<Output, String> {
+    let mut child = command
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| tools::spawn_error(tool, &e))?;
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        if let Err(e) = stdin.write_all(input.as_bytes()) {
+            kill(&mut child);
+            return Err(format!("Не удалось передать данные {}: {e}", tool.name()));
+        }
+    }
+    // Polled so that a hung usbipd or wsl is killed when its operation is
+    // cancelled, times out or the window is closed. Most commands finish in
+    // milliseconds, so the interval starts short.
+    let mut interval = Duration::from_millis(1);
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) => {}
+            Err(e) => {
+                kill(&mut child);
+                return Err(format!("Ошибка ожидания {}: {e}", tool.name()));
+            }
+        }
+        if let Err(err) = executor::check_current() {
+            kill(&mut child);
+            return Err(err);
+        }
+        thread::sleep(interval);
+        interval = (interval * 2).min(MAX_POLL_INTERVAL);
+    };
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+// Both pipes are drained while the child runs so it never blocks on a full
+// pipe.
+fn read_in_background(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        bytes
+    })
+}
+
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[cfg(unix)]
+fn exit_status(code: i32) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    ExitStatus::from_raw((code & 0xff) << 8)
+}
+
+#[cfg(windows)]
+fn exit_status(code: i32) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(code as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::{Executor, ExecutorEvent, Operation, OperationOutcome};
+
+    fn command(args: &[&str], stdout: &str) -> RecordedCommand {
+        RecordedCommand {
+            tool: "usbipd".to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            input: None,
+            error: None,
+            exit_code: Some(0),
+            stdout: Captured::Text(stdout.to_string()),
+            stderr: Captured::Text(String::new()),
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn replays_commands_in_recorded_order() {
+        let mut replay = Replay::new(Recording {
+            commands: vec![
+                command(&["list"], "Not shared"),
+                command(&["attach", "--busid", "2-7"], ""),
+                command(&["list"], "Attached"),
+            ],
+            ..Recording::default()
+        });
+        let mut list = || replay.answer("usbipd", &args(&["list"]), None).cloned();
+        assert_eq!(list().unwrap().stdout, Captured::Text("Not shared".into()));
+        assert_eq!(list().unwrap().stdout, Captured::Text("Attached".into()));
+        assert_eq!(list().unwrap().stdout, Captured::Text("Attached".into()));
+        assert!(replay.answer("usbipd", &args(&["detach"]), None).is_none());
+        assert!(replay.answer("wsl", &args(&["list"]), None).is_none());
+    }
+
+    #[test]
+    fn keeps_non_utf8_output_exact() {
+        let utf16 = [0xFF, 0xFE, b'U', 0x00];
+        let mut recorded = command(&["-l", "-v"], "");
+        recorded.stdout = Captured::new(&utf16);
+        recorded.stderr = Captured::new("ошибка".as_bytes());
+        let json = serde_json::to_string(&recorded).unwrap();
+        assert!(json.contains(r#""stderr":"ошибка""#));
+        let parsed: RecordedCommand = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.stdout.to_bytes(), utf16);
+        assert_eq!(parsed, recorded);
+        assert_eq!(exit_status(1).code(), Some(1));
+    }
+
+    #[test]
+    fn kills_commands_of_timed_out_operations() {
+        let (executor, events) = Executor::new(1, || {});
+        executor.submit(
+            Operation::new("hung", |_| {
+                let mut command = if cfg!(windows) {
+                    let mut ping = Command::new("ping");
+                    ping.args(["-n", "30", "127.0.0.1"]);
+                    ping
+                } else {
+                    let mut sleep = Command::new("sleep");
+                    sleep.arg("30");
+                    sleep
+                };
+                output(Tool::Wsl, &mut command).map(|_| ())
+            })
+            .with_timeout(Duration::from_millis(200)),
+        );
+        let started = std::time::Instant::now();
+        let outcome = loop {
+            if let ExecutorEvent::Finished { outcome, .. } =
+                events.recv_timeout(Duration::from_secs(10)).unwrap()
+            {
+                break outcome;
+            }
+        };
+        assert_eq!(outcome, OperationOutcome::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,289 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::RwLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+    Usbipd,
+    Wsl,
+    Powershell,
+}
+
+impl Tool {
+    pub fn name(self) -> &'static str {
+        match self {
+            Tool::Usbipd => "usbipd",
+            Tool::Wsl => "wsl",
+            Tool::Powershell => "powershell",
+        }
+    }
+
+    pub fn env_var(self) -> &'static str {
+        match self {
+            Tool::Usbipd => "USBIPD_PATH",
+            Tool::Wsl => "USBIPD_WSL_PATH",
+            Tool::Powershell => "USBIPD_POWERSHELL_PATH",
+        }
+    }
+
+    pub fn cli_flag(self) -> &'static str {
+        match self {
+            Tool::Usbipd => "--usbipd",
+            Tool::Wsl => "--wsl-exe",
+            Tool::Powershell => "--powershell",
+        }
+    }
+
+    fn install_hint(self) -> &'static str {
+        match self {
+            Tool::Usbipd => "установите usbipd-win (winget install usbipd)",
+            Tool::Wsl => "установите WSL (wsl --install)",
+            Tool::Powershell => "проверьте установку Windows PowerShell",
+        }
+    }
+
+    // Where the installers put the executable when it is missing from PATH
+    // (e.g. usbipd-win installed for the current session only).
+    fn install_locations(self, var: &impl Fn(&str) -> Option<String>) -> Vec<PathBuf> {
+        let under = |base: &str, path: &str| var(base).map(|base| Path::new(&base).join(path));
+        let locations = match self {
+            Tool::Usbipd => vec![
+                under("ProgramFiles", r"usbipd-win\usbipd.exe"),
+                under("ProgramW6432", r"usbipd-win\usbipd.exe"),
+            ],
+            Tool::Wsl => vec![
+                under("ProgramFiles", r"WSL\wsl.exe"),
+                under("SystemRoot", r"System32\wsl.exe"),
+                under("SystemRoot", r"Sysnative\wsl.exe"),
+            ],
+            Tool::Powershell => vec![
+                under(
+                    "SystemRoot",
+                    r"System32\WindowsPowerShell\v1.0\powershell.exe",
+                ),
+                under("ProgramFiles", r"PowerShell\7\pwsh.exe"),
+            ],
+        };
+        locations.into_iter().flatten().collect()
+    }
+}
+
+pub const TOOLS: [Tool; 3] = [Tool::Usbipd, Tool::Wsl, Tool::Powershell];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolPaths {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usbipd: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wsl: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub powershell: Option<String>,
+}
+
+impl ToolPaths {
+    pub const fn new() -> Self {
+        Self {
+            usbipd: None,
+            wsl: None,
+            powershell: None,
+        }
+    }
+
+    pub fn get(&self, tool: Tool) -> Option<&str> {
+        match tool {
+            Tool::Usbipd => self.usbipd.as_deref(),
+            Tool::Wsl => self.wsl.as_deref(),
+            Tool::Powershell => self.powershell.as_deref(),
+        }
+        .filter(|path| !path.trim().is_empty())
+    }
+
+    pub fn set(&mut self, tool: Tool, path: &str) {
+        let slot = match tool {
+            Tool::Usbipd => &mut self.usbipd,
+            Tool::Wsl => &mut self.wsl,
+            Tool::Powershell => &mut self.powershell,
+        };
+        *slot = Some(path.to_string());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == ToolPaths::default()
+    }
+}
+
+// Paths given on the command line and in config.json. Most of the code runs
+// tools from free functions, so the choice is kept process-wide.
+struct Sources {
+    cli: ToolPaths,
+    config: ToolPaths,
+}
+
+static SOURCES: RwLock<Sources> = RwLock::new(Sources {
+    cli: ToolPaths::new(),
+    config: ToolPaths::new(),
+});
+
+pub fn set_cli_paths(paths: ToolPaths) {
+    SOURCES.write().unwrap().cli = paths;
+}
+
+pub fn set_config_paths(paths: ToolPaths) {
+    SOURCES.write().unwrap().config = paths;
+}
+
+// Where a tool's path came from, for diagnostics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolSource {
+    CommandLine,
+    Environment,
+    Config,
+    Path,
+    InstallLocation,
+}
+
+// Command line, then environment, then config.json win as given (a wrong
+// path should fail loudly, not fall back); otherwise PATH and the usual
+// install locations are searched.
+pub fn find_tool(
+    tool: Tool,
+    cli: &ToolPaths,
+    config: &ToolPaths,
+    var: impl Fn(&str) -> Option<String>,
+) -> Option<(PathBuf, ToolSource)> {
+    if let Some(path) = cli.get(tool) {
+        return Some((PathBuf::from(path), ToolSource::CommandLine));
+    }
+    if let Some(path) = var(tool.env_var()).filter(|path| !path.trim().is_empty()) {
+        return Some((PathBuf::from(path), ToolSource::Environment));
+    }
+    if let Some(path) = config.get(tool) {
+        return Some((PathBuf::from(path), ToolSource::Config));
+    }
+    let file_name = format!("{}{}", tool.name(), env::consts::EXE_SUFFIX);
+    if let Some(path) = var("PATH").and_then(|paths| {
+        env::split_paths(&paths)
+            .map(|dir| dir.join(&file_name))
+            .find(|candidate| candidate.is_file())
+    }) {
+        return Some((path, ToolSource::Path));
+    }
+    tool.install_locations(&var)
+        .into_iter()
+        .find(|candidate| candidate.is_file())
+        .map(|path| (path, ToolSource::InstallLocation))
+}
+
+pub fn locate(tool: Tool) -> Option<(PathBuf, ToolSource)> {
+    let sources = SOURCES.read().unwrap();
+    find_tool(tool, &sources.cli, &sources.config, |name| {
+        env::var(name).ok()
+    })
+}
+
+// Falls back to the bare name so the spawn error names the tool.
+pub fn tool_path(tool: Tool) -> PathBuf {
+    locate(tool)
+        .map(|(path, _)| path)
+        .unwrap_or_else(|| PathBuf::from(tool.name()))
+}
+
+pub fn command(tool: Tool) -> Command {
+    Command::new(tool_path(tool))
+}
+
+// For command lines handed to cmd.exe (elevation, Auto-Attach supervisors).
+pub fn shell_path(tool: Tool) -> String {
+    let path = tool_path(tool).to_string_lossy().to_string();
+    if path.contains(' ') {
+        format!("\"{path}\"")
+    } else {
+        path
+    }
+}
+
+pub fn spawn_error(tool: Tool, err: &io::Error) -> String {
+    if err.kind() == io::ErrorKind::NotFound {
+        format!(
+            "{} не найден ({}): {} или укажите путь в config.json (tools.{}), \
+             переменной окружения {} или параметром {}",
+            tool.name(),
+            tool_path(tool).display(),
+            tool.install_hint(),
+            tool.name(),
+            tool.env_var(),
+            tool.cli_flag()
+        )
+    } else {
+        format!("Не удалось запустить {}: {err}", tool.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+
+    #[test]
+    fn prefers_explicit_paths_over_discovery() {
+        let mut cli = ToolPaths::default();
+        let mut config = ToolPaths::default();
+        config.set(Tool::Usbipd, r"D:\portable\usbipd.exe");
+        config.set(Tool::Wsl, r"D:\portable\wsl.exe");
+        cli.set(Tool::Wsl, "/opt/fake/wsl");
+        let env: HashMap<&str, &str> = [("USBIPD_POWERSHELL_PATH", "/opt/fake/pwsh")].into();
+        let var = |name: &str| env.get(name).map(|value| value.to_string());
+
+        let found = |tool| find_tool(tool, &cli, &config, var).unwrap();
+        assert_eq!(
+            found(Tool::Usbipd),
+            (PathBuf::from(r"D:\portable\usbipd.exe"), ToolSource::Config)
+        );
+        assert_eq!(
+            found(Tool::Wsl),
+            (PathBuf::from("/opt/fake/wsl"), ToolSource::CommandLine)
+        );
+        assert_eq!(
+            found(Tool::Powershell),
+            (PathBuf::from("/opt/fake/pwsh"), ToolSource::Environment)
+        );
+    }
+
+    #[test]
+    fn searches_path_then_install_locations() {
+        let root = env::temp_dir().join(format!("usbipd-gui-tools-{}", std::process::id()));
+        let bin = root.join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        let usbipd = bin.join(format!("usbipd{}", env::consts::EXE_SUFFIX));
+        fs::write(&usbipd, "").unwrap();
+
+        let path = env::join_paths([root.join("missing"), bin.clone()]).unwrap();
+        let path = path.to_string_lossy().to_string();
+        let none = ToolPaths::default();
+        let found = find_tool(Tool::Usbipd, &none, &none, |name| {
+            (name == "PATH").then(|| path.clone())
+        });
+        assert_eq!(found, Some((usbipd, ToolSource::Path)));
+        assert_eq!(find_tool(Tool::Wsl, &none, &none, |_| None), None);
+
+        let program_files = root.join("Program Files");
+        let installed = program_files.join(r"usbipd-win\usbipd.exe");
+        fs::create_dir_all(installed.parent().unwrap()).unwrap();
+        fs::write(&installed, "").unwrap();
+        let program_files = program_files.to_string_lossy().to_string();
+        let var = |name: &str| match name {
+            "PATH" => Some(root.join("missing").to_string_lossy().to_string()),
+            "ProgramFiles" => Some(program_files.clone()),
+            _ => None,
+        };
+        assert_eq!(
+            find_tool(Tool::Usbipd, &none, &none, var),
+            Some((installed, ToolSource::InstallLocation))
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
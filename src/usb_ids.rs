@@ -0,0 +1,317 @@
+// Vendor, product and class names from the usb.ids database, for devices
+// that Windows only calls "USB Input Device" and the like. Only a stub is
+// built in (raw DEFLATE, data/usb.ids.deflate): 18 common vendors and the
+// class names. Other devices are only resolved through a usb.ids in the state
+// directory, e.g. a fresh one from linux-usb.org, which is read on top of it.
+use crate::config::state_dir;
+use crate::logging;
+use crate::usbipd::UsbDevice;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+const USB_IDS_FILE: &str = "usb.ids";
+static EMBEDDED: &[u8] = include_bytes!("../data/usb.ids.deflate");
+
+// Windows class driver names, English and Russian: the product is unknown to
+// Windows, but the name gives away the device class.
+const GENERIC_NAMES: &[(&str, Option<u8>)] = &[
+    ("USB Input Device", Some(0x03)),
+    ("USB-устройство ввода", Some(0x03)),
+    ("USB Mass Storage Device", Some(0x08)),
+    ("Запоминающее устройство для USB", Some(0x08)),
+    ("USB Serial Device", Some(0x02)),
+    ("Последовательное устройство USB", Some(0x02)),
+    ("USB Printing Support", Some(0x07)),
+    ("Поддержка USB принтера", Some(0x07)),
+    ("USB Video Device", Some(0x0e)),
+    ("USB Audio Device", Some(0x01)),
+    ("Generic USB Hub", Some(0x09)),
+    ("Универсальный USB-концентратор", Some(0x09)),
+    ("Microsoft Usbccid Smartcard Reader", Some(0x0b)),
+    ("USB Composite Device", None),
+    ("Составное USB устройство", None),
+    ("Unknown USB Device", None),
+];
+
+#[derive(Debug, Default)]
+pub struct UsbIds {
+    vendors: HashMap<u16, Vendor>,
+    classes: HashMap<u8, String>,
+}
+
+#[derive(Debug, Default)]
+struct Vendor {
+    name: String,
+    products: HashMap<u16, String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub vendor: Option<String>,
+    pub product: Option<String>,
+    pub class: Option<String>,
+}
+
+impl DeviceInfo {
+    // "Logitech, Inc. Unifying Receiver", or just the vendor.
+    pub fn name(&self) -> Option<String> {
+        match (&self.vendor, &self.product) {
+            (Some(vendor), Some(product)) => Some(format!("{vendor} {product}")),
+            (Some(vendor), None) => Some(vendor.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl UsbIds {
+    // Later entries win, so a newer file can be read over an older one.
+    // Interfaces, subclasses and the sections after the classes are skipped.
+    pub fn parse(&mut self, text: &str) {
+        let mut vendor = None;
+        for line in text.lines() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(product) = line.strip_prefix('\t') {
+                if product.starts_with('\t') {
+                    continue;
+                }
+                if let (Some(vendor), Some((id, name))) = (vendor, split_entry(product)) {
+                    if let Ok(id) = u16::from_str_radix(id, 16) {
+                        let vendor: &mut Vendor = self.vendors.entry(vendor).or_default();
+                        vendor.products.insert(id, name.to_string());
+                    }
+                }
+                continue;
+            }
+            vendor = None;
+            if let Some(class) = line.strip_prefix("C ") {
+                if let Some((id, name)) = split_entry(class) {
+                    if let Ok(id) = u8::from_str_radix(id, 16) {
+                        self.classes.insert(id, name.to_string());
+                    }
+                }
+                continue;
+            }
+            let Some((id, name)) = split_entry(line) else {
+                continue;
+            };
+            if id.len() != 4 {
+                continue;
+            }
+            if let Ok(id) = u16::from_str_radix(id, 16) {
+                self.vendors.entry(id).or_default().name = name.to_string();
+                vendor = Some(id);
+            }
+        }
+    }
+
+    pub fn describe(&self, device: &UsbDevice) -> DeviceInfo {
+        let (vendor, product) = match parse_vid_pid(&device.vid_pid) {
+            Some((vid, pid)) => match self.vendors.get(&vid) {
+                Some(vendor) => (
+                    Some(vendor.name.clone()).filter(|name| !name.is_empty()),
+                    vendor.products.get(&pid).cloned(),
+                ),
+                None => (None, None),
+            },
+            None => (None, None),
+        };
+        let names: Vec<&str> = class_codes(device)
+            .iter()
+            .filter_map(|code| self.classes.get(code).map(String::as_str))
+            .collect();
+        let class = Some(names.join(", ")).filter(|names| !names.is_empty());
+        DeviceInfo {
+            vendor,
+            product,
+            class,
+        }
+    }
+}
+
+// "046d  Logitech, Inc." -> ("046d", "Logitech, Inc.")
+fn split_entry(line: &str) -> Option<(&str, &str)> {
+    let (id, name) = line.split_once("  ")?;
+    Some((id.trim(), name.trim())).filter(|(id, name)| !id.is_empty() && !name.is_empty())
+}
+
+fn parse_vid_pid(vid_pid: &str) -> Option<(u16, u16)> {
+    let (vid, pid) = vid_pid.split_once(':')?;
+    Some((
+        u16::from_str_radix(vid, 16).ok()?,
+        u16::from_str_radix(pid, 16).ok()?,
+    ))
+}
+
+// `Some` for a generic Windows name, with the classes the name tells. usbipd
+// joins the names of a composite device's interfaces with ", " ("USB Input
+// Device, USB Input Device"), and the COM port is appended to serial devices:
+// "USB Serial Device (COM3)".
+fn generic_classes(device_name: &str) -> Option<Vec<u8>> {
+    let mut classes = Vec::new();
+    for part in device_name.split(", ") {
+        let (_, class) = GENERIC_NAMES.iter().find(|(name, _)| {
+            part == *name
+                || part
+                    .strip_prefix(name)
+                    .is_some_and(|rest| rest.starts_with(" ("))
+        })?;
+        classes.extend(*class);
+    }
+    Some(classes)
+}
+
+static USER_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+static DATABASE: OnceLock<UsbIds> = OnceLock::new();
+
+pub fn default_user_ids_path() -> Option<PathBuf> {
+    state_dir().map(|dir| dir.join(USB_IDS_FILE))
+}
+
+// Where the full database is expected, if it can be anywhere.
+pub fn user_file() -> Option<PathBuf> {
+    USER_FILE.lock().unwrap().clone()
+}
+
+// The database is only read on the first lookup.
+pub fn start(path: Option<PathBuf>) {
+    *USER_FILE.lock().unwrap() = path;
+}
+
+fn database() -> &'static UsbIds {
+    DATABASE.get_or_init(|| {
+        let mut ids = UsbIds::default();
+        match miniz_oxide::inflate::decompress_to_vec(EMBEDDED) {
+            Ok(text) => ids.parse(&String::from_utf8_lossy(&text)),
+            Err(e) => logging::warn(format!("Встроенный usb.ids повреждён: {e:?}")),
+        }
+        if let Some(path) = USER_FILE.lock().unwrap().clone() {
+            match fs::read(&path) {
+                // Current copies are UTF-8; stray bytes in older ones are replaced.
+                Ok(bytes) => ids.parse(&String::from_utf8_lossy(&bytes)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => logging::warn(format!("Не удалось прочитать {}: {e}", path.display())),
+            }
+        }
+        ids
+    })
+}
+
+pub fn describe(device: &UsbDevice) -> DeviceInfo {
+    database().describe(device)
+}
+
+// The interface classes Windows reported, or else what the name tells; a
+// composite device usually has several.
+pub fn class_codes(device: &UsbDevice) -> Vec<u8> {
+    let mut codes = device.classes.clone();
+    codes.extend(generic_classes(&device.device_name).unwrap_or_default());
+    codes.sort();
+    codes.dedup();
+    codes
+}
+
+// What the list shows instead of a generic Windows name.
+pub fn display_name(device: &UsbDevice) -> String {
+    if generic_classes(&device.device_name).is_none() {
+        return device.device_name.clone();
+    }
+    match describe(device).name() {
+        Some(name) => format!("{name} ({})", device.device_name),
+        None => device.device_name.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbipd::parse_usbipd_line;
+
+    fn device(line: &str) -> UsbDevice {
+        parse_usbipd_line(line).unwrap()
+    }
+
+    #[test]
+    fn resolves_names_and_class_from_the_embedded_copy() {
+        let receiver = device(
+            "2-9    046D:C52B  USB Input Device                                              Not shared",
+        );
+        let info = describe(&receiver);
+        assert_eq!(
+            info.name().as_deref(),
+            Some("Logitech, Inc. Unifying Receiver")
+        );
+        assert_eq!(info.class.as_deref(), Some("Human Interface Device"));
+        let converter = device(
+            "2-9    1a86:7523  USB Serial Device (COM5)                                      Not shared",
+        );
+        assert_eq!(
+            display_name(&converter),
+            "QinHeng Electronics CH340 serial converter (USB Serial Device (COM5))"
+        );
+        // Specific names and unknown devices are left alone.
+        let reader = device(
+            "2-9    058f:9540  Alcorlink USB Smart Card Reader                               Not shared",
+        );
+        assert_eq!(display_name(&reader), "Alcorlink USB Smart Card Reader");
+        let unknown = device(
+            "2-9    ffff:0001  USB Input Device                                              Not shared",
+        );
+        assert_eq!(display_name(&unknown), "USB Input Device");
+        let unknown = device(
+            "2-9    ffff:0001  USB-устройство ввода                                          Not shared",
+        );
+        assert_eq!(class_codes(&unknown), [0x03]);
+    }
+
+    #[test]
+    fn composite_devices_get_the_classes_of_their_interfaces() {
+        let receiver = device(
+            "2-9    046d:c52b  USB Input Device, USB Input Device                            Not shared",
+        );
+        assert_eq!(class_codes(&receiver), [0x03]);
+        assert_eq!(
+            display_name(&receiver),
+            "Logitech, Inc. Unifying Receiver (USB Input Device, USB Input Device)"
+        );
+        let headset = UsbDevice {
+            classes: vec![0x01, 0x03],
+            ..device(
+                "2-9    046d:0a44  USB Composite Device                                          Not shared",
+            )
+        };
+        assert_eq!(class_codes(&headset), [0x01, 0x03]);
+        assert_eq!(
+            describe(&headset).class.as_deref(),
+            Some("Audio, Human Interface Device")
+        );
+    }
+
+    #[test]
+    fn newer_file_overrides_and_extends() {
+        let mut ids = UsbIds::default();
+        ids.parse("1a86  QinHeng\n\t7523  CH340\n\t\t00  interface\nC 03  HID\n");
+        ids.parse(
+            "# newer\n1a86  QinHeng Electronics\n\t55d4  CH9102 Serial\n\
+             C 03  Human Interface Device\n\t01  Boot Interface Subclass\n\
+             AT 0100  USB Undefined\n",
+        );
+        let converter = device(
+            "2-9    1a86:7523  USB Input Device                                              Not shared",
+        );
+        let info = ids.describe(&converter);
+        assert_eq!(info.vendor.as_deref(), Some("QinHeng Electronics"));
+        assert_eq!(info.product.as_deref(), Some("CH340"));
+        assert_eq!(info.class.as_deref(), Some("Human Interface Device"));
+        let newer = device(
+            "2-9    1a86:55d4  CH9102                                                        Not shared",
+        );
+        let info = ids.describe(&newer);
+        assert_eq!(info.product.as_deref(), Some("CH9102 Serial"));
+        assert_eq!(info.class, None);
+        assert_eq!(ids.vendors.len(), 1);
+    }
+}
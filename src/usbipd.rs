@@ -1,14 +1,39 @@
-use std::process::Command;
-use std::str;
+use crate::encoding::decode_output;
+use crate::logging;
+use crate::session;
+use crate::tools::{self, Tool};
+use crate::usb_ids;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use usbipd_agent::sysfs::DeviceNode;
 
 pub const KNOWN_STATES: &[&str] = &["Shared (forced)", "Not shared", "Attached", "Shared"];
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct UsbDevice {
     pub bus_id: String,
     pub vid_pid: String,
     pub device_name: String,
     pub state: String,
+    pub serial: Option<String>,
+    pub nodes: Vec<DeviceNode>,
+    // USB class codes of the device's interfaces, from the Windows device
+    // tree; empty when unknown.
+    pub classes: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct UsbipdState {
+    devices: Vec<UsbipdStateDevice>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct UsbipdStateDevice {
+    bus_id: Option<String>,
+    instance_id: Option<String>,
 }
 
 pub fn parse_usbipd_list(output: &str) -> Vec<UsbDevice> {
@@ -46,27 +71,72 @@ pub fn parse_usbipd_line(line: &str) -> Option<UsbDevice> {
         vid_pid,
         device_name,
         state,
+        serial: None,
+        nodes: Vec::new(),
+        classes: Vec::new(),
     })
 }
 
-pub fn format_device_display(device: &UsbDevice, auto_attach: bool) -> String {
+// Windows instance ids look like `USB\VID_058F&PID_9540\<serial>`; devices
+// without a serial get a generated segment containing '&' instead.
+pub fn serial_from_instance_id(instance_id: &str) -> Option<String> {
+    let serial = instance_id.rsplit('\\').next()?;
+    if serial.is_empty() || serial.contains('&') || !instance_id.contains('\\') {
+        None
+    } else {
+        Some(serial.to_string())
+    }
+}
+
+pub fn parse_usbipd_state(output: &str) -> Result<HashMap<String, String>, String> {
+    let state: UsbipdState = serde_json::from_str(output)
+        .map_err(|e| format!("Ошибка разбора вывода usbipd state: {e}"))?;
+    Ok(state
+        .devices
+        .into_iter()
+        .filter_map(|device| {
+            let serial = serial_from_instance_id(device.instance_id.as_deref()?)?;
+            Some((device.bus_id?, serial))
+        })
+        .collect())
+}
+
+pub fn format_device_display(
+    device: &UsbDevice,
+    nickname: Option<&str>,
+    tags: &[String],
+    auto_attach: bool,
+) -> String {
+    let device_name = usb_ids::display_name(device);
+    let mut name = match nickname {
+        Some(nickname) => format!("{nickname} ({device_name})"),
+        None => device_name,
+    };
+    for tag in tags {
+        name.push_str(&format!(" #{tag}"));
+    }
+    let nodes = if device.nodes.is_empty() {
+        String::new()
+    } else {
+        let paths: Vec<&str> = device.nodes.iter().map(|node| node.path.as_str()).collect();
+        format!(" → {}", paths.join(", "))
+    };
     if auto_attach {
         format!(
-            "{}: {} [{}] [Auto-Attach]",
-            device.bus_id, device.device_name, device.state
+            "{}: {name}{nodes} [{}] [Auto-Attach]",
+            device.bus_id, device.state
         )
     } else {
-        format!(
-            "{}: {} [{}]",
-            device.bus_id, device.device_name, device.state
-        )
+        format!("{}: {name}{nodes} [{}]", device.bus_id, device.state)
     }
 }
 
+#[cfg_attr(not(windows), allow(dead_code))]
 pub fn extract_bus_id(display_text: &str) -> Option<String> {
     display_text.split(": ").next().map(str::to_string)
 }
 
+#[cfg_attr(not(windows), allow(dead_code))]
 pub fn extract_state_from_display(display_text: &str) -> Option<String> {
     let text = display_text.trim_end();
     let text = text.strip_suffix(" [Auto-Attach]").unwrap_or(text);
@@ -76,15 +146,141 @@ pub fn extract_state_from_display(display_text: &str) -> Option<String> {
 }
 
 pub fn fetch_usb_devices() -> Result<Vec<UsbDevice>, String> {
-    let output = Command::new("usbipd")
-        .arg("list")
-        .output()
-        .map_err(|e| format!("Ошибка выполнения usbipd list: {e}"))?;
+    let output = session::output(Tool::Usbipd, tools::command(Tool::Usbipd).arg("list"))?;
+
+    Ok(parse_usbipd_list(&decode_output(&output.stdout)))
+}
+
+pub fn fetch_device_serials() -> Result<HashMap<String, String>, String> {
+    let output = session::output(Tool::Usbipd, tools::command(Tool::Usbipd).arg("state"))?;
+    if !output.status.success() {
+        return Err(format!("usbipd state завершился с кодом {}", output.status));
+    }
+    parse_usbipd_state(&decode_output(&output.stdout))
+}
 
-    let output_str = str::from_utf8(&output.stdout)
-        .map_err(|e| format!("Ошибка декодирования вывода usbipd: {e}"))?;
+// `usbipd state` is optional (older usbipd versions lack it), so a failure
+// just leaves the serials empty; the same goes for the interface classes.
+pub fn fetch_usb_devices_with_serials() -> Result<Vec<UsbDevice>, String> {
+    let mut devices = fetch_usb_devices()?;
+    if let Ok(serials) = fetch_device_serials() {
+        for device in &mut devices {
+            device.serial = serials.get(&device.bus_id).cloned();
+        }
+    }
+    let classes = interface_classes(&devices);
+    for device in &mut devices {
+        device.classes = classes
+            .get(&device.vid_pid.to_lowercase())
+            .cloned()
+            .unwrap_or_default();
+    }
+    Ok(devices)
+}
 
-    Ok(parse_usbipd_list(output_str))
+// Windows setup classes of interface and HID collection devices, by the USB
+// class they stand for.
+const SETUP_CLASSES: &[(&str, u8)] = &[
+    ("HIDClass", 0x03),
+    ("Keyboard", 0x03),
+    ("Mouse", 0x03),
+    ("Ports", 0x02),
+    ("Modem", 0x02),
+    ("Printer", 0x07),
+    ("Image", 0x06),
+    ("SmartCardReader", 0x0b),
+    ("Camera", 0x0e),
+    ("Media", 0x01),
+];
+
+// Lines of `<instance id>|<setup class>` for the USB and HID devices that are
+// present: `USB\VID_046D&PID_C52B&MI_00\...|HIDClass`.
+const PNP_QUERY: &str = "Get-PnpDevice -PresentOnly -ErrorAction SilentlyContinue | \
+    Where-Object { $_.InstanceId -match '^(USB|HID)\\\\VID_' } | \
+    ForEach-Object { $_.InstanceId + '|' + $_.Class }";
+
+// Classes by lowercase VID:PID.
+pub fn parse_interface_classes(output: &str) -> HashMap<String, Vec<u8>> {
+    let mut classes: HashMap<String, Vec<u8>> = HashMap::new();
+    for line in output.lines() {
+        let Some((instance_id, setup_class)) = line.trim().rsplit_once('|') else {
+            continue;
+        };
+        let Some(vid_pid) = vid_pid_from_instance_id(instance_id) else {
+            continue;
+        };
+        let entry = classes.entry(vid_pid).or_default();
+        if let Some((_, code)) = SETUP_CLASSES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(setup_class.trim()))
+        {
+            if !entry.contains(code) {
+                entry.push(*code);
+                entry.sort();
+            }
+        }
+    }
+    classes
+}
+
+// `USB\VID_046D&PID_C52B&MI_00\7&...` -> "046d:c52b"
+fn vid_pid_from_instance_id(instance_id: &str) -> Option<String> {
+    let upper = instance_id.to_uppercase();
+    let vid = upper.split("VID_").nth(1)?.get(..4)?;
+    let pid = upper.split("PID_").nth(1)?.get(..4)?;
+    Some(format!("{vid}:{pid}").to_lowercase())
+}
+
+// Classes do not change for a VID:PID, so PowerShell (slow to start) only
+// runs when a model that was not looked up yet shows up.
+static INTERFACE_CLASSES: Mutex<Option<HashMap<String, Vec<u8>>>> = Mutex::new(None);
+
+fn interface_classes(devices: &[UsbDevice]) -> HashMap<String, Vec<u8>> {
+    let mut cache = INTERFACE_CLASSES.lock().unwrap();
+    let known = cache.get_or_insert_with(HashMap::new);
+    let missing: Vec<String> = devices
+        .iter()
+        .map(|device| device.vid_pid.to_lowercase())
+        .filter(|vid_pid| !known.contains_key(vid_pid))
+        .collect();
+    if missing.is_empty() {
+        return known.clone();
+    }
+    let output = session::output(
+        Tool::Powershell,
+        tools::command(Tool::Powershell).args([
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            PNP_QUERY,
+        ]),
+    );
+    match output {
+        Ok(output) if output.status.success() => {
+            known.extend(parse_interface_classes(&decode_output(&output.stdout)))
+        }
+        Ok(output) => logging::warn(format!(
+            "Не удалось получить классы устройств (Get-PnpDevice): код {}",
+            output.status
+        )),
+        Err(err) => logging::warn(format!("Не удалось получить классы устройств: {err}")),
+    }
+    // Not asked again, even if Windows knew nothing about them.
+    for vid_pid in missing {
+        known.entry(vid_pid).or_default();
+    }
+    known.clone()
+}
+
+pub fn usbipd_version() -> Result<String, String> {
+    let output = session::output(Tool::Usbipd, tools::command(Tool::Usbipd).arg("--version"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "usbipd --version завершился с кодом {}",
+            output.status
+        ));
+    }
+    Ok(decode_output(&output.stdout).trim().to_string())
 }
 
 pub fn get_device_state(bus_id: &str) -> Result<Option<String>, String> {
@@ -95,16 +291,13 @@ pub fn get_device_state(bus_id: &str) -> Result<Option<String>, String> {
 }
 
 pub fn run_usbipd_command(args: &[&str]) -> Result<(), String> {
-    let output = Command::new("usbipd")
-        .args(args)
-        .output()
-        .map_err(|e| format!("Не удалось запустить usbipd: {e}"))?;
+    let output = session::output(Tool::Usbipd, tools::command(Tool::Usbipd).args(args))?;
 
     if output.status.success() {
         Ok(())
     } else {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = decode_output(&output.stderr);
+        let stdout = decode_output(&output.stdout);
         let message = if stderr.trim().is_empty() {
             stdout.trim().to_string()
         } else {
@@ -123,15 +316,20 @@ pub fn run_elevated_usbipd_command(command: &str) -> Result<(), String> {
         "Start-Process -FilePath 'cmd.exe' -ArgumentList '/C {command}' -Verb RunAs -Wait -WindowStyle Hidden"
     );
 
-    let output = Command::new("powershell")
-        .args(["-NoProfile", "-NonInteractive", "-Command", &ps_command])
-        .output()
-        .map_err(|e| format!("Не удалось запустить elevated-команду: {e}"))?;
+    let output = session::output(
+        Tool::Powershell,
+        tools::command(Tool::Powershell).args([
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            &ps_command,
+        ]),
+    )?;
 
     if output.status.success() {
         Ok(())
     } else {
-        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr = decode_output(&output.stderr);
         Err(format!(
             "Не удалось выполнить команду с правами администратора: {}",
             stderr.trim()
@@ -139,12 +337,19 @@ pub fn run_elevated_usbipd_command(command: &str) -> Result<(), String> {
     }
 }
 
-pub fn run_usbipd_bind(bus_id: &str) -> Result<(), String> {
-    run_elevated_usbipd_command(&format!("usbipd bind --busid {bus_id} --force"))
+pub fn run_usbipd_bind(bus_id: &str, force: bool) -> Result<(), String> {
+    let force = if force { " --force" } else { "" };
+    run_elevated_usbipd_command(&format!(
+        "{} bind --busid {bus_id}{force}",
+        tools::shell_path(Tool::Usbipd)
+    ))
 }
 
 pub fn run_usbipd_unbind(bus_id: &str) -> Result<(), String> {
-    run_elevated_usbipd_command(&format!("usbipd unbind --busid {bus_id}"))
+    run_elevated_usbipd_command(&format!(
+        "{} unbind --busid {bus_id}",
+        tools::shell_path(Tool::Usbipd)
+    ))
 }
 
 pub fn run_usbipd_attach(bus_id: &str, wsl_distro: &str) -> Result<(), String> {
@@ -155,20 +360,26 @@ pub fn run_usbipd_detach(bus_id: &str) -> Result<(), String> {
     run_usbipd_command(&["detach", "--busid", bus_id])
 }
 
+#[cfg(windows)]
 pub fn attach_auto_command(bus_id: &str, wsl_distro: &str) -> String {
-    format!("usbipd attach --wsl {wsl_distro} --busid {bus_id} --auto-attach")
+    format!(
+        "{} attach --wsl {wsl_distro} --busid {bus_id} --auto-attach",
+        tools::shell_path(Tool::Usbipd)
+    )
 }
 
 pub fn is_bindable_state(state: &str) -> bool {
     state == "Not shared" || state == "Unknown"
 }
 
+#[cfg(windows)]
 pub fn is_unbindable_state(state: &str) -> bool {
     matches!(state, "Shared" | "Attached" | "Shared (forced)")
 }
 
+#[cfg(windows)]
 pub fn is_auto_attachable_state(state: &str) -> bool {
-    state == "Shared"
+    matches!(state, "Shared" | "Shared (forced)")
 }
 
 #[cfg(test)]
@@ -216,10 +427,70 @@ GUID                                  DEVICE
             vid_pid: "058f:9540".to_string(),
             device_name: "Reader".to_string(),
             state: "Not shared".to_string(),
+            serial: None,
+            nodes: Vec::new(),
+            classes: Vec::new(),
         };
         assert_eq!(
-            format_device_display(&device, true),
+            format_device_display(&device, None, &[], true),
             "2-7: Reader [Not shared] [Auto-Attach]"
         );
+        assert_eq!(
+            format_device_display(
+                &device,
+                Some("Ридер [бухгалтерия]"),
+                &["склад".to_string(), "эцп".to_string()],
+                false
+            ),
+            "2-7: Ридер [бухгалтерия] (Reader) #склад #эцп [Not shared]"
+        );
+    }
+
+    #[test]
+    fn formats_device_nodes_before_state() {
+        let device = UsbDevice {
+            bus_id: "2-10".to_string(),
+            vid_pid: "2912:0008".to_string(),
+            device_name: "ATOL USB (COM4)".to_string(),
+            state: "Attached".to_string(),
+            serial: None,
+            nodes: vec![DeviceNode {
+                kind: usbipd_agent::sysfs::NodeKind::Tty,
+                path: "/dev/ttyACM0".to_string(),
+            }],
+            classes: Vec::new(),
+        };
+        let display = format_device_display(&device, None, &[], false);
+        assert_eq!(display, "2-10: ATOL USB (COM4) → /dev/ttyACM0 [Attached]");
+        assert_eq!(
+            extract_state_from_display(&display),
+            Some("Attached".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_serials_from_usbipd_state() {
+        let output = r#"{
+  "Devices": [
+    {"BusId": "2-7", "InstanceId": "USB\\VID_058F&PID_9540\\AU9540-0001", "IsForced": false},
+    {"BusId": "2-9", "InstanceId": "USB\\VID_04A9&PID_26B4\\5&2C5F2D4C&0&9", "IsForced": false},
+    {"BusId": null, "InstanceId": "USB\\VID_1A2C&PID_2124\\ABC", "IsForced": false}
+  ]
+}"#;
+        let serials = parse_usbipd_state(output).unwrap();
+        assert_eq!(serials.len(), 1);
+        assert_eq!(serials["2-7"], "AU9540-0001");
+    }
+
+    #[test]
+    fn reads_interface_classes_from_the_device_tree() {
+        let output = "USB\\VID_046D&PID_C52B\\5&2A1B&0&3|USB\r\n\
+                      USB\\VID_046D&PID_C52B&MI_00\\6&1F&0&0000|HIDClass\r\n\
+                      HID\\VID_046D&PID_C52B&MI_00\\7&2B&0&0000|Keyboard\r\n\
+                      USB\\VID_1A86&PID_7523\\5&3C&0&4|Ports\r\n";
+        let classes = parse_interface_classes(output);
+        assert_eq!(classes["046d:c52b"], [0x03]);
+        assert_eq!(classes["1a86:7523"], [0x02]);
+        assert_eq!(vid_pid_from_instance_id("ROOT\\HTREE\\ROOT\\0"), None);
     }
 }
@@ -0,0 +1,269 @@
+use crate::config::Config;
+use crate::logging;
+use crate::usbipd::UsbDevice;
+use crate::wsl::{find_distro, keep_alive_command, WslDistro, WslState};
+use std::collections::{HashMap, HashSet};
+use std::process::{Child, Stdio};
+
+// Remembers which distro each device was seen attached to, so a device that
+// WSL dropped when its distro shut down can be attached again. Devices with
+// Auto-Attach are left to the usbipd supervisor.
+#[derive(Default)]
+pub struct AttachWatcher {
+    attached: HashMap<String, String>,
+    stopped: HashSet<String>,
+}
+
+impl AttachWatcher {
+    // Called when the user detaches or moves a device on purpose.
+    pub fn forget(&mut self, bus_id: &str) {
+        self.attached.remove(bus_id);
+    }
+
+    pub fn distro_stopped(&mut self, wsl_distro: &str) {
+        self.stopped.insert(wsl_distro.to_string());
+    }
+
+    // Returns the devices to attach again, with the distro each was attached
+    // to. A device that merely went from Attached to Shared while its distro
+    // kept running was detached by someone else and is not touched.
+    //
+    // usbipd does not say which distro a device is attached to, so when a
+    // device shows up attached `attach_target` is asked where the attach went
+    // (`--distro` and moves included); the configured distro is only a guess
+    // for when it cannot tell.
+    pub fn update(
+        &mut self,
+        devices: &[UsbDevice],
+        config: &Config,
+        distros: &[WslDistro],
+        attach_target: impl Fn(&UsbDevice) -> Option<String>,
+    ) -> Vec<(UsbDevice, String)> {
+        for distro in distros.iter().filter(|d| d.state != WslState::Running) {
+            self.stopped.insert(distro.name.clone());
+        }
+
+        let mut lost = Vec::new();
+        let mut previous = std::mem::take(&mut self.attached);
+        for device in devices {
+            let settings = config.settings_for(device);
+            if device.state == "Attached" {
+                let wsl_distro = previous
+                    .remove(&device.bus_id)
+                    .or_else(|| attach_target(device))
+                    .unwrap_or(settings.wsl_distro);
+                self.attached.insert(device.bus_id.clone(), wsl_distro);
+                continue;
+            }
+            let Some(wsl_distro) = previous.get(&device.bus_id) else {
+                continue;
+            };
+            let shut_down = self
+                .stopped
+                .iter()
+                .any(|stopped| stopped.eq_ignore_ascii_case(wsl_distro));
+            if shut_down && !settings.auto_attach && device.state.starts_with("Shared") {
+                lost.push((device.clone(), wsl_distro.clone()));
+            }
+        }
+        self.stopped.clear();
+        lost
+    }
+
+    // Distros that currently have at least one attached device.
+    pub fn attached_distros(&self) -> Vec<String> {
+        let mut distros: Vec<String> = self.attached.values().cloned().collect();
+        distros.sort();
+        distros.dedup();
+        distros
+    }
+}
+
+// One idle session per distro with attached devices.
+#[derive(Default)]
+pub struct KeepAlive {
+    sessions: HashMap<String, Child>,
+}
+
+impl KeepAlive {
+    // Sessions that ended on their own mean the distro was shut down
+    // (`wsl --shutdown`, `wsl -t`); they are returned and dropped.
+    pub fn take_exited(&mut self) -> Vec<String> {
+        let exited: Vec<String> = self
+            .sessions
+            .iter_mut()
+            .filter_map(|(distro, child)| match child.try_wait() {
+                Ok(None) => None,
+                _ => Some(distro.clone()),
+            })
+            .collect();
+        for distro in &exited {
+            self.sessions.remove(distro);
+        }
+        exited
+    }
+
+    pub fn update(&mut self, wanted: &[String], distros: &[WslDistro]) {
+        let unwanted: Vec<String> = self
+            .sessions
+            .keys()
+            .filter(|distro| !wanted.contains(distro))
+            .cloned()
+            .collect();
+        for distro in unwanted {
+            self.stop(&distro);
+        }
+
+        for distro in wanted {
+            // Starting a session would boot a stopped distro; that is left
+            // to the re-attach, which knows whether the devices need it.
+            let running =
+                find_distro(distros, distro).is_some_and(|found| found.state == WslState::Running);
+            if self.sessions.contains_key(distro) || !running {
+                continue;
+            }
+            match keep_alive_command(distro)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+            {
+                Ok(child) => {
+                    logging::info(format!("Поддержание {distro} запущенным"));
+                    self.sessions.insert(distro.clone(), child);
+                }
+                Err(e) => logging::warn(format!("Не удалось удерживать {distro} запущенным: {e}")),
+            }
+        }
+    }
+
+    fn stop(&mut self, wsl_distro: &str) {
+        if let Some(mut child) = self.sessions.remove(wsl_distro) {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    pub fn shutdown(&mut self) {
+        let distros: Vec<String> = self.sessions.keys().cloned().collect();
+        for distro in distros {
+            self.stop(&distro);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbipd::parse_usbipd_list;
+    use crate::wsl::parse_wsl_list_verbose;
+
+    fn config() -> Config {
+        serde_json::from_str(
+            r#"{
+                "auto_attach_devices": ["2-9"],
+                "wsl_distro": "Ubuntu",
+                "devices": {"2-11": {"wsl_distro": "Debian"}}
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn devices(states: [&str; 3]) -> Vec<UsbDevice> {
+        parse_usbipd_list(&format!(
+            "Connected:\n\
+             BUSID  VID:PID    DEVICE                                                        STATE\n\
+             2-7    058f:9540  Alcorlink USB Smart Card Reader                               {}\n\
+             2-9    04a9:26b4  Canon MF4010 Series                                           {}\n\
+             2-11   0403:6001  USB Serial Converter                                          {}\n",
+            states[0], states[1], states[2]
+        ))
+    }
+
+    #[test]
+    fn reattaches_devices_dropped_by_distro_shutdown() {
+        let config = config();
+        let running = parse_wsl_list_verbose("* Ubuntu Running 2\n  Debian Running 2\n");
+        let mut watcher = AttachWatcher::default();
+        let attached = devices(["Attached", "Attached", "Attached"]);
+        assert!(watcher
+            .update(&attached, &config, &running, |_| None)
+            .is_empty());
+        assert_eq!(watcher.attached_distros(), ["Debian", "Ubuntu"]);
+
+        // Ubuntu went down: 2-7 comes back, 2-9 has its own supervisor and the
+        // Debian device was detached by hand while Debian kept running.
+        let ubuntu_stopped = parse_wsl_list_verbose("* Ubuntu Stopped 2\n  Debian Running 2\n");
+        let lost = watcher.update(
+            &devices(["Shared", "Shared", "Shared"]),
+            &config,
+            &ubuntu_stopped,
+            |_| None,
+        );
+        let lost: Vec<(&str, &str)> = lost
+            .iter()
+            .map(|(device, distro)| (device.bus_id.as_str(), distro.as_str()))
+            .collect();
+        assert_eq!(lost, [("2-7", "Ubuntu")]);
+
+        // Already handled: nothing is reported twice.
+        let shared = devices(["Shared", "Shared", "Shared"]);
+        assert!(watcher
+            .update(&shared, &config, &ubuntu_stopped, |_| None)
+            .is_empty());
+    }
+
+    #[test]
+    fn trusts_keep_alive_over_a_restarted_distro() {
+        let config = config();
+        let running = parse_wsl_list_verbose("* Ubuntu Running 2\n  Debian Running 2\n");
+        let mut watcher = AttachWatcher::default();
+        watcher.update(
+            &devices(["Attached", "Shared", "Attached"]),
+            &config,
+            &running,
+            |_| None,
+        );
+
+        // The keep-alive session saw Debian die although it is running again
+        // by the time the device list is read.
+        watcher.distro_stopped("Debian");
+        watcher.forget("2-7");
+        let lost = watcher.update(
+            &devices(["Shared", "Shared", "Shared"]),
+            &config,
+            &running,
+            |_| None,
+        );
+        let lost: Vec<&str> = lost.iter().map(|(d, _)| d.bus_id.as_str()).collect();
+        assert_eq!(lost, ["2-11"]);
+    }
+
+    #[test]
+    fn tracks_the_distro_an_attach_targeted() {
+        let config = config();
+        let running = parse_wsl_list_verbose("* Ubuntu Running 2\n  Debian Running 2\n");
+        let mut watcher = AttachWatcher::default();
+        // 2-7 was attached with --distro Debian although Ubuntu is configured.
+        let target = |device: &UsbDevice| (device.bus_id == "2-7").then(|| "Debian".to_string());
+        let attached = devices(["Attached", "Shared", "Shared"]);
+        watcher.update(&attached, &config, &running, target);
+        assert_eq!(watcher.attached_distros(), ["Debian"]);
+
+        // Ubuntu going down does not concern it and leaves it attached.
+        let ubuntu_stopped = parse_wsl_list_verbose("* Ubuntu Stopped 2\n  Debian Running 2\n");
+        assert!(watcher
+            .update(&attached, &config, &ubuntu_stopped, |_| None)
+            .is_empty());
+
+        // Debian going down does.
+        let debian_stopped = parse_wsl_list_verbose("* Ubuntu Running 2\n  Debian Stopped 2\n");
+        let shared = devices(["Shared", "Shared", "Shared"]);
+        let lost = watcher.update(&shared, &config, &debian_stopped, |_| None);
+        let lost: Vec<(&str, &str)> = lost
+            .iter()
+            .map(|(device, distro)| (device.bus_id.as_str(), distro.as_str()))
+            .collect();
+        assert_eq!(lost, [("2-7", "Debian")]);
+    }
+}
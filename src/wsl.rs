@@ -0,0 +1,393 @@
+use crate::agent;
+use crate::encoding::decode_output;
+use crate::session;
+use crate::tools::{self, Tool};
+use crate::usbipd::UsbDevice;
+use std::process::Command;
+use usbipd_agent::procfs::{Holder, Mount};
+use usbipd_agent::protocol::{Request, Response};
+use usbipd_agent::sysfs::{parse_dump, SysfsSnapshot, SysfsUsbDevice, DUMP_SCRIPT};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttachCheck {
+    Present(SysfsUsbDevice),
+    Missing { vhci_loaded: bool },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WslState {
+    Running,
+    Stopped,
+    Installing,
+    Converting,
+    Uninstalling,
+    Other(String),
+}
+
+impl WslState {
+    // `wsl -l -v` prints the state localized along with the rest of the table.
+    fn parse(text: &str) -> WslState {
+        match text.to_lowercase().as_str() {
+            "running" | "выполняется" | "работает" => WslState::Running,
+            "stopped" | "остановлено" | "остановлен" => WslState::Stopped,
+            "installing" | "устанавливается" => WslState::Installing,
+            "converting" | "преобразуется" => WslState::Converting,
+            "uninstalling" | "удаляется" => WslState::Uninstalling,
+            _ => WslState::Other(text.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WslDistro {
+    pub name: String,
+    pub state: WslState,
+    pub version: u8,
+    pub is_default: bool,
+}
+
+// Distro names cannot contain spaces, the version is the last column and the
+// header is skipped because its last column is not a number.
+pub fn parse_wsl_list_verbose(text: &str) -> Vec<WslDistro> {
+    text.lines()
+        .filter_map(|line| {
+            let line = line.trim().trim_start_matches('\u{FEFF}');
+            let (is_default, line) = match line.strip_prefix('*') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [name, state @ .., version] = fields.as_slice() else {
+                return None;
+            };
+            Some(WslDistro {
+                name: name.to_string(),
+                state: WslState::parse(&state.join(" ")),
+                version: version.parse().ok()?,
+                is_default,
+            })
+        })
+        .collect()
+}
+
+pub fn list_wsl_distros() -> Result<Vec<WslDistro>, String> {
+    let output = session::output(Tool::Wsl, tools::command(Tool::Wsl).args(["-l", "-v"]))?;
+    if !output.status.success() {
+        return Err(format!(
+            "wsl -l -v завершилась с ошибкой: {}",
+            decode_output(&output.stdout).trim()
+        ));
+    }
+    Ok(parse_wsl_list_verbose(&decode_output(&output.stdout)))
+}
+
+// Only the Store version of WSL knows `--version`; the inbox one prints its
+// usage and fails.
+pub fn wsl_version() -> Result<String, String> {
+    let output = session::output(Tool::Wsl, tools::command(Tool::Wsl).arg("--version"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "wsl --version завершилась с кодом {}",
+            output.status
+        ));
+    }
+    Ok(decode_output(&output.stdout))
+}
+
+pub fn find_distro<'a>(distros: &'a [WslDistro], name: &str) -> Option<&'a WslDistro> {
+    distros
+        .iter()
+        .find(|distro| distro.name.eq_ignore_ascii_case(name))
+}
+
+pub fn run_in_distro(wsl_distro: &str, args: &[&str]) -> Result<String, String> {
+    run_wsl(wsl_distro, &[], args)
+}
+
+pub fn run_in_distro_as_root(wsl_distro: &str, args: &[&str]) -> Result<String, String> {
+    run_wsl(wsl_distro, &["-u", "root"], args)
+}
+
+fn run_wsl(wsl_distro: &str, wsl_args: &[&str], args: &[&str]) -> Result<String, String> {
+    let output = session::output(
+        Tool::Wsl,
+        tools::command(Tool::Wsl)
+            .args(["-d", wsl_distro])
+            .args(wsl_args)
+            .arg("--exec")
+            .args(args),
+    )?;
+
+    if output.status.success() {
+        Ok(decode_output(&output.stdout))
+    } else {
+        let stderr = decode_output(&output.stderr);
+        Err(format!(
+            "Команда в {wsl_distro} завершилась с ошибкой: {}",
+            stderr.trim()
+        ))
+    }
+}
+
+// Any command boots a stopped distro; it then stays up until WSL's idle
+// timeout, which is long enough for an attach.
+pub fn start_distro(wsl_distro: &str) -> Result<(), String> {
+    run_in_distro(wsl_distro, &["true"]).map(|_| ())
+}
+
+// WSL does not stop a distro while a wsl.exe session is open in it.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn keep_alive_command(wsl_distro: &str) -> Command {
+    let mut command = tools::command(Tool::Wsl);
+    command.args(["-d", wsl_distro, "--exec", "sleep", "infinity"]);
+    command
+}
+
+pub fn read_distro_sysfs(wsl_distro: &str) -> Result<SysfsSnapshot, String> {
+    run_in_distro(wsl_distro, &["sh", "-c", DUMP_SCRIPT, "sh", "/sys"])
+        .map(|output| parse_dump(&output))
+}
+
+pub fn check_attached_in(
+    snapshot: &SysfsSnapshot,
+    vid_pid: &str,
+    serial: Option<&str>,
+) -> AttachCheck {
+    match snapshot.find_device(vid_pid, serial) {
+        Some(device) => AttachCheck::Present(device.clone()),
+        None => AttachCheck::Missing {
+            vhci_loaded: snapshot.vhci_loaded,
+        },
+    }
+}
+
+// Prefers the agent (which also reports device nodes) and falls back to the
+// shell dump when the agent is not installed in the distro.
+pub fn check_attached(
+    wsl_distro: &str,
+    vid_pid: &str,
+    serial: Option<&str>,
+) -> Result<AttachCheck, String> {
+    let request = Request::Resolve {
+        vid_pid: vid_pid.to_string(),
+        serial: serial.map(str::to_string),
+    };
+    match agent::query(wsl_distro, &request) {
+        Ok(Response::Resolved {
+            device: Some(device),
+            ..
+        }) => Ok(AttachCheck::Present(device)),
+        Ok(Response::Resolved {
+            device: None,
+            vhci_loaded,
+        }) => Ok(AttachCheck::Missing { vhci_loaded }),
+        _ => read_distro_sysfs(wsl_distro)
+            .map(|snapshot| check_attached_in(&snapshot, vid_pid, serial)),
+    }
+}
+
+pub fn apply_device_nodes(snapshot: &SysfsSnapshot, devices: &mut [UsbDevice]) {
+    for device in devices
+        .iter_mut()
+        .filter(|device| device.state == "Attached")
+    {
+        if let Some(found) = snapshot.find_device(&device.vid_pid, device.serial.as_deref()) {
+            device.nodes = found.nodes.clone();
+        }
+    }
+}
+
+// Node lookup needs the agent; without it devices are simply shown without
+// their Linux nodes.
+pub fn resolve_device_nodes(wsl_distro: &str, devices: &mut [UsbDevice]) {
+    if !devices.iter().any(|device| device.state == "Attached") {
+        return;
+    }
+    if let Ok(Response::Snapshot { snapshot }) = agent::query(wsl_distro, &Request::Snapshot) {
+        apply_device_nodes(&snapshot, devices);
+    }
+}
+
+// Mounts of the device's block nodes and processes holding its nodes or files
+// on those mounts. Requires the agent: a shell fallback would not be reliable.
+pub fn find_device_holders(
+    wsl_distro: &str,
+    device: &UsbDevice,
+) -> Result<(Vec<Mount>, Vec<Holder>), String> {
+    let request = Request::Holders {
+        vid_pid: device.vid_pid.clone(),
+        serial: device.serial.clone(),
+    };
+    match agent::query_as_root(wsl_distro, &request)? {
+        Response::Holders { mounts, holders } => Ok((mounts, holders)),
+        other => Err(format!("Неожиданный ответ агента: {other:?}")),
+    }
+}
+
+// A lazy unmount detaches the filesystem even while it is busy; used only when
+// the user forces the detach.
+pub fn unmount(wsl_distro: &str, target: &str, lazy: bool) -> Result<(), String> {
+    let mut args = vec!["umount"];
+    if lazy {
+        args.push("-l");
+    }
+    args.push(target);
+    run_in_distro_as_root(wsl_distro, &args).map(|_| ())
+}
+
+pub fn describe_holders(holders: &[Holder]) -> String {
+    holders
+        .iter()
+        .map(|holder| format!("{} (PID {}): {}", holder.command, holder.pid, holder.path))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn describe_attach_check(check: &AttachCheck, vid_pid: &str, wsl_distro: &str) -> String {
+    match check {
+        AttachCheck::Present(device) => format!(
+            "{vid_pid} найдено в {wsl_distro} как {}{}{}",
+            device.name,
+            device
+                .product
+                .as_ref()
+                .map(|product| format!(" ({product})"))
+                .unwrap_or_default(),
+            if device.nodes.is_empty() {
+                String::new()
+            } else {
+                let paths: Vec<&str> = device.nodes.iter().map(|node| node.path.as_str()).collect();
+                format!(": {}", paths.join(", "))
+            }
+        ),
+        AttachCheck::Missing { vhci_loaded: true } => {
+            format!("Устройство {vid_pid} не найдено в {wsl_distro}")
+        }
+        AttachCheck::Missing { vhci_loaded: false } => format!(
+            "Устройство {vid_pid} не найдено в {wsl_distro}: модуль vhci_hcd не загружен \
+             (выполните `sudo modprobe vhci_hcd` или обновите ядро WSL)"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbipd::parse_usbipd_line;
+    use usbipd_agent::sysfs::{DeviceNode, NodeKind};
+
+    #[test]
+    fn reports_present_device() {
+        let snapshot = parse_dump("#vhci\n@1-1\nidVendor=058f\nidProduct=9540\nproduct=Reader\n");
+        let check = check_attached_in(&snapshot, "058f:9540", None);
+        assert!(matches!(&check, AttachCheck::Present(device) if device.name == "1-1"));
+        assert_eq!(
+            describe_attach_check(&check, "058f:9540", "Ubuntu"),
+            "058f:9540 найдено в Ubuntu как 1-1 (Reader)"
+        );
+    }
+
+    #[test]
+    fn lists_device_nodes() {
+        let mut snapshot = parse_dump("@1-1\nidVendor=2912\nidProduct=0008\n");
+        snapshot.devices[0].nodes = vec![DeviceNode {
+            kind: NodeKind::Tty,
+            path: "/dev/ttyACM0".to_string(),
+        }];
+        let check = check_attached_in(&snapshot, "2912:0008", None);
+        assert_eq!(
+            describe_attach_check(&check, "2912:0008", "Ubuntu"),
+            "2912:0008 найдено в Ubuntu как 1-1: /dev/ttyACM0"
+        );
+    }
+
+    #[test]
+    fn reports_missing_vhci_driver() {
+        let snapshot = parse_dump("@usb1\nidVendor=1d6b\nidProduct=0002\n");
+        let check = check_attached_in(&snapshot, "058f:9540", None);
+        assert_eq!(check, AttachCheck::Missing { vhci_loaded: false });
+        assert!(describe_attach_check(&check, "058f:9540", "Ubuntu").contains("vhci_hcd"));
+    }
+
+    #[test]
+    fn describes_holding_processes() {
+        let holders = vec![
+            Holder {
+                pid: 101,
+                command: "minicom".to_string(),
+                path: "/dev/ttyACM0".to_string(),
+            },
+            Holder {
+                pid: 303,
+                command: "cp".to_string(),
+                path: "/media/stick/firmware.bin".to_string(),
+            },
+        ];
+        assert_eq!(
+            describe_holders(&holders),
+            "minicom (PID 101): /dev/ttyACM0\ncp (PID 303): /media/stick/firmware.bin"
+        );
+    }
+
+    #[test]
+    fn parses_verbose_distro_list() {
+        let text = "  NAME            STATE           VERSION\r\n\
+                    * Ubuntu-24.04    Running         2\r\n\
+                    \x20 Debian          Stopped         2\r\n\
+                    \x20 Legacy          Stopped         1\r\n";
+        let mut bytes = Vec::new();
+        for unit in text.encode_utf16() {
+            bytes.extend_from_slice(&unit.to_le_bytes());
+        }
+        let distros = parse_wsl_list_verbose(&decode_output(&bytes));
+        assert_eq!(distros.len(), 3);
+        assert_eq!(
+            distros[0],
+            WslDistro {
+                name: "Ubuntu-24.04".to_string(),
+                state: WslState::Running,
+                version: 2,
+                is_default: true,
+            }
+        );
+        assert_eq!(distros[1].state, WslState::Stopped);
+        assert!(!distros[1].is_default);
+        assert_eq!(find_distro(&distros, "legacy").map(|d| d.version), Some(1));
+
+        let localized = parse_wsl_list_verbose(
+            "\u{FEFF}  ИМЯ      СОСТОЯНИЕ      ВЕРСИЯ\n* Debian   Выполняется    2\n",
+        );
+        assert_eq!(localized[0].state, WslState::Running);
+        assert!(localized[0].is_default);
+    }
+
+    #[test]
+    fn applies_nodes_to_attached_devices_by_serial() {
+        let mut snapshot = parse_dump(
+            "@1-1\nidVendor=0403\nidProduct=6001\nserial=A1\n\
+             @1-2\nidVendor=0403\nidProduct=6001\nserial=B2\n",
+        );
+        for (device, tty) in snapshot.devices.iter_mut().zip(["ttyUSB0", "ttyUSB1"]) {
+            device.nodes = vec![DeviceNode {
+                kind: NodeKind::Tty,
+                path: format!("/dev/{tty}"),
+            }];
+        }
+
+        let mut devices: Vec<UsbDevice> = [
+            "2-3    0403:6001  USB Serial Converter                                          Attached",
+            "2-4    0403:6001  USB Serial Converter                                          Attached",
+            "2-5    0403:6001  USB Serial Converter                                          Shared",
+        ]
+        .iter()
+        .filter_map(|line| parse_usbipd_line(line))
+        .collect();
+        devices[0].serial = Some("B2".to_string());
+        devices[1].serial = Some("A1".to_string());
+
+        apply_device_nodes(&snapshot, &mut devices);
+        assert_eq!(devices[0].nodes[0].path, "/dev/ttyUSB1");
+        assert_eq!(devices[1].nodes[0].path, "/dev/ttyUSB0");
+        assert!(devices[2].nodes.is_empty());
+    }
+}
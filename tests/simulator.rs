@@ -0,0 +1,588 @@
+// End-to-end scenarios: the CLI runs against the usbipd_sim stand-ins for
+// usbipd, wsl and powershell, pointed to through the executable path flags.
+#![cfg(unix)]
+
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_SIM: AtomicUsize = AtomicUsize::new(0);
+
+struct Sim {
+    dir: PathBuf,
+}
+
+impl Sim {
+    fn new(state: Value, config: Value) -> Sim {
+        let dir = env::temp_dir().join(format!(
+            "usbipd-sim-{}-{}",
+            std::process::id(),
+            NEXT_SIM.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        for tool in ["usbipd", "wsl", "powershell"] {
+            symlink(env!("CARGO_BIN_EXE_usbipd_sim"), dir.join(tool)).unwrap();
+        }
+        let sim = Sim { dir };
+        sim.write("state.json", &state);
+        sim.write("config.json", &config);
+        sim
+    }
+
+    fn write(&self, name: &str, value: &Value) {
+        fs::write(self.dir.join(name), value.to_string()).unwrap();
+    }
+
+    fn read(&self, name: &str) -> Value {
+        serde_json::from_str(&fs::read_to_string(self.dir.join(name)).unwrap()).unwrap()
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        let tool = |name: &str| self.dir.join(name).to_string_lossy().to_string();
+        Command::new(env!("CARGO_BIN_EXE_usbipd_gui"))
+            .args(["--usbipd", &tool("usbipd")])
+            .args(["--wsl-exe", &tool("wsl")])
+            .args(["--powershell", &tool("powershell")])
+            .args(args)
+            .current_dir(&self.dir)
+            .env("USBIPD_SIM_STATE", self.dir.join("state.json"))
+            .env("USBIPD_GUI_STATE_DIR", self.dir.join("state"))
+            .env("USERNAME", "operator")
+            .env_remove("USERDOMAIN")
+            .env_remove("USBIPD_AGENT_PATH")
+            .output()
+            .unwrap()
+    }
+
+    fn device(&self, bus_id: &str) -> Value {
+        self.read("state.json")["devices"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|device| device["bus_id"] == bus_id)
+            .cloned()
+            .unwrap()
+    }
+
+    fn app_log(&self) -> Vec<Value> {
+        fs::read_to_string(self.dir.join("state/logs/usbipd_gui.log"))
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn log(&self) -> Vec<String> {
+        serde_json::from_value(self.read("state.json")["log"].clone()).unwrap()
+    }
+}
+
+impl Drop for Sim {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).to_string()
+}
+
+fn workstation() -> Value {
+    json!({
+        "distros": [
+            {"name": "Ubuntu", "default": true},
+            {"name": "Debian", "state": "Stopped"}
+        ],
+        "devices": [
+            {
+                "bus_id": "2-7", "vid_pid": "2912:0008", "name": "ATOL USB",
+                "state": "Attached", "serial": "00106", "attached_to": "Ubuntu",
+                "nodes": [{"kind": "tty", "path": "/dev/ttyACM0"}]
+            },
+            {
+                "bus_id": "2-9", "vid_pid": "1a2c:2124", "name": "USB-устройство ввода",
+                "state": "Not shared",
+                "nodes": [{"kind": "hidraw", "path": "/dev/hidraw0"}]
+            }
+        ]
+    })
+}
+
+fn config() -> Value {
+    json!({"auto_attach_devices": [], "wsl_distro": "Ubuntu"})
+}
+
+#[test]
+fn lists_devices_with_linux_nodes() {
+    let sim = Sim::new(workstation(), config());
+    let output = sim.run(&["list"]);
+    assert!(output.status.success(), "{}", text(&output.stderr));
+    let stdout = text(&output.stdout);
+    assert!(
+        stdout.contains("2-7: ATOL USB → /dev/ttyACM0 [Attached]"),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains("2-9: USB-устройство ввода [Not shared]"),
+        "{stdout}"
+    );
+
+    let output = sim.run(&["list", "--json"]);
+    let devices: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(devices[0]["serial"], "00106");
+}
+
+#[test]
+fn connects_and_rolls_back_on_failure() {
+    let mut state = workstation();
+    state["failures"] = json!({"attach": "simulated attach failure"});
+    let sim = Sim::new(state, config());
+    let output = sim.run(&["connect", "2-9", "--yes"]);
+    assert!(!output.status.success());
+    assert!(text(&output.stderr).contains("simulated attach failure"));
+    assert_eq!(sim.device("2-9")["state"], "Not shared");
+    assert!(sim
+        .log()
+        .iter()
+        .any(|entry| entry == "usbipd unbind --busid 2-9"));
+
+    let sim = Sim::new(workstation(), config());
+    let output = sim.run(&["connect", "2-9", "--yes"]);
+    assert!(output.status.success(), "{}", text(&output.stderr));
+    assert!(text(&output.stdout).contains("/dev/hidraw0"));
+    let device = sim.device("2-9");
+    assert_eq!(device["state"], "Attached");
+    assert_eq!(device["attached_to"], "Ubuntu");
+    assert!(sim
+        .log()
+        .iter()
+        .any(|entry| entry == "usbipd bind --busid 2-9"));
+    let records = sim.app_log();
+    let record = records.last().unwrap();
+    assert_eq!(record["level"], "info");
+    assert_eq!(record["bus_id"], "2-9");
+    assert_eq!(record["outcome"], "succeeded");
+    assert!(record["duration_ms"].is_u64());
+}
+
+#[test]
+fn refuses_busy_detach_until_forced() {
+    let mut state = workstation();
+    state["devices"][0]["holders"] =
+        json!([{"pid": 101, "command": "minicom", "path": "/dev/ttyACM0"}]);
+    state["devices"][0]["mounts"] = json!([{"source": "/dev/ttyACM0", "target": "/mnt/x"}]);
+    let mut config = config();
+    config["hooks"] = json!({
+        "pre_detach": {"command": "echo $USBIPD_BUS_ID >> pre_detach.txt", "target": "wsl"}
+    });
+    let sim = Sim::new(state, config);
+    let pre_detach = || fs::read_to_string(sim.dir.join("pre_detach.txt")).unwrap_or_default();
+
+    let output = sim.run(&["detach", "2-7"]);
+    assert!(!output.status.success());
+    assert!(text(&output.stderr).contains("minicom (PID 101)"));
+    assert_eq!(sim.device("2-7")["state"], "Attached");
+    // The hook only runs for the detach that actually happens.
+    assert_eq!(pre_detach(), "");
+
+    let output = sim.run(&["detach", "2-7", "--force"]);
+    assert!(output.status.success(), "{}", text(&output.stderr));
+    assert_eq!(sim.device("2-7")["state"], "Shared");
+    assert_eq!(pre_detach(), "2-7\n");
+    assert!(sim
+        .log()
+        .iter()
+        .any(|entry| entry.ends_with("umount -l /mnt/x")));
+}
+
+#[test]
+fn refuses_detach_when_handles_cannot_be_checked() {
+    let mut state = workstation();
+    state["failures"] = json!({"agent": "agent not installed"});
+    let sim = Sim::new(state, config());
+
+    let output = sim.run(&["detach", "2-7"]);
+    assert!(!output.status.success());
+    assert!(text(&output.stderr).contains("Не удалось проверить"));
+    assert_eq!(sim.device("2-7")["state"], "Attached");
+
+    let output = sim.run(&["detach", "2-7", "--force"]);
+    assert!(output.status.success(), "{}", text(&output.stderr));
+    assert_eq!(sim.device("2-7")["state"], "Shared");
+}
+
+#[test]
+fn moves_device_to_a_stopped_distro() {
+    let sim = Sim::new(workstation(), config());
+    let output = sim.run(&["move", "2-7", "Debian"]);
+    assert!(output.status.success(), "{}", text(&output.stderr));
+    assert_eq!(sim.device("2-7")["attached_to"], "Debian");
+    assert_eq!(sim.read("state.json")["distros"][1]["state"], "Running");
+    assert_eq!(
+        sim.read("config.json")["devices"]["2912:0008/00106"]["wsl_distro"],
+        "Debian"
+    );
+}
+
+#[test]
+fn moves_a_busy_device_only_when_forced() {
+    let mut state = workstation();
+    state["devices"][0]["holders"] =
+        json!([{"pid": 101, "command": "minicom", "path": "/dev/ttyACM0"}]);
+    let sim = Sim::new(state, config());
+
+    let output = sim.run(&["move", "2-7", "Debian"]);
+    assert!(!output.status.success());
+    assert!(text(&output.stderr).contains("minicom (PID 101)"));
+    assert_eq!(sim.device("2-7")["attached_to"], "Ubuntu");
+    assert!(sim.read("config.json").get("devices").is_none());
+
+    let output = sim.run(&["move", "2-7", "Debian", "--force"]);
+    assert!(output.status.success(), "{}", text(&output.stderr));
+    assert_eq!(sim.device("2-7")["attached_to"], "Debian");
+}
+
+#[test]
+fn replays_a_recorded_session_without_the_tools() {
+    let sim = Sim::new(workstation(), config());
+    let recording = sim.dir.join("session.json");
+    let recording = recording.to_string_lossy();
+    let output = sim.run(&["connect", "2-9", "--yes", "--record", &recording]);
+    assert!(output.status.success(), "{}", text(&output.stderr));
+    let recorded = sim.read("session.json");
+    assert!(recorded["commands"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|command| command["tool"] == "usbipd" && command["args"][0] == "attach"));
+
+    // The recorded state is replayed even though the device is gone.
+    sim.write("state.json", &json!({}));
+    let output = Command::new(env!("CARGO_BIN_EXE_usbipd_gui"))
+        .args(["--replay", &recording, "--usbipd", "/nonexistent/usbipd"])
+        .args(["--wsl-exe", "/nonexistent/wsl"])
+        .args(["--powershell", "/nonexistent/powershell"])
+        .args(["connect", "2-9", "--yes"])
+        .current_dir(&sim.dir)
+        .env("USBIPD_GUI_STATE_DIR", sim.dir.join("state"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", text(&output.stderr));
+    assert!(text(&output.stdout).contains("/dev/hidraw0"));
+    assert_eq!(sim.read("state.json"), json!({}));
+}
+
+#[test]
+fn doctor_reports_blocked_port_and_vpn() {
+    let mut state = workstation();
+    state["port_open"] = json!(false);
+    state["adapters"] = json!(["Intel(R) Ethernet Connection", "WireGuard Tunnel"]);
+    let sim = Sim::new(state, config());
+    let output = sim.run(&["doctor", "--json"]);
+    assert!(!output.status.success());
+    let results: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let status = |name: &str| {
+        results
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|result| result["name"] == name)
+            .map(|result| result["status"].clone())
+            .unwrap()
+    };
+    assert_eq!(status("usbipd"), "pass");
+    assert_eq!(status("WSL"), "pass");
+    assert_eq!(status("Дистрибутив"), "pass");
+    assert_eq!(status("Порт 3240"), "fail");
+    assert_eq!(status("VPN"), "warn");
+    assert_eq!(status("config.json"), "pass");
+    assert_eq!(status("usb.ids"), "warn");
+}
+
+#[test]
+fn exports_a_diagnostic_bundle() {
+    let mut config = config();
+    config["hooks"] = json!({"pre_bind": {"command": "net use /user:admin secret"}});
+    let sim = Sim::new(workstation(), config);
+    let output = sim.run(&["export-diagnostics", "bundle.json"]);
+    assert!(output.status.success(), "{}", text(&output.stderr));
+    let bundle = sim.read("bundle.json");
+    assert_eq!(bundle["format"], "usbipd_gui.diagnostics");
+    assert_eq!(bundle["format_version"], 1);
+    assert_eq!(bundle["config"]["hooks"]["pre_bind"]["command"], "<скрыто>");
+    assert!(bundle["commands"]["usbipd_list"]["stdout"]
+        .as_str()
+        .unwrap()
+        .contains("ATOL USB"));
+    assert!(bundle["commands"]["wsl_list"]["stdout"]
+        .as_str()
+        .unwrap()
+        .contains("Debian"));
+    assert_eq!(
+        bundle["environment"]["tools"]["usbipd"]["source"],
+        "command_line"
+    );
+    assert!(bundle["doctor"].as_array().unwrap().len() >= 8);
+}
+
+#[test]
+fn records_history_of_device_actions() {
+    let mut state = workstation();
+    state["failures"] = json!({"attach": "simulated attach failure"});
+    let sim = Sim::new(state, config());
+    assert!(!sim.run(&["connect", "2-9", "--yes"]).status.success());
+    let output = sim.run(&["detach", "2-7"]);
+    assert!(output.status.success(), "{}", text(&output.stderr));
+
+    let output = sim.run(&["history", "--device", "2-9", "--json"]);
+    assert!(output.status.success(), "{}", text(&output.stderr));
+    let entries: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let actions: Vec<(&str, bool)> = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| {
+            assert_eq!(entry["user"], "operator");
+            assert_eq!(entry["vid_pid"], "1a2c:2124");
+            (
+                entry["action"].as_str().unwrap(),
+                entry["succeeded"].as_bool().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        actions,
+        vec![("bind", true), ("attach", false), ("unbind", true)]
+    );
+    assert!(entries[1]["error"]
+        .as_str()
+        .unwrap()
+        .contains("simulated attach failure"));
+    assert_eq!(entries[1]["distro"], "Ubuntu");
+
+    let output = sim.run(&["history", "--since", "1h"]);
+    let stdout = text(&output.stdout);
+    assert_eq!(stdout.lines().count(), 4, "{stdout}");
+    assert!(stdout
+        .lines()
+        .last()
+        .unwrap()
+        .contains("operator detach 2-7"));
+}
+
+#[test]
+fn remembers_unplugged_devices_in_the_inventory() {
+    let mut config = config();
+    config["devices"] = json!({"2912:0008": {"nickname": "Касса"}});
+    let sim = Sim::new(workstation(), config);
+    let output = sim.run(&["list"]);
+    assert!(output.status.success(), "{}", text(&output.stderr));
+
+    let mut state = sim.read("state.json");
+    state["devices"].as_array_mut().unwrap().remove(0);
+    sim.write("state.json", &state);
+    let output = sim.run(&["inventory"]);
+    assert!(output.status.success(), "{}", text(&output.stderr));
+    let stdout = text(&output.stdout);
+    assert!(
+        stdout.contains("2912:0008/00106: Касса (ATOL USB) — не подключено, последний BUSID 2-7"),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains("1a2c:2124: USB-устройство ввода — 2-9 [Not shared]"),
+        "{stdout}"
+    );
+
+    let output = sim.run(&["inventory", "--json"]);
+    let entries: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(entries.as_array().unwrap().len(), 2);
+    assert_eq!(entries[0]["last_state"], "Attached");
+    assert_eq!(entries[0]["serial"], "00106");
+}
+
+#[test]
+fn annotates_and_searches_devices() {
+    let sim = Sim::new(workstation(), config());
+    let output = sim.run(&[
+        "annotate",
+        "2912:0008/00106",
+        "--nickname",
+        "Касса",
+        "--tags",
+        "склад,фискальный",
+        "--notes",
+        "Заводской номер на наклейке",
+    ]);
+    assert!(output.status.success(), "{}", text(&output.stderr));
+    let device = &sim.read("config.json")["devices"]["2912:0008/00106"];
+    assert_eq!(device["tags"], json!(["склад", "фискальный"]));
+
+    let output = sim.run(&["list", "--search", "наклейк"]);
+    let stdout = text(&output.stdout);
+    assert!(
+        stdout.contains("2-7: Касса (ATOL USB) #склад #фискальный → /dev/ttyACM0 [Attached]"),
+        "{stdout}"
+    );
+    assert!(!stdout.contains("2-9"), "{stdout}");
+
+    let output = sim.run(&["list", "--tag", "склад", "--json"]);
+    let devices: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(devices.as_array().unwrap().len(), 1);
+    assert_eq!(devices[0]["nickname"], "Касса");
+    assert_eq!(devices[0]["notes"], "Заводской номер на наклейке");
+    assert_eq!(devices[0]["bus_id"], "2-7");
+}
+
+#[test]
+fn enforces_the_protected_device_policy() {
+    // 2-9 is the only input device, so binding it has to be confirmed.
+    let sim = Sim::new(workstation(), config());
+    let output = sim.run(&["bind", "2-9"]);
+    assert!(!output.status.success());
+    assert!(
+        text(&output.stderr).contains("последнее устройство ввода"),
+        "{}",
+        text(&output.stderr)
+    );
+    assert!(!sim.log().iter().any(|entry| entry.contains("bind")));
+    // A move binds a device that is not shared yet.
+    let output = sim.run(&["move", "2-9", "Debian"]);
+    assert!(!output.status.success());
+    assert!(
+        text(&output.stderr).contains("последнее устройство ввода"),
+        "{}",
+        text(&output.stderr)
+    );
+    assert!(!sim.log().iter().any(|entry| entry.contains("bind")));
+    let output = sim.run(&["bind", "2-9", "--yes"]);
+    assert!(output.status.success(), "{}", text(&output.stderr));
+    assert_eq!(sim.device("2-9")["state"], "Shared");
+
+    // A receiver that Windows only calls a composite device is recognised by
+    // the classes of its interfaces.
+    let mut state = workstation();
+    state["devices"][1]["name"] = json!("USB Composite Device");
+    state["devices"][1]["setup_classes"] = json!(["HIDClass", "Keyboard"]);
+    let sim = Sim::new(state, config());
+    let output = sim.run(&["bind", "2-9"]);
+    assert!(!output.status.success());
+    assert!(
+        text(&output.stderr).contains("последнее устройство ввода"),
+        "{}",
+        text(&output.stderr)
+    );
+
+    // A denied device is refused even when confirmed.
+    let mut config = config();
+    config["protect"] = json!({
+        "deny": [{"class": "HID", "reason": "Клавиатура стойки"}]
+    });
+    let sim = Sim::new(workstation(), config);
+    let output = sim.run(&["connect", "2-9", "--yes"]);
+    assert!(!output.status.success());
+    assert!(
+        text(&output.stderr).contains("Привязка запрещена: Клавиатура стойки"),
+        "{}",
+        text(&output.stderr)
+    );
+    assert_eq!(sim.device("2-9")["state"], "Not shared");
+}
+
+#[test]
+fn keeps_a_malformed_config_and_asks_before_binding() {
+    let mut state = workstation();
+    state["devices"][0]["state"] = json!("Not shared");
+    state["devices"][0]["attached_to"] = Value::Null;
+    let sim = Sim::new(state, config());
+    let broken = r#"{"protect": {"deny": [{"vid_pid": "2912:0008"}]},}"#;
+    fs::write(sim.dir.join("config.json"), broken).unwrap();
+
+    // Without the rules nothing is known to be safe to bind.
+    let output = sim.run(&["bind", "2-7"]);
+    assert!(!output.status.success());
+    assert!(
+        text(&output.stderr).contains("Правила protect не загружены"),
+        "{}",
+        text(&output.stderr)
+    );
+    assert_eq!(sim.device("2-7")["state"], "Not shared");
+
+    let output = sim.run(&["annotate", "2912:0008/00106", "--nickname", "Касса"]);
+    assert!(!output.status.success());
+    assert!(
+        text(&output.stderr).contains("изменения не сохранены"),
+        "{}",
+        text(&output.stderr)
+    );
+    assert_eq!(
+        fs::read_to_string(sim.dir.join("config.json")).unwrap(),
+        broken
+    );
+}
+
+#[test]
+fn binds_with_or_without_force_and_converts() {
+    let mut config = config();
+    config["devices"] = json!({"2-9": {"force_bind": true}});
+    let sim = Sim::new(workstation(), config);
+    let output = sim.run(&["bind", "2-9", "--yes"]);
+    assert!(output.status.success(), "{}", text(&output.stderr));
+    assert_eq!(sim.device("2-9")["state"], "Shared (forced)");
+    let output = sim.run(&["list"]);
+    assert!(
+        text(&output.stdout).contains("2-9: USB-устройство ввода [Shared (forced)]"),
+        "{}",
+        text(&output.stdout)
+    );
+
+    let output = sim.run(&["rebind", "2-9", "--no-force"]);
+    assert!(output.status.success(), "{}", text(&output.stderr));
+    assert_eq!(sim.device("2-9")["state"], "Shared");
+    let log = sim.log();
+    let binds: Vec<&String> = log
+        .iter()
+        .filter(|entry| entry.starts_with("usbipd bind") || entry.starts_with("usbipd unbind"))
+        .collect();
+    assert_eq!(
+        binds,
+        [
+            "usbipd bind --busid 2-9 --force",
+            "usbipd unbind --busid 2-9",
+            "usbipd bind --busid 2-9"
+        ]
+    );
+
+    // An attached device has to be detached first.
+    let output = sim.run(&["rebind", "2-7", "--force"]);
+    assert!(!output.status.success());
+    assert!(text(&output.stderr).contains("detach"));
+}
+
+#[test]
+fn rebinds_in_the_original_mode_when_the_new_bind_fails() {
+    let mut state = workstation();
+    state["devices"][1]["state"] = json!("Shared");
+    state["failures"] = json!({"powershell bind --busid 2-9 --force": "UAC declined"});
+    let sim = Sim::new(state, config());
+    let output = sim.run(&["rebind", "2-9", "--force", "--yes"]);
+    assert!(!output.status.success());
+    assert!(
+        text(&output.stderr).contains("UAC declined"),
+        "{}",
+        text(&output.stderr)
+    );
+    assert_eq!(sim.device("2-9")["state"], "Shared");
+    let log = sim.log();
+    let binds: Vec<&String> = log
+        .iter()
+        .filter(|entry| entry.starts_with("usbipd bind") || entry.starts_with("usbipd unbind"))
+        .collect();
+    assert_eq!(
+        binds,
+        ["usbipd unbind --busid 2-9", "usbipd bind --busid 2-9"]
+    );
+}